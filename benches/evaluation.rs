@@ -0,0 +1,246 @@
+#[macro_use]
+extern crate criterion;
+extern crate match_diagram;
+extern crate rand;
+
+use criterion::Criterion;
+use rand::{Rng, SeedableRng, XorShiftRng};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use match_diagram::database::Database;
+use match_diagram::evaluation::Evaluation;
+use match_diagram::fact::Fact;
+use match_diagram::parse::parse_diagram;
+use match_diagram::predicate::Predicate;
+use match_diagram::rand_utils::random_database;
+use match_diagram::registers::{RegisterFile, RegisterSet};
+use match_diagram::simple_query::{SimpleQuery, SimpleQueryTerm};
+use match_diagram::value::Value;
+use match_diagram::weight::Weight;
+
+fn rng() -> XorShiftRng {
+    XorShiftRng::from_seed([0xde, 0xad, 0xbe, 0xef])
+}
+
+/**
+ * Counts allocations, so benchmarks can report how many each approach makes.
+ */
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn count_allocations<T, F: FnOnce() -> T>(f: F) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let _result = f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn nested_filtering(c: &mut Criterion) {
+    let (diagram, _) = parse_diagram(
+        r#"
+    root: @0(:1 -> %0, _ -> %1) {
+      @0(_, _ -> %1) {
+        output @1(%0, %1)
+      }
+    }
+    "#,
+        2,
+    ).unwrap();
+    for &num_facts in [10_000, 100_000].iter() {
+        let input = random_database(&mut rng(), 1, 2, 100, num_facts);
+        let diagram = diagram.clone();
+        c.bench_function(
+            &format!("evaluate nested filtering diagram over {} facts", num_facts),
+            move |b| b.iter(|| diagram.evaluate(&input)),
+        );
+    }
+}
+
+fn deep_dag(c: &mut Criterion) {
+    // Each level matches and refutes into the next, so the fixpoint loop propagates the last
+    // levels once per path through the diagram.
+    let depth = 10;
+    let mut src = "root: @0(_ -> %0) { n0 }\n".to_owned();
+    for level in 0..depth {
+        src += &format!("n{}: @1(%0) {{ n{} }} {{ n{} }}\n", level, level + 1, level + 1);
+    }
+    src += &format!("n{}: output @2(%0)\n", depth);
+    let (diagram, _) = parse_diagram(&src, 1).unwrap();
+    let input = random_database(&mut rng(), 2, 1, 100, 1_000);
+    let ordered_diagram = diagram.clone();
+    let ordered_input = input.clone();
+    c.bench_function("evaluate deep DAG diagram with the fixpoint loop", move |b| {
+        b.iter(|| Evaluation::run_multi(&diagram, &input, 1))
+    });
+    c.bench_function("evaluate deep DAG diagram in topological order", move |b| {
+        b.iter(|| Evaluation::run_multi_ordered(&ordered_diagram, &ordered_input, 1))
+    });
+}
+
+fn many_outputs(c: &mut Criterion) {
+    let num_outputs = 50;
+    let mut src = "root: @0(_ -> %0, _ -> %1) {\n".to_owned();
+    let outputs: Vec<String> = (0..num_outputs)
+        .map(|i| format!("  output @{}(%0, %1)", i + 1))
+        .collect();
+    src += &outputs.join(";\n");
+    src += "\n}\n";
+    let (diagram, _) = parse_diagram(&src, 2).unwrap();
+    let input = random_database(&mut rng(), 1, 2, 100, 1_000);
+    let lazy_diagram = diagram.clone();
+    let lazy_input = input.clone();
+    c.bench_function("evaluate many-output diagram with total_db", move |b| {
+        b.iter(|| Evaluation::run_multi(&diagram, &input, 2))
+    });
+    c.bench_function("evaluate many-output diagram without total_db", move |b| {
+        b.iter(|| Evaluation::run_multi_lazy(&lazy_diagram, &lazy_input, 2))
+    });
+}
+
+fn evaluation_reuse(c: &mut Criterion) {
+    // The diagram the evolution test looks for, scored over several samples the way
+    // `StepProblem` rescores an individual.
+    let (diagram, _) = parse_diagram("root: @0(_ -> %0) { output @1(%0) }", 1).unwrap();
+    let mut rng = rng();
+    let inputs: Vec<Database> = (0..10)
+        .map(|_| random_database(&mut rng, 1, 1, 100, 1_000))
+        .collect();
+    let fresh = |diagram: &_, inputs: &[Database]| {
+        for input in inputs {
+            Evaluation::run_multi(diagram, input, 1);
+        }
+    };
+    let reused = |diagram: &_, inputs: &[Database], eval: &mut Evaluation| {
+        for input in inputs {
+            eval.rerun(diagram, input, 1);
+        }
+    };
+    let mut eval = Evaluation::new();
+    reused(&diagram, &inputs, &mut eval);
+    println!(
+        "allocations per pass over {} samples: {} fresh, {} reused",
+        inputs.len(),
+        count_allocations(|| fresh(&diagram, &inputs)),
+        count_allocations(|| reused(&diagram, &inputs, &mut eval))
+    );
+    let reused_diagram = diagram.clone();
+    let reused_inputs = inputs.clone();
+    c.bench_function("evaluate samples with a fresh Evaluation each", move |b| {
+        b.iter(|| fresh(&diagram, &inputs))
+    });
+    c.bench_function("evaluate samples reusing one Evaluation", move |b| {
+        b.iter(|| reused(&reused_diagram, &reused_inputs, &mut eval))
+    });
+}
+
+fn push_all(mut set: RegisterSet, register_files: &[(RegisterFile, Weight)]) -> RegisterSet {
+    for &(ref registers, weight) in register_files.iter() {
+        set.push(registers.clone(), weight, 0);
+    }
+    set
+}
+
+fn register_set_push(c: &mut Criterion) {
+    let mut rng = rng();
+    let register_files: Vec<(RegisterFile, Weight)> = (0..10_000)
+        .map(|_| {
+            let mut registers = RegisterFile::new(4);
+            for i in 0..4 {
+                registers[i] = Some(Value::Symbol(rng.gen_range(0, 16)));
+            }
+            let weight = if rng.gen() { Weight(1) } else { Weight(-1) };
+            (registers, weight)
+        })
+        .collect();
+    let num_files = register_files.len();
+    println!(
+        "allocations pushing {} register files: {} without capacity, {} with capacity",
+        num_files,
+        count_allocations(|| push_all(RegisterSet::new(4), &register_files)),
+        count_allocations(|| push_all(RegisterSet::with_capacity(4, num_files), &register_files))
+    );
+    let unsized_files = register_files.clone();
+    c.bench_function("push 10000 register files into a RegisterSet", move |b| {
+        b.iter(|| push_all(RegisterSet::new(4), &unsized_files))
+    });
+    c.bench_function(
+        "push 10000 register files into a RegisterSet with capacity",
+        move |b| b.iter(|| push_all(RegisterSet::with_capacity(4, num_files), &register_files)),
+    );
+}
+
+fn query_column(db: &Database) -> usize {
+    let terms = &[
+        SimpleQueryTerm::Constant {
+            value: &Value::Symbol(1),
+        },
+        SimpleQueryTerm::Free,
+    ];
+    db.simple_query(SimpleQuery {
+        predicate: Predicate(0),
+        terms,
+    }).count()
+}
+
+fn simple_query(c: &mut Criterion) {
+    let db = random_database(&mut rng(), 1, 2, 100, 100_000);
+    let mut indexed_db = db.clone();
+    indexed_db.add_index(Predicate(0), 0, Value::Symbol(1));
+    c.bench_function("simple_query over 100000 facts without an index", move |b| {
+        b.iter(|| query_column(&db))
+    });
+    c.bench_function("simple_query over 100000 facts with an index", move |b| {
+        b.iter(|| query_column(&indexed_db))
+    });
+}
+
+fn insert_facts(c: &mut Criterion) {
+    let db = random_database(&mut rng(), 4, 2, 1000, 100_000);
+    let facts: Vec<(Vec<Value>, Predicate, Weight)> = db.weighted_facts()
+        .map(|(fact, weight)| (fact.values.to_vec(), fact.predicate, weight))
+        .collect();
+    let bulk_facts = facts.clone();
+    c.bench_function("insert 100000 facts one at a time", move |b| {
+        b.iter(|| {
+            let mut db = Database::new();
+            for &(ref values, predicate, weight) in facts.iter() {
+                db.insert_fact_with_weight(Fact { predicate, values }, weight);
+            }
+            db
+        })
+    });
+    c.bench_function("insert 100000 facts in bulk", move |b| {
+        b.iter(|| {
+            let facts: Vec<(Fact, Weight)> = bulk_facts
+                .iter()
+                .map(|&(ref values, predicate, weight)| (Fact { predicate, values }, weight))
+                .collect();
+            let mut db = Database::new();
+            db.insert_facts_bulk(&facts);
+            db
+        })
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = nested_filtering, deep_dag, many_outputs, evaluation_reuse, register_set_push,
+        simple_query, insert_facts
+}
+criterion_main!(benches);
@@ -6,16 +6,65 @@ use node_index::NodeIndex;
 use predicate::Predicate;
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct NodeInfo {
     pub index: NodeIndex,
     pub defined: bool,
 }
 
 #[derive(Clone, Debug)]
+pub struct Remap {
+    pub predicates: HashMap<Predicate, Predicate>,
+    pub node_offset: usize,
+    /**
+     * Nodes of the merged in diagram whose name was already in the context, mapped to the node
+     * that name refers to after merging.
+     */
+    pub nodes: HashMap<NodeIndex, NodeIndex>,
+    /**
+     * Undefined nodes of the existing diagram mapped to the merged in node which defines their
+     * name. Edges into the former should be redirected to the latter.
+     */
+    pub redirects: HashMap<NodeIndex, NodeIndex>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MergeError {
+    NodeDefinedTwice(String),
+    WrongNumberOfTerms(String),
+}
+
+impl Remap {
+    pub fn predicate(&self, predicate: Predicate) -> Predicate {
+        self.predicates
+            .get(&predicate)
+            .cloned()
+            .unwrap_or(predicate)
+    }
+
+    pub fn node(&self, node: NodeIndex) -> NodeIndex {
+        self.nodes
+            .get(&node)
+            .cloned()
+            .unwrap_or(NodeIndex(node.0 + self.node_offset))
+    }
+}
+
+/**
+ * The names used by a parsed diagram. With the `serialize` feature this can be saved and
+ * reloaded alongside `GraphDiagram::into_parts`, so that the reloaded diagram keeps its names.
+ */
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct Context {
     pub num_terms_for_predicate: HashMap<Predicate, usize>,
     pub predicate_name_to_predicate: HashMap<String, Predicate>,
     pub node_name_to_info: HashMap<String, NodeInfo>,
+    /**
+     * The full-line `#` comments directly above each node in the source, without the `#`. Only
+     * retained if this is `Some`, see `with_comments`.
+     */
+    pub node_comments: Option<HashMap<NodeIndex, Vec<String>>>,
 }
 
 impl Context {
@@ -24,9 +73,27 @@ impl Context {
             num_terms_for_predicate: HashMap::new(),
             predicate_name_to_predicate: HashMap::new(),
             node_name_to_info: HashMap::new(),
+            node_comments: None,
         }
     }
 
+    /**
+     * Creates a context which retains the comments above each node parsed into it.
+     */
+    pub fn with_comments() -> Self {
+        let mut context = Context::new();
+        context.node_comments = Some(HashMap::new());
+        context
+    }
+
+    pub fn comments_for_node(&self, node: NodeIndex) -> &[String] {
+        self.node_comments
+            .as_ref()
+            .and_then(|comments| comments.get(&node))
+            .map(|comments| &comments[..])
+            .unwrap_or(&[])
+    }
+
     pub fn check_num_terms_for_predicate(&mut self, predicate: Predicate, num_terms: usize) {
         match self.num_terms_for_predicate.entry(predicate) {
             hash_map::Entry::Occupied(entry) => {
@@ -51,6 +118,7 @@ impl Context {
             let node = Node::Match {
                 predicate: Predicate(0),
                 terms: Vec::new(),
+                require_bound: false,
             };
             let index = diagram.insert_node(node);
             let info = NodeInfo {
@@ -62,6 +130,86 @@ impl Context {
         }
     }
 
+    pub fn define_node_name(&mut self, name: &str) {
+        self.node_name_to_info
+            .get_mut(name)
+            .expect("Node name should be reserved before it is defined")
+            .defined = true;
+    }
+
+    /**
+     * Merge in the context of a diagram which will be appended after the first `num_nodes` nodes.
+     * The returned remap should be used to merge the diagrams themselves. Fails without changing
+     * this context if a node name is defined in both contexts, or a predicate name is used with
+     * different numbers of terms.
+     */
+    pub fn merge(&mut self, other: Context, num_nodes: usize) -> Result<Remap, MergeError> {
+        for (name, info) in other.node_name_to_info.iter() {
+            if let Some(existing) = self.node_name_to_info.get(name) {
+                if existing.defined && info.defined {
+                    return Err(MergeError::NodeDefinedTwice(name.clone()));
+                }
+            }
+        }
+        for (name, predicate) in other.predicate_name_to_predicate.iter() {
+            let existing = self.predicate_name_to_predicate
+                .get(name)
+                .and_then(|&p| self.get_num_terms_for_predicate(p));
+            let num_terms = other.get_num_terms_for_predicate(*predicate);
+            if let (Some(existing), Some(num_terms)) = (existing, num_terms) {
+                if existing != num_terms {
+                    return Err(MergeError::WrongNumberOfTerms(name.clone()));
+                }
+            }
+        }
+        let mut remap = Remap {
+            predicates: HashMap::new(),
+            node_offset: num_nodes,
+            nodes: HashMap::new(),
+            redirects: HashMap::new(),
+        };
+        let mut predicate_names: Vec<(String, Predicate)> =
+            other.predicate_name_to_predicate.into_iter().collect();
+        predicate_names.sort_by_key(|&(_, p)| p.0);
+        for (name, predicate) in predicate_names {
+            let new_predicate = self.reserve_predicate(&name);
+            remap.predicates.insert(predicate, new_predicate);
+        }
+        for (predicate, num_terms) in other.num_terms_for_predicate {
+            self.check_num_terms_for_predicate(remap.predicate(predicate), num_terms);
+        }
+        for (name, info) in other.node_name_to_info {
+            let appended = NodeIndex(info.index.0 + num_nodes);
+            match self.node_name_to_info.entry(name) {
+                hash_map::Entry::Occupied(mut entry) => {
+                    if info.defined {
+                        remap.redirects.insert(entry.get().index, appended);
+                        *entry.get_mut() = NodeInfo {
+                            index: appended,
+                            defined: true,
+                        };
+                    } else {
+                        remap.nodes.insert(info.index, entry.get().index);
+                    }
+                }
+                hash_map::Entry::Vacant(entry) => {
+                    entry.insert(NodeInfo {
+                        index: appended,
+                        defined: info.defined,
+                    });
+                }
+            }
+        }
+        if let (&mut Some(ref mut comments), Some(other_comments)) =
+            (&mut self.node_comments, other.node_comments)
+        {
+            for (node, node_comments) in other_comments {
+                comments.insert(remap.node(node), node_comments);
+            }
+        }
+        Ok(remap)
+    }
+
     pub fn reserve_predicate(&mut self, name: &str) -> Predicate {
         let next_predicate = Predicate(self.predicate_name_to_predicate.len() as u64);
         if self.predicate_name_to_predicate.contains_key(name) {
@@ -73,3 +221,142 @@ impl Context {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::database_literal;
+    use diagram::{Edge, MultiDiagram, OutputTerm};
+    use graph_diagram::GraphDiagram;
+    use parse::parse_diagram;
+    use value::Value;
+
+    #[test]
+    fn can_merge_contexts_sharing_a_predicate() {
+        let (mut diagram, mut context) = parse_diagram(
+            r#"
+        root: a(_ -> %0) {
+          output b(%0)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let (other_diagram, other_context) = parse_diagram(
+            r#"
+        root: c(_ -> %0) {
+          other: output a(%0)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let other_a = other_context.predicate_name_to_predicate["a"];
+        let other_c = other_context.predicate_name_to_predicate["c"];
+        let remap = context.merge(other_context, diagram.len()).unwrap();
+        diagram.merge(&other_diagram, &remap);
+
+        let a = context.predicate_name_to_predicate["a"];
+        let b = context.predicate_name_to_predicate["b"];
+        let c = context.predicate_name_to_predicate["c"];
+        assert_eq!(context.predicate_name_to_predicate.len(), 3);
+        assert!(c != a && c != b);
+        assert_eq!(remap.predicate(other_a), a);
+        assert_eq!(remap.predicate(other_c), c);
+        assert_eq!(
+            context.node_name_to_info["other"].index,
+            remap.node(NodeIndex(0))
+        );
+
+        let input = database_literal(vec![(c, vec![Value::Symbol(1)])]);
+        let output = diagram.evaluate(&input);
+        assert_eq!(output, database_literal(vec![(a, vec![Value::Symbol(1)])]));
+    }
+
+    #[test]
+    fn merge_links_reserved_names_to_their_definition() {
+        let (mut diagram, mut context) =
+            parse_diagram("root: first: a(:2) { output b(:2) }", 1).unwrap();
+        let root = context.node_name_to_info["first"].index;
+        let later = context.reserve_node_name("later", &mut diagram).index;
+        diagram.insert_edge(Edge::Refute {
+            source: root,
+            target: later,
+        });
+        let (other_diagram, other_context) = parse_diagram("later: output c(:1)", 1).unwrap();
+        let remap = context.merge(other_context, diagram.len()).unwrap();
+        diagram.merge(&other_diagram, &remap);
+
+        let info = context.node_name_to_info["later"].clone();
+        assert!(info.defined);
+        assert_eq!(info.index, remap.node(NodeIndex(0)));
+        assert_eq!(remap.redirects[&later], info.index);
+        assert!(diagram.refute_source_group(later).is_empty());
+        let a = context.predicate_name_to_predicate["a"];
+        let c = context.predicate_name_to_predicate["c"];
+        let input = database_literal(vec![(a, vec![Value::Symbol(1)])]);
+        let output = diagram.evaluate(&input);
+        assert_eq!(output, database_literal(vec![(c, vec![Value::Symbol(1)])]));
+    }
+
+    #[test]
+    fn merge_rejects_names_defined_twice() {
+        let (_, mut context) = parse_diagram("root: first: output a(:1)", 0).unwrap();
+        let predicates = context.predicate_name_to_predicate.clone();
+        let (_, other_context) = parse_diagram("root: first: output b(:1)", 0).unwrap();
+        assert_eq!(
+            context.merge(other_context, 1).err(),
+            Some(MergeError::NodeDefinedTwice("first".to_owned()))
+        );
+        assert_eq!(context.predicate_name_to_predicate, predicates);
+        assert_eq!(context.num_terms_for_predicate.len(), 1);
+        let (_, other_context) = parse_diagram("other: output a(:1, :2)", 0).unwrap();
+        assert_eq!(
+            context.merge(other_context, 1).err(),
+            Some(MergeError::WrongNumberOfTerms("a".to_owned()))
+        );
+    }
+
+    #[cfg(feature = "serialize")]
+    #[test]
+    fn diagram_and_context_round_trip_through_serde() {
+        let (diagram, context) = parse_diagram(
+            r#"
+        root: edge(_ -> %0, _ -> %1) {
+          flip: output reversed(%1, %0)
+        } {
+          later
+        }
+        later: output @5(:1)
+        "#,
+            2,
+        ).unwrap();
+        let mut context = context;
+        // Names aren't restricted to identifiers when a context is built directly.
+        context.reserve_predicate("two words");
+        let json = serde_json::to_string(&(diagram.clone().into_parts(), &context)).unwrap();
+        let ((num_registers, roots, nodes), reloaded): (_, Context) =
+            serde_json::from_str(&json).unwrap();
+        let reloaded_diagram = GraphDiagram::from_parts(num_registers, roots, nodes).unwrap();
+        assert_eq!(reloaded_diagram, diagram);
+
+        let edge = reloaded.predicate_name_to_predicate["edge"];
+        let reversed = reloaded.predicate_name_to_predicate["reversed"];
+        assert_eq!(edge, context.predicate_name_to_predicate["edge"]);
+        assert_eq!(
+            reloaded.predicate_name_to_predicate["two words"],
+            context.predicate_name_to_predicate["two words"]
+        );
+        assert_eq!(reloaded.get_num_terms_for_predicate(reversed), Some(2));
+        assert_eq!(reloaded.get_num_terms_for_predicate(Predicate(5)), Some(1));
+        let flip = reloaded.node_name_to_info["flip"].clone();
+        assert!(flip.defined);
+        assert_eq!(flip.index, context.node_name_to_info["flip"].index);
+        assert_eq!(
+            *reloaded_diagram.get_node(flip.index),
+            Node::Output {
+                predicate: reversed,
+                terms: vec![OutputTerm::Register(1), OutputTerm::Register(0)],
+                skip_on_unbound: false,
+            }
+        );
+    }
+}
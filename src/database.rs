@@ -1,10 +1,15 @@
 use std::collections::HashMap;
 use std::collections::hash_map;
+use std::hash::{Hash, Hasher};
 
-use fact::Fact;
+use rand::Rng;
+
+use context::Context;
+
+use fact::{Fact, OwnedFact};
 use index::{Index, IndexIter};
 use predicate::Predicate;
-use simple_query::{SimpleQuery, SimpleQueryTerm};
+use simple_query::{OwnedSimpleQuery, SimpleQuery, SimpleQueryTerm};
 use table;
 use table::Table;
 use value::Value;
@@ -22,29 +27,527 @@ impl Database {
         }
     }
 
+    pub fn predicates<'a>(&'a self) -> impl Iterator<Item = Predicate> + 'a {
+        self.tables.keys().cloned()
+    }
+
+    pub fn table(&self, predicate: Predicate) -> Option<&Table> {
+        self.tables.get(&predicate)
+    }
+
+    pub fn num_facts_for_predicate(&self, predicate: Predicate) -> usize {
+        self.tables
+            .get(&predicate)
+            .map(|t| t.num_rows())
+            .unwrap_or(0)
+    }
+
+    /**
+     * Returns a database holding a copy of just `predicate`'s table, with its rows, weights and
+     * indices.
+     */
+    pub fn only_predicate(&self, predicate: Predicate) -> Database {
+        let mut result = Database::new();
+        if let Some(table) = self.tables.get(&predicate) {
+            result.tables.insert(predicate, table.clone());
+        }
+        result
+    }
+
+    pub fn len(&self) -> usize {
+        self.tables.values().map(|t| t.num_rows()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.values().all(|t| t.num_rows() == 0)
+    }
+
+    pub fn clear(&mut self) {
+        self.tables.clear();
+    }
+
+    /**
+     * Compacts every table, see `Table::compact`, and drops tables left empty. Long running
+     * sessions which retract many facts can call this periodically to reclaim memory.
+     */
+    pub fn compact(&mut self) {
+        for table in self.tables.values_mut() {
+            table.compact();
+        }
+        self.tables.retain(|_, table| table.num_rows() != 0);
+        self.tables.shrink_to_fit();
+    }
+
     pub fn insert_fact<'a, 'b>(&'a mut self, fact: Fact<'b>) {
         self.insert_fact_with_weight(fact, Weight(1));
     }
 
+    /**
+     * Adds `weight` to `fact`, see `Table::add_weight`. Weights of opposite sign cancel, and a fact
+     * whose total weight reaches zero is removed.
+     */
     pub fn insert_fact_with_weight<'a, 'b>(&'a mut self, fact: Fact<'b>, weight: Weight) {
         match self.tables.entry(fact.predicate) {
             hash_map::Entry::Occupied(mut entry) => {
-                entry.get_mut().push(fact.values, weight);
+                entry.get_mut().add_weight(fact.values, weight);
+                if entry.get().num_rows() == 0 {
+                    entry.remove();
+                }
             }
             hash_map::Entry::Vacant(entry) => {
                 let mut table = Table::new(fact.values.len());
-                table.push(fact.values, weight);
-                entry.insert(table);
+                table.add_weight(fact.values, weight);
+                if table.num_rows() != 0 {
+                    entry.insert(table);
+                }
+            }
+        };
+    }
+
+    pub fn insert_facts_bulk<'a, 'b>(&'a mut self, facts: &[(Fact<'b>, Weight)]) {
+        let mut by_predicate: HashMap<Predicate, (Vec<&'b [Value]>, Vec<Weight>)> =
+            HashMap::new();
+        for &(fact, weight) in facts {
+            let &mut (ref mut rows, ref mut weights) = by_predicate
+                .entry(fact.predicate)
+                .or_insert_with(|| (Vec::new(), Vec::new()));
+            rows.push(fact.values);
+            weights.push(weight);
+        }
+        for (predicate, (rows, weights)) in by_predicate {
+            let empty = {
+                let table = self.tables
+                    .entry(predicate)
+                    .or_insert_with(|| Table::new(rows[0].len()));
+                table.add_weights(&rows, &weights);
+                table.num_rows() == 0
+            };
+            if empty {
+                self.tables.remove(&predicate);
+            }
+        }
+    }
+
+    pub fn map_values<F>(&self, f: F) -> Database
+    where
+        F: Fn(&Value) -> Value,
+    {
+        let mut result = Database::new();
+        let mut values = Vec::new();
+        for (fact, weight) in self.weighted_facts() {
+            values.clear();
+            values.extend(fact.values.iter().map(&f));
+            result.insert_fact_with_weight(
+                Fact {
+                    predicate: fact.predicate,
+                    values: &values,
+                },
+                weight,
+            );
+        }
+        return result;
+    }
+
+    /**
+     * Renumbers symbols densely from zero, returning the renumbered database and the map from old
+     * to new values. Symbols are told apart by the predicates, columns and weights of the facts
+     * they appear in, refined by the symbols they appear alongside, then numbered in order of
+     * first appearance, visiting tables in predicate order and each table's rows sorted by those
+     * distinctions. The result's rows are sorted by their new values. So databases which differ
+     * only in their symbol numbering and fact order canonicalize to equal databases, unless they
+     * have symbols in alike positions which the refinement can't tell apart, such as the nodes
+     * of a cycle, whose numbering can still depend on fact order.
+     */
+    pub fn canonicalize_symbols(&self) -> (Database, HashMap<Value, Value>) {
+        let colors = self.symbol_colors();
+        let color_key = |value: &Value| match *value {
+            Value::Symbol(_) => colors[&value] as u64 + 1,
+            Value::Nil => 0,
+        };
+        let mut remap = HashMap::new();
+        for predicate in self.sorted_predicates() {
+            let mut rows: Vec<(Vec<u64>, i32, &[Value])> = self.tables[&predicate]
+                .weighted_rows()
+                .map(|(row, weight)| (row.iter().map(&color_key).collect(), weight.0, row))
+                .collect();
+            rows.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+            for (_, _, row) in rows {
+                for value in row {
+                    if let Value::Symbol(_) = *value {
+                        let next = Value::Symbol(remap.len() as u64);
+                        remap.entry(value.clone()).or_insert(next);
+                    }
+                }
+            }
+        }
+        let remapped = |value: &Value| remap.get(value).cloned().unwrap_or_else(|| value.clone());
+        let canonical_key = |value: &Value| match *value {
+            Value::Symbol(symbol) => symbol + 1,
+            Value::Nil => 0,
+        };
+        let mut result = Database::new();
+        for predicate in self.sorted_predicates() {
+            let mut rows: Vec<(Vec<Value>, Weight)> = self.tables[&predicate]
+                .weighted_rows()
+                .map(|(row, weight)| (row.iter().map(&remapped).collect(), weight))
+                .collect();
+            rows.sort_by_key(|&(ref row, weight)| {
+                (row.iter().map(&canonical_key).collect::<Vec<_>>(), weight.0)
+            });
+            for (values, weight) in rows {
+                result.insert_fact_with_weight(
+                    Fact {
+                        predicate,
+                        values: &values,
+                    },
+                    weight,
+                );
+            }
+        }
+        (result, remap)
+    }
+
+    /**
+     * Colors each symbol so that symbols get different colors if the facts they appear in differ
+     * in predicate, column, weight, or the colors of the other values, repeating until no more
+     * symbols are told apart. Colors are numbered in order of these descriptions, so they don't
+     * depend on how symbols are numbered.
+     */
+    fn symbol_colors(&self) -> HashMap<&Value, usize> {
+        let mut colors: HashMap<&Value, usize> = HashMap::new();
+        for table in self.tables.values() {
+            for row in table.iter() {
+                for value in row {
+                    if let Value::Symbol(_) = *value {
+                        colors.insert(value, 0);
+                    }
+                }
+            }
+        }
+        // A symbol's color, and the predicate, column, weight and value colors of each of its
+        // occurrences.
+        type Signature = (usize, Vec<(u64, usize, i32, Vec<usize>)>);
+        let mut num_colors = 1;
+        loop {
+            let mut signatures: HashMap<&Value, Signature> = HashMap::new();
+            for (predicate, table) in self.tables.iter() {
+                for (row, weight) in table.weighted_rows() {
+                    let key: Vec<usize> = row.iter()
+                        .map(|value| colors.get(value).map_or(0, |color| color + 1))
+                        .collect();
+                    for (column, value) in row.iter().enumerate() {
+                        if let Some(&color) = colors.get(value) {
+                            signatures
+                                .entry(value)
+                                .or_insert_with(|| (color, Vec::new()))
+                                .1
+                                .push((predicate.0, column, weight.0, key.clone()));
+                        }
+                    }
+                }
+            }
+            for signature in signatures.values_mut() {
+                signature.1.sort();
+            }
+            let mut distinct: Vec<_> = signatures.values().cloned().collect();
+            distinct.sort();
+            distinct.dedup();
+            if distinct.len() == num_colors || distinct.is_empty() {
+                return colors;
+            }
+            num_colors = distinct.len();
+            for (value, signature) in signatures {
+                colors.insert(value, distinct.binary_search(&signature).unwrap());
+            }
+        }
+    }
+
+    fn sorted_predicates(&self) -> Vec<Predicate> {
+        let mut predicates: Vec<Predicate> = self.predicates().collect();
+        predicates.sort_by_key(|predicate| predicate.0);
+        predicates
+    }
+
+    /**
+     * Returns a database containing each row of this one, with its weight, with probability
+     * `fraction`. Tables are visited in predicate order, so the result only depends on the
+     * contents of the database and the state of `rng`.
+     */
+    pub fn sample<R: Rng>(&self, rng: &mut R, fraction: f64) -> Database {
+        let mut result = Database::new();
+        for predicate in self.sorted_predicates() {
+            for (_, values, weight) in self.tables[&predicate].enumerate_rows() {
+                if rng.gen::<f64>() < fraction {
+                    result.insert_fact_with_weight(Fact { predicate, values }, weight);
+                }
             }
+        }
+        return result;
+    }
+
+    /**
+     * Returns a database containing `n` rows, chosen uniformly without replacement, from each
+     * table of this one, or every row of tables with fewer than `n` rows. Rows keep their weight
+     * and relative order.
+     */
+    pub fn sample_n<R: Rng>(&self, rng: &mut R, n: usize) -> Database {
+        let mut result = Database::new();
+        for predicate in self.sorted_predicates() {
+            let table = &self.tables[&predicate];
+            let mut rows: Vec<usize> = (0..table.num_rows()).collect();
+            rng.shuffle(&mut rows);
+            rows.truncate(n);
+            rows.sort();
+            for row in rows {
+                result.insert_fact_with_weight(
+                    Fact {
+                        predicate,
+                        values: table.row(row),
+                    },
+                    table.weight(row),
+                );
+            }
+        }
+        return result;
+    }
+
+    /**
+     * Joins the facts of `left` and `right` whose values are equal in each pair of `on` columns,
+     * into facts of `out` holding the left values followed by the right values. The weight of
+     * each output fact is the product of the joined weights. Returns `None` if a column of `on`
+     * is beyond the number of columns of its table.
+     */
+    pub fn join(
+        &self,
+        left: Predicate,
+        right: Predicate,
+        on: &[(usize, usize)],
+        out: Predicate,
+    ) -> Option<Database> {
+        let mut result = Database::new();
+        let (left_table, right_table) = match (self.tables.get(&left), self.tables.get(&right)) {
+            (Some(l), Some(r)) => (l, r),
+            _ => return Some(result),
         };
+        let in_bounds = on.iter().all(|&(l, r)| {
+            l < left_table.num_columns() && r < right_table.num_columns()
+        });
+        if !in_bounds {
+            return None;
+        }
+        let mut right_rows: HashMap<Vec<Value>, Vec<usize>> = HashMap::new();
+        for row in 0..right_table.num_rows() {
+            let values = right_table.row(row);
+            let key = on.iter().map(|&(_, c)| values[c].clone()).collect();
+            right_rows.entry(key).or_insert_with(Vec::new).push(row);
+        }
+        let mut key = Vec::with_capacity(on.len());
+        let mut values = Vec::new();
+        for (left_values, left_weight) in left_table.weighted_rows() {
+            key.clear();
+            key.extend(on.iter().map(|&(c, _)| left_values[c].clone()));
+            if let Some(rows) = right_rows.get(&key) {
+                for &row in rows {
+                    values.clear();
+                    values.extend_from_slice(left_values);
+                    values.extend_from_slice(right_table.row(row));
+                    result.insert_fact_with_weight(
+                        Fact {
+                            predicate: out,
+                            values: &values,
+                        },
+                        left_weight.saturating_mul(right_table.weight(row)),
+                    );
+                }
+            }
+        }
+        return Some(result);
+    }
+
+    /**
+     * Buckets the facts of `predicate` by their value in `column`, in insertion order within each
+     * bucket. Returns `None` if `predicate` has no column `column`.
+     */
+    pub fn group_by(
+        &self,
+        predicate: Predicate,
+        column: usize,
+    ) -> Option<HashMap<Value, Vec<OwnedFact>>> {
+        let mut groups = HashMap::new();
+        if let Some(table) = self.tables.get(&predicate) {
+            if column >= table.num_columns() {
+                return None;
+            }
+            for values in table.iter() {
+                groups
+                    .entry(values[column].clone())
+                    .or_insert_with(Vec::new)
+                    .push(Fact { predicate, values }.to_owned_fact());
+            }
+        }
+        Some(groups)
+    }
+
+    /**
+     * Renders one fact per line, sorted, so that the result is stable across runs. Predicates are
+     * printed by name when `context` knows them, and facts with a weight other than one are
+     * suffixed with their weight.
+     */
+    pub fn to_display_string(&self, context: Option<&Context>) -> String {
+        let mut names: HashMap<Predicate, &str> = HashMap::new();
+        if let Some(context) = context {
+            for (name, &predicate) in context.predicate_name_to_predicate.iter() {
+                names.insert(predicate, name);
+            }
+        }
+        let mut lines: Vec<String> = self.weighted_facts()
+            .map(|(fact, weight)| {
+                let mut line = match names.get(&fact.predicate) {
+                    Some(name) => name.to_string(),
+                    None => format!("@{}", fact.predicate.0),
+                };
+                let values: Vec<String> = fact.values
+                    .iter()
+                    .map(|value| match *value {
+                        Value::Symbol(n) => format!(":{}", n),
+                        Value::Nil => "nil".to_owned(),
+                    })
+                    .collect();
+                line.push_str(&format!("({})", values.join(", ")));
+                if weight != Weight(1) {
+                    line.push_str(&format!(" ×{}", weight));
+                }
+                line
+            })
+            .collect();
+        lines.sort();
+        let mut result = String::new();
+        for line in lines {
+            result.push_str(&line);
+            result.push('\n');
+        }
+        return result;
+    }
+
+    /**
+     * Hashes the facts with their total weights in a canonical form, so that databases which
+     * contain the same facts hash equally regardless of insertion order or of how each fact's
+     * weight is split across rows. Facts whose total weight is zero are ignored.
+     */
+    pub fn content_hash(&self) -> u64 {
+        let mut totals: HashMap<Fact, Weight> = HashMap::new();
+        for (fact, weight) in self.weighted_facts() {
+            let total = totals.entry(fact).or_insert(Weight(0));
+            *total = total.saturating_add(weight);
+        }
+        let mut fact_hashes: Vec<u64> = totals
+            .into_iter()
+            .filter(|&(_, weight)| !weight.is_zero())
+            .map(|(fact, weight)| {
+                let mut hasher = hash_map::DefaultHasher::new();
+                fact.predicate.hash(&mut hasher);
+                fact.values.hash(&mut hasher);
+                weight.hash(&mut hasher);
+                hasher.finish()
+            })
+            .collect();
+        fact_hashes.sort();
+        let mut hasher = hash_map::DefaultHasher::new();
+        fact_hashes.hash(&mut hasher);
+        return hasher.finish();
+    }
+
+    /**
+     * Subtracts the total weight of each fact in `other` from its total weight in `self`. Facts
+     * whose remaining weight isn't positive are dropped.
+     */
+    pub fn saturating_sub(&self, other: &Database) -> Database {
+        let mut other_weights: HashMap<Fact, Weight> = HashMap::new();
+        for (fact, weight) in other.weighted_facts() {
+            let total = other_weights.entry(fact).or_insert(Weight(0));
+            *total = total.saturating_add(weight);
+        }
+        let mut order = Vec::new();
+        let mut weights: HashMap<Fact, Weight> = HashMap::new();
+        for (fact, weight) in self.weighted_facts() {
+            match weights.entry(fact) {
+                hash_map::Entry::Occupied(mut entry) => {
+                    let total = entry.get().saturating_add(weight);
+                    *entry.get_mut() = total;
+                }
+                hash_map::Entry::Vacant(entry) => {
+                    order.push(fact);
+                    entry.insert(weight);
+                }
+            }
+        }
+        let mut result = Database::new();
+        for fact in order {
+            let subtracted = other_weights.get(&fact).cloned().unwrap_or(Weight(0));
+            let remaining = weights[&fact].0.saturating_sub(subtracted.0);
+            if remaining > 0 {
+                result.insert_fact_with_weight(fact, Weight(remaining));
+            }
+        }
+        return result;
+    }
+
+    /**
+     * Returns whether both databases contain the same facts, ignoring row order, duplicate rows,
+     * and weights.
+     */
+    pub fn semantically_eq(&self, other: &Database) -> bool {
+        self.all_facts().all(|fact| other.contains(fact))
+            && other.all_facts().all(|fact| self.contains(fact))
+    }
+
+    pub fn add_index(&mut self, predicate: Predicate, column: usize, value: Value) -> bool {
+        if let Some(table) = self.tables.get_mut(&predicate) {
+            table.add_index(column, value);
+            true
+        } else {
+            false
+        }
     }
 
     pub fn simple_query<'a, 'b, 'c>(
         &'a self,
         query: SimpleQuery<'b, 'c>,
     ) -> SimpleQueryIter<'a, 'b, 'c> {
+        let inner = self.tables.get(&query.predicate).map(|table| {
+            query
+                .terms
+                .iter()
+                .enumerate()
+                .filter_map(|(column, term)| match *term {
+                    SimpleQueryTerm::Constant { value } => table.iter_index(column, value),
+                    SimpleQueryTerm::NotConstant { .. } | SimpleQueryTerm::Free => None,
+                })
+                .next()
+                .unwrap_or_else(|| table.iter())
+        });
         SimpleQueryIter {
-            predicate_iter: self.facts_for_predicate(query.predicate),
+            predicate_iter: PredicateIter {
+                predicate: query.predicate,
+                inner,
+            },
+            query,
+        }
+    }
+
+    /**
+     * Runs `query`, returning copies of the matching facts.
+     */
+    pub fn owned_simple_query(&self, query: &OwnedSimpleQuery) -> Vec<OwnedFact> {
+        query.with_query(|query| self.simple_query(query).into_owned())
+    }
+
+    pub fn weighted_simple_query<'a, 'b, 'c>(
+        &'a self,
+        query: SimpleQuery<'b, 'c>,
+    ) -> WeightedSimpleQueryIter<'a, 'b, 'c> {
+        WeightedSimpleQueryIter {
+            rows: self.tables.get(&query.predicate).map(|t| t.weighted_rows()),
             query,
         }
     }
@@ -70,6 +573,14 @@ impl Database {
         }
     }
 
+    pub fn into_weighted_facts(self) -> IntoWeightedFacts {
+        IntoWeightedFacts {
+            tables_iter: self.tables.into_iter(),
+            current_table: None,
+            row: 0,
+        }
+    }
+
     pub fn contains(&self, fact: Fact) -> bool {
         if let Some(table) = self.tables.get(&fact.predicate) {
             for row in table.iter() {
@@ -82,15 +593,15 @@ impl Database {
     }
 
     pub fn weight(&self, fact: Fact) -> Weight {
-        let mut total = 0;
+        let mut total = Weight(0);
         if let Some(table) = self.tables.get(&fact.predicate) {
             for (row, weight) in table.weighted_rows() {
                 if row == fact.values {
-                    total += weight.0;
+                    total = total.saturating_add(weight);
                 }
             }
         }
-        return Weight(total);
+        return total;
     }
 }
 
@@ -100,6 +611,15 @@ pub struct PredicateIter<'a> {
     inner: Option<table::Iter<'a>>,
 }
 
+impl<'a> PredicateIter<'a> {
+    /**
+     * Collects the remaining facts into owned facts, which can outlive the database.
+     */
+    pub fn into_owned(self) -> Vec<OwnedFact> {
+        self.map(|fact| fact.to_owned_fact()).collect()
+    }
+}
+
 impl<'a> Iterator for PredicateIter<'a> {
     type Item = Fact<'a>;
 
@@ -121,6 +641,12 @@ pub struct AllFactIter<'a> {
     inner: WeightedFacts<'a>,
 }
 
+impl<'a> AllFactIter<'a> {
+    pub fn into_owned(self) -> Vec<OwnedFact> {
+        self.map(|fact| fact.to_owned_fact()).collect()
+    }
+}
+
 impl<'a> Iterator for AllFactIter<'a> {
     type Item = Fact<'a>;
 
@@ -164,12 +690,47 @@ impl<'a> Iterator for WeightedFacts<'a> {
     }
 }
 
+#[derive(Debug)]
+pub struct IntoWeightedFacts {
+    tables_iter: hash_map::IntoIter<Predicate, Table>,
+    current_table: Option<(Predicate, Table)>,
+    row: usize,
+}
+
+impl Iterator for IntoWeightedFacts {
+    type Item = (OwnedFact, Weight);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((predicate, ref table)) = self.current_table {
+            if self.row < table.num_rows() {
+                let values = table.row(self.row).to_vec();
+                let weight = table.weight(self.row);
+                self.row += 1;
+                return Some((OwnedFact { predicate, values }, weight));
+            }
+        };
+        if let Some(current_table) = self.tables_iter.next() {
+            self.current_table = Some(current_table);
+            self.row = 0;
+            return self.next();
+        } else {
+            return None;
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct SimpleQueryIter<'a, 'b, 'c: 'b> {
     predicate_iter: PredicateIter<'a>,
     query: SimpleQuery<'b, 'c>,
 }
 
+impl<'a, 'b, 'c> SimpleQueryIter<'a, 'b, 'c> {
+    pub fn into_owned(self) -> Vec<OwnedFact> {
+        self.map(|fact| fact.to_owned_fact()).collect()
+    }
+}
+
 impl<'a, 'b, 'c> Iterator for SimpleQueryIter<'a, 'b, 'c> {
     type Item = Fact<'a>;
 
@@ -183,6 +744,31 @@ impl<'a, 'b, 'c> Iterator for SimpleQueryIter<'a, 'b, 'c> {
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct WeightedSimpleQueryIter<'a, 'b, 'c: 'b> {
+    rows: Option<table::WeightedRows<'a>>,
+    query: SimpleQuery<'b, 'c>,
+}
+
+impl<'a, 'b, 'c> Iterator for WeightedSimpleQueryIter<'a, 'b, 'c> {
+    type Item = (Fact<'a>, Weight);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(ref mut rows) = self.rows {
+            while let Some((values, weight)) = rows.next() {
+                let fact = Fact {
+                    predicate: self.query.predicate,
+                    values,
+                };
+                if self.query.matches(fact) {
+                    return Some((fact, weight));
+                }
+            }
+        }
+        return None;
+    }
+}
+
 pub fn database_literal(data: Vec<(Predicate, Vec<Value>)>) -> Database {
     let mut db = Database::new();
     for &(predicate, ref values) in data.iter() {
@@ -192,11 +778,28 @@ pub fn database_literal(data: Vec<(Predicate, Vec<Value>)>) -> Database {
     return db;
 }
 
+pub fn symbols(predicate: Predicate, rows: &[&[u64]]) -> Database {
+    let mut db = Database::new();
+    let mut values = Vec::new();
+    for row in rows {
+        values.clear();
+        values.extend(row.iter().map(|&s| Value::Symbol(s)));
+        db.insert_fact(Fact {
+            predicate,
+            values: &values,
+        });
+    }
+    return db;
+}
+
 #[cfg(test)]
 mod tests {
+    use std::i32;
+
     use super::*;
     use predicate;
-    use simple_query::SimpleQueryTerm;
+    use rand::{SeedableRng, XorShiftRng};
+    use simple_query::{OwnedSimpleQueryTerm, SimpleQueryBuilder, SimpleQueryTerm};
 
     fn insert_symbols_run_query_expect_rows(
         symbols: &[&[u64]],
@@ -309,4 +912,670 @@ mod tests {
         let query = SimpleQuery { predicate, terms };
         insert_symbols_run_query_expect_rows(&symbols, query, &[0, 4]);
     }
+
+    #[test]
+    fn isomorphic_databases_canonicalize_equal() {
+        let edge = predicate::Predicate(0);
+        let node = predicate::Predicate(1);
+        let a = database_literal(vec![
+            (node, vec![Value::Symbol(5), Value::Nil]),
+            (edge, vec![Value::Symbol(5), Value::Symbol(7)]),
+            (edge, vec![Value::Symbol(7), Value::Symbol(9)]),
+        ]);
+        let b = database_literal(vec![
+            (edge, vec![Value::Symbol(1), Value::Symbol(4)]),
+            (node, vec![Value::Symbol(2), Value::Nil]),
+            (edge, vec![Value::Symbol(2), Value::Symbol(1)]),
+        ]);
+        assert!(a != b);
+        let (canonical_a, remap_a) = a.canonicalize_symbols();
+        let (canonical_b, remap_b) = b.canonicalize_symbols();
+        assert_eq!(canonical_a, canonical_b);
+        assert_eq!(
+            canonical_a,
+            database_literal(vec![
+                (edge, vec![Value::Symbol(0), Value::Symbol(1)]),
+                (edge, vec![Value::Symbol(2), Value::Symbol(0)]),
+                (node, vec![Value::Symbol(2), Value::Nil]),
+            ])
+        );
+        assert_eq!(remap_a[&Value::Symbol(9)], Value::Symbol(1));
+        assert_eq!(remap_b[&Value::Symbol(4)], Value::Symbol(1));
+        assert_eq!(remap_a.len(), 3);
+
+        let reordered = database_literal(vec![
+            (edge, vec![Value::Symbol(3), Value::Symbol(8)]),
+            (edge, vec![Value::Symbol(6), Value::Symbol(3)]),
+            (node, vec![Value::Symbol(6), Value::Nil]),
+        ]);
+        assert_eq!(reordered.canonicalize_symbols().0, canonical_a);
+    }
+
+    #[test]
+    fn iterators_collect_owned_facts() {
+        let predicate = predicate::Predicate(0);
+        let owned = {
+            let db = database_literal(vec![
+                (predicate, vec![Value::Symbol(1), Value::Symbol(2)]),
+                (predicate, vec![Value::Symbol(2), Value::Symbol(3)]),
+            ]);
+            let terms = &[
+                SimpleQueryTerm::Constant {
+                    value: &Value::Symbol(2),
+                },
+                SimpleQueryTerm::Free,
+            ];
+            let query = SimpleQuery { predicate, terms };
+            assert_eq!(db.all_facts().into_owned().len(), 2);
+            assert_eq!(db.facts_for_predicate(predicate).into_owned().len(), 2);
+            db.simple_query(query).into_owned()
+        };
+        assert_eq!(
+            owned,
+            vec![OwnedFact {
+                predicate,
+                values: vec![Value::Symbol(2), Value::Symbol(3)],
+            }]
+        );
+    }
+
+    #[test]
+    fn compaction_keeps_remaining_facts() {
+        let edge = predicate::Predicate(0);
+        let node = predicate::Predicate(1);
+        let mut db = Database::new();
+        for i in 0..100 {
+            let values = [Value::Symbol(i % 3), Value::Symbol(i)];
+            db.insert_fact(Fact {
+                predicate: edge,
+                values: &values,
+            });
+            db.insert_fact(Fact {
+                predicate: node,
+                values: &values[1..],
+            });
+        }
+        db.insert_fact_with_weight(
+            Fact {
+                predicate: edge,
+                values: &[Value::Symbol(1), Value::Symbol(1)],
+            },
+            Weight(2),
+        );
+        assert!(db.add_index(edge, 0, Value::Symbol(1)));
+        for i in 0..100 {
+            if i % 10 != 1 {
+                let values = [Value::Symbol(i % 3), Value::Symbol(i)];
+                db.insert_fact_with_weight(
+                    Fact {
+                        predicate: edge,
+                        values: &values,
+                    },
+                    Weight(-1),
+                );
+            }
+            db.insert_fact_with_weight(
+                Fact {
+                    predicate: node,
+                    values: &[Value::Symbol(i)],
+                },
+                Weight(-1),
+            );
+        }
+        let before = db.clone();
+        db.compact();
+        assert!(db.semantically_eq(&before));
+        assert_eq!(db.len(), 10);
+        assert!(db.table(node).is_none());
+        for i in 0..100 {
+            let values = [Value::Symbol(i % 3), Value::Symbol(i)];
+            let expected = match i {
+                1 => 3,
+                i if i % 10 == 1 => 1,
+                _ => 0,
+            };
+            assert_eq!(
+                db.weight(Fact {
+                    predicate: edge,
+                    values: &values,
+                }),
+                Weight(expected)
+            );
+        }
+        let indexed: Vec<_> = db.table(edge)
+            .unwrap()
+            .iter_index(0, &Value::Symbol(1))
+            .unwrap()
+            .map(|row| row.to_vec())
+            .collect();
+        assert_eq!(
+            indexed,
+            vec![
+                vec![Value::Symbol(1), Value::Symbol(1)],
+                vec![Value::Symbol(1), Value::Symbol(31)],
+                vec![Value::Symbol(1), Value::Symbol(61)],
+                vec![Value::Symbol(1), Value::Symbol(91)],
+            ]
+        );
+    }
+
+    #[test]
+    fn index_is_maintained_on_push() {
+        let predicate = predicate::Predicate(0);
+        let mut db = database_literal(vec![
+            (predicate, vec![Value::Symbol(1), Value::Symbol(2)]),
+            (predicate, vec![Value::Symbol(2), Value::Symbol(1)]),
+        ]);
+        assert!(db.add_index(predicate, 0, Value::Symbol(1)));
+        db.insert_fact(Fact {
+            predicate,
+            values: &[Value::Symbol(1), Value::Symbol(3)],
+        });
+        db.insert_fact(Fact {
+            predicate,
+            values: &[Value::Symbol(2), Value::Symbol(3)],
+        });
+        let terms = &[
+            SimpleQueryTerm::Constant {
+                value: &Value::Symbol(1),
+            },
+            SimpleQueryTerm::Free,
+        ];
+        let query = SimpleQuery { predicate, terms };
+        let results: Vec<_> = db.simple_query(query).collect();
+        assert_eq!(
+            results,
+            vec![
+                Fact {
+                    predicate,
+                    values: &[Value::Symbol(1), Value::Symbol(2)],
+                },
+                Fact {
+                    predicate,
+                    values: &[Value::Symbol(1), Value::Symbol(3)],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn builder_queries_match_manual_queries() {
+        let db = symbols(Predicate(0), &[&[1, 2, 3], &[1, 4, 3], &[2, 2, 3], &[1, 2, 5]]);
+        let query = SimpleQueryBuilder::new(Predicate(0), 3)
+            .not_eq(1, Value::Symbol(4))
+            .eq(0, Value::Symbol(1))
+            .build();
+        assert_eq!(
+            query.terms,
+            vec![
+                OwnedSimpleQueryTerm::Constant(Value::Symbol(1)),
+                OwnedSimpleQueryTerm::NotConstant(Value::Symbol(4)),
+                OwnedSimpleQueryTerm::Free,
+            ]
+        );
+        let expected = vec![
+            OwnedFact {
+                predicate: Predicate(0),
+                values: vec![Value::Symbol(1), Value::Symbol(2), Value::Symbol(3)],
+            },
+            OwnedFact {
+                predicate: Predicate(0),
+                values: vec![Value::Symbol(1), Value::Symbol(2), Value::Symbol(5)],
+            },
+        ];
+        assert_eq!(db.owned_simple_query(&query), expected);
+        let query = SimpleQueryBuilder::new(Predicate(0), 3)
+            .eq(2, Value::Symbol(5))
+            .free(2)
+            .build();
+        assert_eq!(db.owned_simple_query(&query).len(), 4);
+    }
+
+    #[test]
+    #[should_panic]
+    fn builder_rejects_columns_beyond_arity() {
+        SimpleQueryBuilder::new(Predicate(0), 3).eq(5, Value::Symbol(1));
+    }
+
+    #[test]
+    fn owned_queries_can_be_stored() {
+        struct Watch {
+            query: OwnedSimpleQuery,
+        }
+
+        fn watch_first_column(value: u64) -> Watch {
+            Watch {
+                query: SimpleQueryBuilder::new(Predicate(0), 2)
+                    .eq(0, Value::Symbol(value))
+                    .build(),
+            }
+        }
+
+        let watch = watch_first_column(1);
+        let db = symbols(Predicate(0), &[&[1, 2], &[2, 3], &[1, 4]]);
+        let total: i32 = watch
+            .query
+            .with_query(|query| db.weighted_simple_query(query).map(|(_, w)| w.0).sum());
+        assert_eq!(total, 2);
+        assert!(watch.query.matches(Fact {
+            predicate: Predicate(0),
+            values: &[Value::Symbol(1), Value::Symbol(5)],
+        }));
+    }
+
+    #[test]
+    fn can_sum_weights_of_filtered_rows() {
+        let predicate = predicate::Predicate(0);
+        let mut db = Database::new();
+        db.insert_fact_with_weight(
+            Fact {
+                predicate,
+                values: &[Value::Symbol(1), Value::Symbol(2)],
+            },
+            Weight(3),
+        );
+        db.insert_fact_with_weight(
+            Fact {
+                predicate,
+                values: &[Value::Symbol(2), Value::Symbol(2)],
+            },
+            Weight(5),
+        );
+        db.insert_fact_with_weight(
+            Fact {
+                predicate,
+                values: &[Value::Symbol(1), Value::Symbol(3)],
+            },
+            Weight(7),
+        );
+        let terms = &[
+            SimpleQueryTerm::Constant {
+                value: &Value::Symbol(1),
+            },
+            SimpleQueryTerm::Free,
+        ];
+        let query = SimpleQuery { predicate, terms };
+        let total: i32 = db.weighted_simple_query(query).map(|(_, w)| w.0).sum();
+        assert_eq!(total, 10);
+    }
+
+    #[test]
+    fn bulk_insert_matches_per_fact_insert() {
+        let values: Vec<Vec<Value>> = (0..10)
+            .map(|i| vec![Value::Symbol(i), Value::Symbol(i % 3)])
+            .collect();
+        let facts: Vec<_> = values
+            .iter()
+            .enumerate()
+            .map(|(i, vs)| {
+                (
+                    Fact {
+                        predicate: predicate::Predicate(i as u64 % 2),
+                        values: vs,
+                    },
+                    Weight(i as i32),
+                )
+            })
+            .collect();
+        let mut bulk = Database::new();
+        bulk.insert_facts_bulk(&facts);
+        let mut per_fact = Database::new();
+        for &(fact, weight) in facts.iter() {
+            per_fact.insert_fact_with_weight(fact, weight);
+        }
+        assert_eq!(bulk, per_fact);
+    }
+
+    #[test]
+    fn can_list_predicates() {
+        let db = database_literal(vec![
+            (predicate::Predicate(0), vec![Value::Symbol(1)]),
+            (predicate::Predicate(2), vec![Value::Symbol(1), Value::Symbol(2)]),
+            (predicate::Predicate(2), vec![Value::Symbol(3), Value::Symbol(4)]),
+        ]);
+        let mut predicates: Vec<_> = db.predicates().collect();
+        predicates.sort_by_key(|p| p.0);
+        assert_eq!(
+            predicates,
+            vec![predicate::Predicate(0), predicate::Predicate(2)]
+        );
+        assert_eq!(db.table(predicate::Predicate(2)).unwrap().num_rows(), 2);
+        assert!(db.table(predicate::Predicate(1)).is_none());
+    }
+
+    #[test]
+    fn can_map_values() {
+        let mut db = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1), Value::Symbol(2)]),
+            (Predicate(1), vec![Value::Symbol(3)]),
+        ]);
+        db.insert_fact_with_weight(
+            Fact {
+                predicate: Predicate(1),
+                values: &[Value::Nil],
+            },
+            Weight(3),
+        );
+        let mapped = db.map_values(|value| match *value {
+            Value::Symbol(n) => Value::Symbol(n + 100),
+            ref other => other.clone(),
+        });
+        let mut expected = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(101), Value::Symbol(102)]),
+            (Predicate(1), vec![Value::Symbol(103)]),
+        ]);
+        expected.insert_fact_with_weight(
+            Fact {
+                predicate: Predicate(1),
+                values: &[Value::Nil],
+            },
+            Weight(3),
+        );
+        assert_eq!(mapped, expected);
+    }
+
+    #[test]
+    fn can_join_on_shared_column() {
+        let mut db = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1), Value::Symbol(2)]),
+            (Predicate(0), vec![Value::Symbol(3), Value::Symbol(4)]),
+            (Predicate(1), vec![Value::Symbol(2), Value::Symbol(5)]),
+            (Predicate(1), vec![Value::Symbol(6), Value::Symbol(7)]),
+        ]);
+        db.insert_fact_with_weight(
+            Fact {
+                predicate: Predicate(1),
+                values: &[Value::Symbol(2), Value::Symbol(8)],
+            },
+            Weight(3),
+        );
+        let joined = db.join(Predicate(0), Predicate(1), &[(1, 0)], Predicate(2)).unwrap();
+        let mut expected = database_literal(vec![(
+            Predicate(2),
+            vec![
+                Value::Symbol(1),
+                Value::Symbol(2),
+                Value::Symbol(2),
+                Value::Symbol(5),
+            ],
+        )]);
+        expected.insert_fact_with_weight(
+            Fact {
+                predicate: Predicate(2),
+                values: &[
+                    Value::Symbol(1),
+                    Value::Symbol(2),
+                    Value::Symbol(2),
+                    Value::Symbol(8),
+                ],
+            },
+            Weight(3),
+        );
+        assert_eq!(joined, expected);
+        assert_eq!(db.join(Predicate(0), Predicate(1), &[(2, 0)], Predicate(2)), None);
+        assert_eq!(db.join(Predicate(0), Predicate(1), &[(0, 2)], Predicate(2)), None);
+        assert_eq!(
+            db.join(Predicate(0), Predicate(3), &[(5, 5)], Predicate(2)),
+            Some(Database::new())
+        );
+    }
+
+    #[test]
+    fn join_saturates_weights() {
+        let mut db = Database::new();
+        for &(predicate, weight) in &[(Predicate(0), i32::MAX), (Predicate(1), 2)] {
+            db.insert_fact_with_weight(
+                Fact {
+                    predicate,
+                    values: &[Value::Symbol(1)],
+                },
+                Weight(weight),
+            );
+        }
+        let joined = db.join(Predicate(0), Predicate(1), &[(0, 0)], Predicate(2)).unwrap();
+        assert_eq!(
+            joined.weight(Fact {
+                predicate: Predicate(2),
+                values: &[Value::Symbol(1), Value::Symbol(1)],
+            }),
+            Weight(i32::MAX)
+        );
+    }
+
+    #[test]
+    fn display_string_is_sorted() {
+        let mut db = database_literal(vec![
+            (Predicate(1), vec![Value::Symbol(3)]),
+            (Predicate(0), vec![Value::Symbol(2), Value::Nil]),
+            (Predicate(1), vec![Value::Symbol(1)]),
+        ]);
+        db.insert_fact_with_weight(
+            Fact {
+                predicate: Predicate(0),
+                values: &[Value::Symbol(1), Value::Symbol(1)],
+            },
+            Weight(2),
+        );
+        assert_eq!(
+            db.to_display_string(None),
+            "@0(:1, :1) ×2\n@0(:2, nil)\n@1(:1)\n@1(:3)\n"
+        );
+        let mut context = Context::new();
+        assert_eq!(context.reserve_predicate("edge"), Predicate(0));
+        assert_eq!(
+            db.to_display_string(Some(&context)),
+            "@1(:1)\n@1(:3)\nedge(:1, :1) ×2\nedge(:2, nil)\n"
+        );
+    }
+
+    #[test]
+    fn display_string_shows_multiplicity() {
+        assert_eq!(format!("{}", Weight(3)), "3");
+        let mut db = symbols(Predicate(0), &[&[1], &[2], &[2]]);
+        db.insert_fact_with_weight(
+            Fact {
+                predicate: Predicate(0),
+                values: &[Value::Symbol(3)],
+            },
+            Weight(-1),
+        );
+        assert_eq!(db.to_display_string(None), "@0(:1)\n@0(:2)\n@0(:2)\n@0(:3) ×-1\n");
+        db.compact();
+        assert_eq!(db.to_display_string(None), "@0(:1)\n@0(:2) ×2\n@0(:3) ×-1\n");
+    }
+
+    #[test]
+    fn symbols_matches_database_literal() {
+        let db = symbols(Predicate(3), &[&[1, 2], &[3, 4]]);
+        assert_eq!(
+            db,
+            database_literal(vec![
+                (Predicate(3), vec![Value::Symbol(1), Value::Symbol(2)]),
+                (Predicate(3), vec![Value::Symbol(3), Value::Symbol(4)]),
+            ])
+        );
+    }
+
+    #[test]
+    fn content_hash_ignores_insertion_order() {
+        let a = symbols(Predicate(0), &[&[1, 2], &[3, 4], &[5, 6]]);
+        let b = symbols(Predicate(0), &[&[5, 6], &[1, 2], &[3, 4]]);
+        assert_eq!(a.content_hash(), b.content_hash());
+        let mut c = symbols(Predicate(0), &[&[1, 2], &[3, 4]]);
+        c.insert_fact_with_weight(
+            Fact {
+                predicate: Predicate(0),
+                values: &[Value::Symbol(5), Value::Symbol(6)],
+            },
+            Weight(2),
+        );
+        assert!(a.content_hash() != c.content_hash());
+
+        let mut d = symbols(Predicate(0), &[&[5, 6], &[1, 2], &[5, 6], &[3, 4]]);
+        assert_eq!(c.content_hash(), d.content_hash());
+        d.insert_fact_with_weight(
+            Fact {
+                predicate: Predicate(0),
+                values: &[Value::Symbol(5), Value::Symbol(6)],
+            },
+            Weight(-1),
+        );
+        assert_eq!(a.content_hash(), d.content_hash());
+    }
+
+    #[test]
+    fn only_predicate_copies_one_table() {
+        let mut db = symbols(Predicate(0), &[&[1, 2], &[3, 4]]);
+        let fact = Fact {
+            predicate: Predicate(1),
+            values: &[Value::Symbol(5)],
+        };
+        db.insert_fact_with_weight(fact, Weight(3));
+        db.insert_fact(Fact {
+            predicate: Predicate(2),
+            values: &[Value::Symbol(6)],
+        });
+        let only = db.only_predicate(Predicate(1));
+        assert_eq!(only.len(), 1);
+        assert_eq!(only.predicates().collect::<Vec<_>>(), vec![Predicate(1)]);
+        assert_eq!(only.weight(fact), Weight(3));
+        assert_eq!(
+            db.only_predicate(Predicate(0)),
+            symbols(Predicate(0), &[&[1, 2], &[3, 4]])
+        );
+        assert_eq!(db.only_predicate(Predicate(3)), Database::new());
+    }
+
+    #[test]
+    fn duplicate_weights_saturate() {
+        let fact = Fact {
+            predicate: Predicate(0),
+            values: &[Value::Symbol(1)],
+        };
+        let mut db = Database::new();
+        db.insert_fact_with_weight(fact, Weight(i32::MAX));
+        db.insert_fact_with_weight(fact, Weight(i32::MAX));
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.weight(fact), Weight(i32::MAX));
+    }
+
+    #[test]
+    fn saturating_sub_subtracts_weights() {
+        let mut db = symbols(Predicate(0), &[&[1], &[2]]);
+        db.insert_fact_with_weight(
+            Fact {
+                predicate: Predicate(0),
+                values: &[Value::Symbol(1)],
+            },
+            Weight(4),
+        );
+        assert_eq!(db.saturating_sub(&db).len(), 0);
+        let mut other = symbols(Predicate(0), &[&[1], &[2], &[3]]);
+        other.insert_fact_with_weight(
+            Fact {
+                predicate: Predicate(0),
+                values: &[Value::Symbol(2)],
+            },
+            Weight(2),
+        );
+        let mut expected = Database::new();
+        expected.insert_fact_with_weight(
+            Fact {
+                predicate: Predicate(0),
+                values: &[Value::Symbol(1)],
+            },
+            Weight(4),
+        );
+        assert_eq!(db.saturating_sub(&other), expected);
+    }
+
+    #[test]
+    fn saturating_sub_saturates_extreme_weights() {
+        let fact = Fact {
+            predicate: Predicate(0),
+            values: &[Value::Symbol(1)],
+        };
+        let mut db = Database::new();
+        db.insert_fact_with_weight(fact, Weight(i32::MAX));
+        db.insert_fact_with_weight(fact, Weight(i32::MAX));
+        let mut other = Database::new();
+        other.insert_fact_with_weight(fact, Weight(-i32::MAX));
+        other.insert_fact_with_weight(fact, Weight(-i32::MAX));
+        assert_eq!(db.saturating_sub(&other).weight(fact), Weight(i32::MAX));
+    }
+
+    #[test]
+    fn semantically_eq_ignores_order_and_duplicates() {
+        let a = symbols(Predicate(0), &[&[1], &[2], &[1]]);
+        let b = symbols(Predicate(0), &[&[2], &[1]]);
+        assert!(a != b);
+        assert!(a.semantically_eq(&b));
+        assert!(b.semantically_eq(&a));
+        let c = symbols(Predicate(0), &[&[2]]);
+        assert!(!a.semantically_eq(&c));
+        assert!(!c.semantically_eq(&a));
+    }
+
+    #[test]
+    fn subtracting_a_fact_removes_it() {
+        let fact = Fact {
+            predicate: Predicate(0),
+            values: &[Value::Symbol(1)],
+        };
+        let mut db = Database::new();
+        db.insert_fact(fact);
+        db.insert_fact_with_weight(fact, Weight(-1));
+        assert!(!db.contains(fact));
+        assert_eq!(db.weight(fact), Weight(0));
+        assert_eq!(db, Database::new());
+
+        db.insert_fact_with_weight(fact, Weight(-1));
+        assert_eq!(db.weight(fact), Weight(-1));
+        db.insert_fact(fact);
+        assert!(!db.contains(fact));
+        assert_eq!(db, Database::new());
+    }
+
+    #[test]
+    fn group_by_first_column() {
+        let db = symbols(Predicate(0), &[&[1, 2], &[3, 4], &[1, 5]]);
+        let groups = db.group_by(Predicate(0), 0).unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(
+            groups[&Value::Symbol(1)],
+            vec![
+                OwnedFact {
+                    predicate: Predicate(0),
+                    values: vec![Value::Symbol(1), Value::Symbol(2)],
+                },
+                OwnedFact {
+                    predicate: Predicate(0),
+                    values: vec![Value::Symbol(1), Value::Symbol(5)],
+                },
+            ]
+        );
+        assert_eq!(groups[&Value::Symbol(3)].len(), 1);
+        assert_eq!(db.group_by(Predicate(0), 2), None);
+        assert_eq!(db.group_by(Predicate(1), 0), Some(HashMap::new()));
+    }
+
+    #[test]
+    fn sampling_keeps_each_fact_with_given_probability() {
+        let mut rng = XorShiftRng::from_seed([0xde, 0xad, 0xbe, 0xef]);
+        let mut db = symbols(Predicate(0), &[&[1, 2], &[3, 4], &[5, 6]]);
+        db.insert_fact_with_weight(
+            Fact {
+                predicate: Predicate(1),
+                values: &[Value::Symbol(7)],
+            },
+            Weight(3),
+        );
+        assert_eq!(db.sample(&mut rng, 1.0), db);
+        assert_eq!(db.sample(&mut rng, 0.0), Database::new());
+        let sampled = db.sample_n(&mut rng, 2);
+        assert_eq!(sampled.num_facts_for_predicate(Predicate(0)), 2);
+        assert_eq!(sampled.num_facts_for_predicate(Predicate(1)), 1);
+        assert!(sampled.all_facts().all(|fact| db.contains(fact)));
+        assert_eq!(db.sample_n(&mut rng, 3), db);
+    }
 }
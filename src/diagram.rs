@@ -1,3 +1,4 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 
 use database::Database;
@@ -20,7 +21,7 @@ impl EdgeGroup {
         match self {
             EdgeGroup::Roots => Edge::Root(target),
             EdgeGroup::MatchTargets(source) => Edge::Match { source, target },
-            EdgeGroup::RefuteTargets(source) => Edge::Match { source, target },
+            EdgeGroup::RefuteTargets(source) => Edge::Refute { source, target },
             _ => panic!("can only make an edge to target given a source group"),
         }
     }
@@ -72,6 +73,39 @@ impl Edge {
     }
 }
 
+impl fmt::Display for Edge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Edge::Root(target) => write!(f, "root -> {}", target.0),
+            Edge::Match { source, target } => write!(f, "{} -match-> {}", source.0, target.0),
+            Edge::Refute { source, target } => write!(f, "{} -refute-> {}", source.0, target.0),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum EdgeError {
+    AlreadyPresent(Edge),
+    NotPresent(Edge),
+    OutOfBounds(Edge),
+    SelfLoop(Edge),
+}
+
+/**
+ * Checks that both ends of `edge` are in a diagram of `num_nodes` nodes, and that it isn't a self
+ * loop unless those are allowed.
+ */
+pub fn check_edge(num_nodes: usize, edge: Edge, allow_self_loop: bool) -> Result<(), EdgeError> {
+    let source_in_bounds = edge.source().map_or(true, |source| source.0 < num_nodes);
+    if !source_in_bounds || edge.target().0 >= num_nodes {
+        Err(EdgeError::OutOfBounds(edge))
+    } else if !allow_self_loop && edge.source() == Some(edge.target()) {
+        Err(EdgeError::SelfLoop(edge))
+    } else {
+        Ok(())
+    }
+}
+
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
 pub enum MaybeNodePair {
     Zero,
@@ -97,35 +131,220 @@ impl Iterator for MaybeNodePair {
     }
 }
 
+/**
+ * The parser rejects match nodes in which two terms share a target register, unless all but one of
+ * them have `if_unbound` set. If such a node is constructed directly, the term with the highest
+ * index wins.
+ */
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct MatchTerm {
     pub constraint: MatchTermConstraint,
     pub target: Option<usize>,
+    /**
+     * If set, the target register is only written if it's unbound, so that an earlier binding,
+     * whether from the incoming register file or an earlier term, takes precedence.
+     */
+    pub if_unbound: bool,
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum MatchTermConstraint {
     Register(usize),
     Constant(Value),
+    /**
+     * Matches any value other than the given one.
+     */
+    NotConstant(Value),
     Free,
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum OutputTerm {
     Register(usize),
     Constant(Value),
+    /**
+     * A new symbol for each distinct incoming register file, node, and term position. Within one
+     * evaluation, register files which are equal (ignoring scratch registers) get the same
+     * symbol, and distinct ones get distinct symbols. Symbols are numbered from
+     * `FIRST_FRESH_SYMBOL` in the order the evaluation first needs them, so they can't collide
+     * with constants, but may be numbered differently by another evaluation.
+     */
+    Fresh,
+    /**
+     * The given column of the fact matched by the match node leading to this node. Only valid if
+     * that match node is the node's only source, and the node isn't a root.
+     * `GraphDiagram::resolve_match_columns` replaces these terms with registers, which
+     * `parse_diagram` and `parse_program` do automatically. An unresolved term is evaluated like
+     * an unbound register.
+     */
+    MatchColumn(usize),
+}
+
+// Nil has no literal in match terms, so it's written as `nil`, which doesn't parse.
+fn fmt_value(value: &Value, f: &mut fmt::Formatter) -> fmt::Result {
+    match *value {
+        Value::Symbol(symbol) => write!(f, ":{}", symbol),
+        Value::Nil => write!(f, "nil"),
+    }
+}
+
+fn fmt_terms<T: fmt::Display>(terms: &[T], f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "(")?;
+    for (i, term) in terms.iter().enumerate() {
+        if i != 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", term)?;
+    }
+    write!(f, ")")
+}
+
+impl fmt::Display for MatchTerm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.constraint {
+            MatchTermConstraint::Register(register) => write!(f, "%{}", register)?,
+            MatchTermConstraint::Constant(ref value) => fmt_value(value, f)?,
+            MatchTermConstraint::NotConstant(ref value) => {
+                write!(f, "!")?;
+                fmt_value(value, f)?
+            }
+            MatchTermConstraint::Free => write!(f, "_")?,
+        }
+        if let Some(target) = self.target {
+            let arrow = if self.if_unbound { "->?" } else { "->" };
+            write!(f, " {} %{}", arrow, target)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for OutputTerm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OutputTerm::Register(register) => write!(f, "%{}", register),
+            OutputTerm::Constant(Value::Nil) => write!(f, "_"),
+            OutputTerm::Constant(ref value) => fmt_value(value, f),
+            OutputTerm::Fresh => write!(f, "!"),
+            OutputTerm::MatchColumn(column) => write!(f, "${}", column),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum AggKind {
+    Count,
+    Sum(usize),
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum Node {
     Match {
         predicate: Predicate,
         terms: Vec<MatchTerm>,
+        /**
+         * If set, a register file in which any register constraint is unset produces neither a
+         * match nor a refutation. By default, an unset register never equals a value, so the
+         * node refutes.
+         */
+        require_bound: bool,
     },
     Output {
         predicate: Predicate,
         terms: Vec<OutputTerm>,
+        /**
+         * If set, no fact is emitted for a register file in which any register term is unset,
+         * instead of emitting `Value::Nil` for those terms.
+         */
+        skip_on_unbound: bool,
     },
+    /**
+     * Emits one fact per distinct value of the output terms (i.e. grouped by the output terms),
+     * with the aggregate over all incoming register states in that group appended as the last
+     * value.
+     */
+    Aggregate {
+        predicate: Predicate,
+        terms: Vec<OutputTerm>,
+        agg: AggKind,
+    },
+}
+
+fn map_register(reg_map: &mut HashMap<usize, usize>, from: usize, to: usize) -> bool {
+    if let Some(&mapped) = reg_map.get(&from) {
+        return mapped == to;
+    }
+    if reg_map.values().any(|&mapped| mapped == to) {
+        return false;
+    }
+    reg_map.insert(from, to);
+    true
+}
+
+fn output_terms_eq_modulo_registers(
+    terms: &[OutputTerm],
+    other_terms: &[OutputTerm],
+    reg_map: &mut HashMap<usize, usize>,
+) -> bool {
+    terms.len() == other_terms.len()
+        && terms
+            .iter()
+            .zip(other_terms.iter())
+            .all(|(term, other_term)| match (term, other_term) {
+                (&OutputTerm::Register(a), &OutputTerm::Register(b)) => {
+                    map_register(reg_map, a, b)
+                }
+                (&OutputTerm::Constant(ref a), &OutputTerm::Constant(ref b)) => a == b,
+                (&OutputTerm::Fresh, &OutputTerm::Fresh) => true,
+                (&OutputTerm::MatchColumn(a), &OutputTerm::MatchColumn(b)) => a == b,
+                _ => false,
+            })
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Node::Match {
+                predicate,
+                ref terms,
+                require_bound,
+            } => {
+                write!(f, "@{}", predicate.0)?;
+                if require_bound {
+                    write!(f, "!")?;
+                }
+                fmt_terms(terms, f)
+            }
+            Node::Output {
+                predicate,
+                ref terms,
+                skip_on_unbound,
+            } => {
+                write!(f, "output")?;
+                if skip_on_unbound {
+                    write!(f, "!")?;
+                }
+                write!(f, " @{}", predicate.0)?;
+                fmt_terms(terms, f)
+            }
+            Node::Aggregate {
+                predicate,
+                ref terms,
+                agg,
+            } => {
+                match agg {
+                    AggKind::Count => write!(f, "count")?,
+                    AggKind::Sum(register) => write!(f, "sum %{}", register)?,
+                }
+                write!(f, " @{}", predicate.0)?;
+                fmt_terms(terms, f)
+            }
+        }
+    }
 }
 
 impl Node {
@@ -136,6 +355,98 @@ impl Node {
             false
         }
     }
+
+    /**
+     * Returns whether the two nodes are equal after renaming registers in `self` using
+     * `reg_map`, extending `reg_map` with any new renamings that are needed. The renaming is kept
+     * one-to-one. If the nodes are not equal, `reg_map` is left unchanged.
+     */
+    pub fn eq_modulo_registers(&self, other: &Node, reg_map: &mut HashMap<usize, usize>) -> bool {
+        let mut new_map = reg_map.clone();
+        let result = match (self, other) {
+            (
+                &Node::Match {
+                    predicate,
+                    ref terms,
+                    require_bound,
+                },
+                &Node::Match {
+                    predicate: other_predicate,
+                    terms: ref other_terms,
+                    require_bound: other_require_bound,
+                },
+            ) => {
+                predicate == other_predicate
+                    && require_bound == other_require_bound
+                    && terms.len() == other_terms.len()
+                    && terms.iter().zip(other_terms.iter()).all(|(term, other_term)| {
+                        let constraint_eq = match (&term.constraint, &other_term.constraint) {
+                            (
+                                &MatchTermConstraint::Register(a),
+                                &MatchTermConstraint::Register(b),
+                            ) => map_register(&mut new_map, a, b),
+                            (
+                                &MatchTermConstraint::Constant(ref a),
+                                &MatchTermConstraint::Constant(ref b),
+                            ) => a == b,
+                            (
+                                &MatchTermConstraint::NotConstant(ref a),
+                                &MatchTermConstraint::NotConstant(ref b),
+                            ) => a == b,
+                            (&MatchTermConstraint::Free, &MatchTermConstraint::Free) => true,
+                            _ => false,
+                        };
+                        let target_eq = match (term.target, other_term.target) {
+                            (Some(a), Some(b)) => map_register(&mut new_map, a, b),
+                            (None, None) => true,
+                            _ => false,
+                        };
+                        constraint_eq && target_eq && term.if_unbound == other_term.if_unbound
+                    })
+            }
+            (
+                &Node::Output {
+                    predicate,
+                    ref terms,
+                    skip_on_unbound,
+                },
+                &Node::Output {
+                    predicate: other_predicate,
+                    terms: ref other_terms,
+                    skip_on_unbound: other_skip_on_unbound,
+                },
+            ) => {
+                predicate == other_predicate
+                    && skip_on_unbound == other_skip_on_unbound
+                    && output_terms_eq_modulo_registers(terms, other_terms, &mut new_map)
+            }
+            (
+                &Node::Aggregate {
+                    predicate,
+                    ref terms,
+                    agg,
+                },
+                &Node::Aggregate {
+                    predicate: other_predicate,
+                    terms: ref other_terms,
+                    agg: other_agg,
+                },
+            ) => {
+                predicate == other_predicate
+                    && output_terms_eq_modulo_registers(terms, other_terms, &mut new_map)
+                    && match (agg, other_agg) {
+                        (AggKind::Count, AggKind::Count) => true,
+                        (AggKind::Sum(a), AggKind::Sum(b)) => map_register(&mut new_map, a, b),
+                        _ => false,
+                    }
+            }
+            _ => false,
+        };
+        if result {
+            *reg_map = new_map;
+        }
+        result
+    }
 }
 
 pub trait MultiDiagram: fmt::Debug {
@@ -155,27 +466,124 @@ pub trait MultiDiagram: fmt::Debug {
 
     fn len(&self) -> usize;
 
-    fn insert_edge_if_not_present(&mut self, edge: Edge) -> bool {
+    fn try_insert_edge(&mut self, edge: Edge) -> Result<(), EdgeError> {
+        check_edge(self.len(), edge, true)?;
         if self.edge_exists(edge) {
-            true
+            Err(EdgeError::AlreadyPresent(edge))
         } else {
             self.insert_edge(edge);
-            false
+            Ok(())
         }
     }
 
-    fn remove_edge_if_present(&mut self, edge: Edge) -> bool {
+    fn try_remove_edge(&mut self, edge: Edge) -> Result<(), EdgeError> {
+        check_edge(self.len(), edge, true)?;
         if self.edge_exists(edge) {
             self.remove_edge(edge);
-            true
+            Ok(())
         } else {
-            false
+            Err(EdgeError::NotPresent(edge))
+        }
+    }
+
+    /**
+     * Inserts `edge` unless it's already present, returning whether it was. Panics if `edge`
+     * isn't valid in this diagram, see `try_insert_edge`.
+     */
+    fn insert_edge_if_not_present(&mut self, edge: Edge) -> bool {
+        match self.try_insert_edge(edge) {
+            Ok(()) => false,
+            Err(EdgeError::AlreadyPresent(_)) => true,
+            Err(err) => panic!("Could not insert edge: {:?}", err),
+        }
+    }
+
+    /**
+     * Removes `edge` if it's present, returning whether it was. Panics if `edge` isn't valid in
+     * this diagram, see `try_remove_edge`.
+     */
+    fn remove_edge_if_present(&mut self, edge: Edge) -> bool {
+        match self.try_remove_edge(edge) {
+            Ok(()) => true,
+            Err(EdgeError::NotPresent(_)) => false,
+            Err(err) => panic!("Could not remove edge: {:?}", err),
+        }
+    }
+
+    /**
+     * Returns the match edges followed by the refute edges leaving `node`.
+     */
+    fn out_edges(&self, node: NodeIndex) -> Vec<Edge> {
+        let match_edges = self.get_group(EdgeGroup::MatchTargets(node))
+            .iter()
+            .map(|&target| Edge::Match {
+                source: node,
+                target,
+            });
+        let refute_edges = self.get_group(EdgeGroup::RefuteTargets(node))
+            .iter()
+            .map(|&target| Edge::Refute {
+                source: node,
+                target,
+            });
+        match_edges.chain(refute_edges).collect()
+    }
+
+    fn reachable_nodes(&self) -> HashSet<NodeIndex> {
+        let mut reachable = HashSet::new();
+        let mut to_visit = self.get_group(EdgeGroup::Roots).to_owned();
+        while let Some(node) = to_visit.pop() {
+            if node.0 >= self.len() || !reachable.insert(node) {
+                continue;
+            }
+            to_visit.extend(self.out_edges(node).into_iter().map(Edge::target));
+        }
+        reachable
+    }
+
+    /**
+     * Orders the reachable nodes so that each node comes after every reachable node with an edge
+     * to it. Returns `None` if a cycle is reachable from the roots.
+     */
+    fn topo_order(&self) -> Option<Vec<NodeIndex>> {
+        let reachable = self.reachable_nodes();
+        let mut num_sources: HashMap<NodeIndex, usize> =
+            reachable.iter().map(|&node| (node, 0)).collect();
+        for &node in reachable.iter() {
+            for edge in self.out_edges(node) {
+                if let Some(count) = num_sources.get_mut(&edge.target()) {
+                    *count += 1;
+                }
+            }
+        }
+        let mut ready: Vec<NodeIndex> = num_sources
+            .iter()
+            .filter(|&(_, &count)| count == 0)
+            .map(|(&node, _)| node)
+            .collect();
+        ready.sort_by_key(|node| node.0);
+        let mut order = Vec::with_capacity(reachable.len());
+        while let Some(node) = ready.pop() {
+            order.push(node);
+            for edge in self.out_edges(node) {
+                if let Some(count) = num_sources.get_mut(&edge.target()) {
+                    *count -= 1;
+                    if *count == 0 {
+                        ready.push(edge.target());
+                    }
+                }
+            }
+        }
+        if order.len() == reachable.len() {
+            Some(order)
+        } else {
+            None
         }
     }
 }
 
 pub trait Diagram: MultiDiagram {
-    fn get_root(&self) -> NodeIndex;
+    fn get_root(&self) -> Option<NodeIndex>;
 
     fn set_root(&mut self, root: NodeIndex);
 
@@ -183,6 +591,35 @@ pub trait Diagram: MultiDiagram {
 
     fn set_on_refute(&mut self, src: NodeIndex, target: NodeIndex);
 
+    /**
+     * Like `set_on_match`, but returns an error instead of panicking if either node is out of
+     * bounds, and rejects `src == target` unless `allow_self_loop` is set.
+     */
+    fn try_set_on_match(
+        &mut self,
+        src: NodeIndex,
+        target: NodeIndex,
+        allow_self_loop: bool,
+    ) -> Result<(), EdgeError> {
+        check_edge(self.len(), Edge::Match { source: src, target }, allow_self_loop)?;
+        self.set_on_match(src, target);
+        Ok(())
+    }
+
+    /**
+     * Like `try_set_on_match`, for the refute edge.
+     */
+    fn try_set_on_refute(
+        &mut self,
+        src: NodeIndex,
+        target: NodeIndex,
+        allow_self_loop: bool,
+    ) -> Result<(), EdgeError> {
+        check_edge(self.len(), Edge::Refute { source: src, target }, allow_self_loop)?;
+        self.set_on_refute(src, target);
+        Ok(())
+    }
+
     fn clear_on_match(&mut self, src: NodeIndex);
 
     fn clear_on_refute(&mut self, src: NodeIndex);
@@ -204,3 +641,139 @@ pub struct DiagramSpace {
     pub num_registers: usize,
     pub num_terms: usize,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parse::{node_literal, parse_diagram};
+
+    #[test]
+    fn nodes_equal_under_renaming() {
+        let mut reg_map = HashMap::new();
+        assert!(
+            node_literal("@0(_ -> %0, %1 -> %2)")
+                .eq_modulo_registers(&node_literal("@0(_ -> %3, %0 -> %1)"), &mut reg_map)
+        );
+        assert_eq!(reg_map, [(0, 3), (1, 0), (2, 1)].iter().cloned().collect());
+        assert!(
+            node_literal("output @1(%2, %0)")
+                .eq_modulo_registers(&node_literal("output @1(%1, %3)"), &mut reg_map)
+        );
+    }
+
+    #[test]
+    fn nodes_differing_under_renaming() {
+        let mut reg_map = HashMap::new();
+        assert!(
+            !node_literal("@0(_ -> %0, %1)")
+                .eq_modulo_registers(&node_literal("@0(_ -> %0, %0)"), &mut reg_map)
+        );
+        assert!(reg_map.is_empty());
+        assert!(
+            !node_literal("@0(:1 -> %0)")
+                .eq_modulo_registers(&node_literal("@0(:2 -> %0)"), &mut reg_map)
+        );
+        reg_map.insert(0, 1);
+        assert!(
+            !node_literal("output @1(%0)")
+                .eq_modulo_registers(&node_literal("output @1(%0)"), &mut reg_map)
+        );
+        assert_eq!(reg_map, [(0, 1)].iter().cloned().collect());
+    }
+
+    #[test]
+    fn inserting_edges_if_not_present_reports_existing_edges() {
+        let (mut diagram, _) = parse_diagram("root: @0(_) {} a: output @1(:1)", 0).unwrap();
+        let edge = Edge::Match {
+            source: NodeIndex(0),
+            target: NodeIndex(1),
+        };
+        assert!(!diagram.insert_edge_if_not_present(edge));
+        assert!(diagram.insert_edge_if_not_present(edge));
+        assert!(diagram.remove_edge_if_present(edge));
+        assert!(!diagram.remove_edge_if_present(edge));
+    }
+
+    #[test]
+    #[should_panic]
+    fn inserting_edges_out_of_bounds_panics() {
+        let (mut diagram, _) = parse_diagram("root: @0(_) {}", 0).unwrap();
+        diagram.insert_edge_if_not_present(Edge::Root(NodeIndex(3)));
+    }
+
+    #[test]
+    fn out_edges_lists_match_then_refute_edges() {
+        let (diagram, context) = parse_diagram(
+            r#"
+        root: @0(_) {
+          a: output @1(:1)
+        } {
+          b: output @1(:2)
+        }
+        "#,
+            0,
+        ).unwrap();
+        let root = diagram.get_group(EdgeGroup::Roots)[0];
+        let a = context.node_name_to_info["a"].index;
+        let b = context.node_name_to_info["b"].index;
+        assert_eq!(
+            diagram.out_edges(root),
+            vec![
+                Edge::Match {
+                    source: root,
+                    target: a,
+                },
+                Edge::Refute {
+                    source: root,
+                    target: b,
+                },
+            ]
+        );
+        assert_eq!(diagram.out_edges(a), vec![]);
+    }
+
+    #[test]
+    fn nodes_display_as_source() {
+        let sources = [
+            "@0(_ -> %0, %1, :2 -> %1)",
+            "@0(!:2, !:3 -> %0)",
+            "@0(_ -> %0, _ ->? %0)",
+            "@3!(%0)",
+            "output @1(%0, :1, _, !)",
+            "output @1($1, $0)",
+            "output! @1(%2)",
+            "count @2(%0)",
+            "sum %1 @2(%0, :3)",
+        ];
+        for src in sources.iter() {
+            let node = node_literal(src);
+            assert_eq!(format!("{}", node), *src);
+            assert_eq!(node_literal(&format!("{}", node)), node);
+        }
+    }
+
+    #[test]
+    fn edges_display_with_kind() {
+        assert_eq!(format!("{}", Edge::Root(NodeIndex(2))), "root -> 2");
+        assert_eq!(
+            format!(
+                "{}",
+                Edge::Match {
+                    source: NodeIndex(0),
+                    target: NodeIndex(1),
+                }
+            ),
+            "0 -match-> 1"
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Edge::Refute {
+                    source: NodeIndex(1),
+                    target: NodeIndex(0),
+                }
+            ),
+            "1 -refute-> 0"
+        );
+    }
+}
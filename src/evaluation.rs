@@ -1,14 +1,18 @@
-use std::collections::HashSet;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
 use std::iter;
+use std::mem;
+use std::rc::Rc;
 
-use database::Database;
-use diagram::{EdgeGroup, MatchTerm, MatchTermConstraint, MultiDiagram, Node, OutputTerm};
-use fact::Fact;
+use database::{Database, PredicateIter};
+use diagram::{AggKind, Edge, EdgeGroup, MatchTerm, MatchTermConstraint, MultiDiagram, Node,
+              OutputTerm};
+use fact::{Fact, OwnedFact};
 use node_index::NodeIndex;
 use predicate::Predicate;
-use registers::{RegisterFile, RegisterSet};
+use registers::{RegisterFile, RegisterSet, WeightDecay};
 use simple_query::{SimpleQuery, SimpleQueryTerm};
-use value::Value;
+use value::{Value, FIRST_FRESH_SYMBOL};
 use weight::Weight;
 
 #[derive(Clone, Debug)]
@@ -18,6 +22,21 @@ struct NodeState {
 }
 
 impl NodeState {
+    /**
+     * The number of register states in the input and output, or facts in the output.
+     */
+    fn len(&self) -> usize {
+        let output_len = match self.output {
+            Some(NodeOutputState::Match {
+                ref matches,
+                ref refutes,
+            }) => matches.len() + refutes.len(),
+            Some(NodeOutputState::Output { ref db }) => db.len(),
+            None => 0,
+        };
+        self.input.len() + output_len
+    }
+
     /**
      * Returns whether a new state was added to the output.
      */
@@ -59,8 +78,12 @@ impl NodeState {
     }
 }
 
-#[derive(Clone, Debug)]
-enum NodeOutputState {
+/**
+ * The output of a single node: the register files which matched and refuted for a match node, or
+ * the facts emitted by an output or aggregate node.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NodeOutputState {
     Match {
         matches: RegisterSet,
         refutes: RegisterSet,
@@ -70,12 +93,64 @@ enum NodeOutputState {
     },
 }
 
+/**
+ * Returns whether every register constraint in `terms` refers to a set register.
+ */
+fn constraints_bound(terms: &[MatchTerm], register_file: &RegisterFile) -> bool {
+    terms.iter().all(|term| match term.constraint {
+        MatchTermConstraint::Register(reg) => register_file.get(reg).map_or(false, |v| v.is_some()),
+        _ => true,
+    })
+}
+
+/**
+ * Returns the register file resulting from testing `values` against `terms`, and whether the values
+ * matched.
+ */
+fn match_values(
+    terms: &[MatchTerm],
+    register_file: &RegisterFile,
+    values: &[Value],
+) -> (RegisterFile, bool) {
+    let mut result_registers = register_file.clone();
+    let mut refuted = false;
+    for (term, value) in terms.iter().zip(values) {
+        match term.constraint {
+            MatchTermConstraint::Free => {}
+            MatchTermConstraint::Constant(ref v) => if v != value {
+                refuted = true;
+            },
+            MatchTermConstraint::NotConstant(ref v) => if v == value {
+                refuted = true;
+            },
+            MatchTermConstraint::Register(reg) => {
+                if register_file.get(reg).and_then(|v| v.as_ref()) != Some(value) {
+                    refuted = true;
+                }
+            }
+        }
+        if let Some(target) = term.target {
+            // A target beyond the scratch registers can't hold the value, so it refutes.
+            match result_registers.get_mut(target) {
+                Some(register) => if !term.if_unbound || register.is_none() {
+                    *register = Some(value.clone());
+                },
+                None => {
+                    refuted = true;
+                }
+            }
+        }
+    }
+    (result_registers, !refuted)
+}
+
 /**
  * Return whether a new state was added to one of the outputs.
  */
 fn propagate_match_node_into_output(
     predicate: Predicate,
     terms: &[MatchTerm],
+    require_bound: bool,
     database: &Database,
     register_file: &RegisterFile,
     weight: Weight,
@@ -84,86 +159,210 @@ fn propagate_match_node_into_output(
     refutes: &mut RegisterSet,
 ) -> bool {
     let mut found_new_state = false;
+    if require_bound && !constraints_bound(terms, register_file) {
+        return found_new_state;
+    }
     for fact in database.facts_for_predicate(predicate) {
-        let mut result_registers = register_file.clone();
-        let mut refuted = false;
-        for (term, value) in terms.iter().zip(fact.values) {
-            match term.constraint {
-                MatchTermConstraint::Free => {}
-                MatchTermConstraint::Constant(ref v) => if v != value {
-                    refuted = true;
-                },
-                MatchTermConstraint::Register(reg) => {
-                    if register_file[reg].as_ref() != Some(value) {
-                        refuted = true;
-                    }
-                }
-            }
-            if let Some(target) = term.target {
-                result_registers[target] = Some(value.clone());
-            }
-        }
-        if refuted {
-            found_new_state |= refutes.push(result_registers, weight, input_depth + 1);
-        } else {
+        let (result_registers, matched) = match_values(terms, register_file, fact.values);
+        if matched {
             found_new_state |= matches.push(result_registers, weight, input_depth + 1);
+        } else {
+            found_new_state |= refutes.push(result_registers, weight, input_depth + 1);
         }
     }
     return found_new_state;
 }
 
-fn propagate_output_node_into_output(
-    predicate: Predicate,
+/**
+ * Allocates the symbols of `OutputTerm::Fresh` terms, counting up from `FIRST_FRESH_SYMBOL`.
+ */
+#[derive(Clone, Debug)]
+pub struct FreshSymbols {
+    symbols: HashMap<(NodeIndex, usize, RegisterFile), u64>,
+}
+
+impl FreshSymbols {
+    pub fn new() -> Self {
+        FreshSymbols {
+            symbols: HashMap::new(),
+        }
+    }
+
+    /**
+     * Returns the symbol for term `term` of `node` given `register_file`, allocating the next
+     * symbol the first time they're seen.
+     */
+    pub fn get(&mut self, node: NodeIndex, term: usize, register_file: &RegisterFile) -> Value {
+        let next = FIRST_FRESH_SYMBOL + self.symbols.len() as u64;
+        let symbol = *self.symbols
+            .entry((node, term, register_file.clone()))
+            .or_insert(next);
+        Value::Symbol(symbol)
+    }
+
+    pub fn clear(&mut self) {
+        self.symbols.clear();
+    }
+}
+
+/**
+ * Returns the values an output node emits for `register_file`, or `None` if it emits nothing.
+ * Unresolved match column terms are treated like unbound registers.
+ */
+fn output_values(
+    node: NodeIndex,
     terms: &[OutputTerm],
+    skip_on_unbound: bool,
     register_file: &RegisterFile,
-    weight: Weight,
-    db: &mut Database,
-) {
+    fresh: &mut FreshSymbols,
+) -> Option<Vec<Value>> {
     let mut values = Vec::with_capacity(terms.len());
-    for term in terms {
+    for (term_index, term) in terms.iter().enumerate() {
         match *term {
             OutputTerm::Constant(ref value) => {
                 values.push(value.clone());
             }
             OutputTerm::Register(index) => {
-                if index < register_file.len() {
-                    if let Some(ref value) = register_file[index] {
+                if let Some(&Some(ref value)) = register_file.get(index) {
+                    values.push(value.clone());
+                } else if skip_on_unbound {
+                    return None;
+                } else {
+                    values.push(Value::Nil);
+                }
+            }
+            OutputTerm::Fresh => {
+                values.push(fresh.get(node, term_index, register_file));
+            }
+            OutputTerm::MatchColumn(_) => {
+                if skip_on_unbound {
+                    return None;
+                }
+                values.push(Value::Nil);
+            }
+        }
+    }
+    Some(values)
+}
+
+fn propagate_output_node_into_output(
+    node: NodeIndex,
+    predicate: Predicate,
+    terms: &[OutputTerm],
+    skip_on_unbound: bool,
+    register_file: &RegisterFile,
+    weight: Weight,
+    db: &mut Database,
+    fresh: &mut FreshSymbols,
+) {
+    if let Some(values) = output_values(node, terms, skip_on_unbound, register_file, fresh) {
+        db.insert_fact_with_weight(
+            Fact {
+                predicate,
+                values: &values[..],
+            },
+            weight,
+        );
+    }
+}
+
+/**
+ * Groups the register files by the values of `terms`, and outputs each group's values followed by
+ * its count or sum. Register files which use a register beyond the scratch registers are skipped,
+ * as they would be by a match node. Symbols are unsigned, so totals below zero, which negative
+ * weights can produce, are clamped to zero.
+ */
+fn propagate_aggregate_node_into_output(
+    node: NodeIndex,
+    predicate: Predicate,
+    terms: &[OutputTerm],
+    agg: AggKind,
+    registers: &RegisterSet,
+    fresh: &mut FreshSymbols,
+) -> Database {
+    let mut groups: HashMap<Vec<Value>, (i64, i64)> = HashMap::new();
+    'register_files: for (register_file, weight, _) in registers.iter() {
+        let mut values = Vec::with_capacity(terms.len() + 1);
+        for (term_index, term) in terms.iter().enumerate() {
+            match *term {
+                OutputTerm::Constant(ref value) => {
+                    values.push(value.clone());
+                }
+                OutputTerm::Register(index) => {
+                    if !register_file.has_register(index) {
+                        continue 'register_files;
+                    }
+                    if let Some(&Some(ref value)) = register_file.get(index) {
                         values.push(value.clone());
                     } else {
                         values.push(Value::Nil);
                     }
                 }
+                OutputTerm::Fresh => {
+                    values.push(fresh.get(node, term_index, register_file));
+                }
+                OutputTerm::MatchColumn(_) => {
+                    values.push(Value::Nil);
+                }
             }
         }
+        let amount = match agg {
+            AggKind::Count => 1,
+            AggKind::Sum(index) if !register_file.has_register(index) => continue,
+            AggKind::Sum(index) => match register_file.get(index) {
+                Some(&Some(Value::Symbol(symbol))) => cmp::min(symbol, i64::MAX as u64) as i64,
+                _ => 0,
+            },
+        };
+        let weight = i64::from(weight.0);
+        let group = groups.entry(values).or_insert((0, 0));
+        group.0 += weight;
+        group.1 = group.1.saturating_add(amount.saturating_mul(weight));
     }
-    db.insert_fact_with_weight(
-        Fact {
+    let mut db = Database::new();
+    for (mut values, (count, total)) in groups {
+        if count <= 0 {
+            continue;
+        }
+        values.push(Value::Symbol(cmp::max(total, 0) as u64));
+        db.insert_fact(Fact {
             predicate,
             values: &values[..],
-        },
-        weight,
-    );
+        });
+    }
+    db
 }
 
+const MAX_RESERVED_STATES: usize = 1024;
+
 fn propagate<D: MultiDiagram>(
     diagram: &D,
     node: NodeIndex,
     database: &Database,
     registers: &RegisterSet,
     max_depth: Option<usize>,
+    fresh: &mut FreshSymbols,
 ) -> NodeOutputState {
     match *diagram.get_node(node) {
         Node::Match {
             predicate,
             ref terms,
+            require_bound,
         } => {
-            let mut matches = RegisterSet::new(registers.num_registers());
-            let mut refutes = RegisterSet::new(registers.num_registers());
+            // Each input state produces one output state per fact, in either matches or refutes.
+            // Many of those states are usually duplicates, so don't reserve too much up front.
+            let capacity = cmp::min(
+                registers.len() * database.num_facts_for_predicate(predicate),
+                MAX_RESERVED_STATES,
+            );
+            let mut matches = RegisterSet::with_capacity(registers.num_registers(), capacity);
+            let mut refutes = RegisterSet::with_capacity(registers.num_registers(), capacity);
             for (register_file, weight, depth) in registers.iter() {
                 if max_depth.map(|max_depth| depth < max_depth).unwrap_or(true) {
                     propagate_match_node_into_output(
                         predicate,
                         terms,
+                        require_bound,
                         database,
                         register_file,
                         weight,
@@ -178,22 +377,184 @@ fn propagate<D: MultiDiagram>(
         Node::Output {
             predicate,
             ref terms,
+            skip_on_unbound,
         } => {
             let mut db = Database::new();
             for (register_file, weight, _) in registers.iter() {
-                propagate_output_node_into_output(predicate, terms, register_file, weight, &mut db);
+                propagate_output_node_into_output(
+                    node,
+                    predicate,
+                    terms,
+                    skip_on_unbound,
+                    register_file,
+                    weight,
+                    &mut db,
+                    fresh,
+                );
             }
             NodeOutputState::Output { db }
         }
+        Node::Aggregate {
+            predicate,
+            ref terms,
+            agg,
+        } => NodeOutputState::Output {
+            db: propagate_aggregate_node_into_output(
+                node,
+                predicate,
+                terms,
+                agg,
+                registers,
+                fresh,
+            ),
+        },
     }
 }
 
+/**
+ * Evaluates `node` on its own, given its input `registers` and the facts in `database`, without
+ * following any of its edges. Input states at or beyond `max_depth` are ignored by match nodes.
+ * Fresh symbols are allocated from `fresh`, so reuse it to number them consistently across calls.
+ */
+pub fn evaluate_node<D: MultiDiagram>(
+    diagram: &D,
+    node: NodeIndex,
+    database: &Database,
+    registers: &RegisterSet,
+    max_depth: Option<usize>,
+    fresh: &mut FreshSymbols,
+) -> NodeOutputState {
+    propagate(diagram, node, database, registers, max_depth, fresh)
+}
+
 const DEFAULT_MAX_DEPTH: usize = 8;
 
+/**
+ * Returns each fact of `derived` which isn't in `available`, once.
+ */
+fn unavailable_facts(derived: &Database, available: &Database) -> Database {
+    let mut result = Database::new();
+    for fact in derived.all_facts() {
+        if !available.contains(fact) && !result.contains(fact) {
+            result.insert_fact(fact);
+        }
+    }
+    result
+}
+
+/**
+ * Explains how an evaluation arrived at a fact or a node state. Register files are compared the
+ * same way `RegisterSet` deduplicates them, so scratch registers are ignored.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DerivationTree {
+    /**
+     * An output fact, with one `State` for each output node and register file which emitted it.
+     */
+    Fact {
+        fact: OwnedFact,
+        sources: Vec<DerivationTree>,
+    },
+    /**
+     * `node` was reached with `registers`, for each of `causes`.
+     */
+    State {
+        node: NodeIndex,
+        registers: RegisterFile,
+        causes: Vec<DerivationTree>,
+    },
+    /**
+     * The state was given to a root when the evaluation started.
+     */
+    Root,
+    /**
+     * A match node in `source`'s state tested `fact`, and the resulting match or refutation was
+     * passed along. `source` is shared between every step which passes through the same state.
+     */
+    Step {
+        fact: OwnedFact,
+        matched: bool,
+        source: Rc<DerivationTree>,
+    },
+    /**
+     * This state is already being explained further up the tree, so the diagram has a cycle.
+     */
+    BackReference {
+        node: NodeIndex,
+        registers: RegisterFile,
+    },
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct NodeStats {
+    /**
+     * The number of register files pushed into the node's input, including duplicates.
+     */
+    pub num_pushes: usize,
+    /**
+     * The number of facts the node has output. For aggregate nodes, this is the number of facts
+     * in the latest aggregation.
+     */
+    pub num_output_facts: usize,
+    /**
+     * The number of times the node's output was computed from a batch of input states.
+     */
+    pub num_propagations: usize,
+}
+
+/**
+ * A rough account of the state held by an evaluation, see `Evaluation::approx_memory`.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryReport {
+    /**
+     * The number of register files in node inputs and in match and refute outputs.
+     */
+    pub num_register_states: usize,
+    /**
+     * The number of facts in the output databases of output and aggregate nodes.
+     */
+    pub num_output_facts: usize,
+    /**
+     * An estimate of the bytes used by the above and by `total_db`, ignoring hash table and
+     * index overhead.
+     */
+    pub approx_bytes: usize,
+}
+
+fn approx_register_set_bytes(registers: &RegisterSet) -> usize {
+    let per_state = mem::size_of::<RegisterFile>()
+        + registers.num_registers() * mem::size_of::<Option<Value>>()
+        + mem::size_of::<Weight>()
+        + mem::size_of::<usize>();
+    registers.len() * per_state
+}
+
+fn approx_database_bytes(db: &Database) -> usize {
+    db.predicates()
+        .filter_map(|predicate| db.table(predicate))
+        .map(|table| {
+            let per_row = table.num_columns() * mem::size_of::<Value>() + mem::size_of::<Weight>();
+            table.num_rows() * per_row
+        })
+        .sum()
+}
+
 #[derive(Clone, Debug)]
 pub struct Evaluation {
     states: Vec<NodeState>,
     max_depth: usize,
+    max_facts: Option<usize>,
+    /**
+     * The sum of `len` over `states`, kept up to date as they change so that checking
+     * `max_facts` doesn't need to visit every state.
+     */
+    num_facts: usize,
+    truncated: bool,
+    stats: Option<Vec<NodeStats>>,
+    weight_decay: WeightDecay,
+    subsumption: bool,
+    fresh_symbols: FreshSymbols,
     pub total_db: Database,
 }
 
@@ -202,6 +563,13 @@ impl Evaluation {
         Evaluation {
             states: Vec::new(),
             max_depth: DEFAULT_MAX_DEPTH,
+            max_facts: None,
+            num_facts: 0,
+            truncated: false,
+            stats: None,
+            weight_decay: WeightDecay::None,
+            subsumption: false,
+            fresh_symbols: FreshSymbols::new(),
             total_db: Database::new(),
         }
     }
@@ -210,10 +578,181 @@ impl Evaluation {
         Evaluation {
             states: Vec::with_capacity(cap),
             max_depth: DEFAULT_MAX_DEPTH,
+            max_facts: None,
+            num_facts: 0,
+            truncated: false,
+            stats: None,
+            weight_decay: WeightDecay::None,
+            subsumption: false,
+            fresh_symbols: FreshSymbols::new(),
             total_db: Database::new(),
         }
     }
 
+    /**
+     * Creates an evaluation which records `NodeStats` for each node as it runs.
+     */
+    pub fn with_stats() -> Self {
+        let mut eval = Self::new();
+        eval.stats = Some(Vec::new());
+        eval
+    }
+
+    /**
+     * Returns the statistics for each node, or an empty slice if stats aren't being recorded.
+     */
+    pub fn node_stats(&self) -> &[NodeStats] {
+        self.stats.as_ref().map(|stats| &stats[..]).unwrap_or(&[])
+    }
+
+    /**
+     * Returns the number of distinct register states `node` matched and refuted, which is
+     * `(0, 0)` for nodes which aren't match nodes or weren't reached. A node which refutes every
+     * state is a likely reason for a diagram producing nothing.
+     */
+    pub fn match_stats(&self, node: NodeIndex) -> (usize, usize) {
+        match self.states.get(node.0).and_then(|state| state.output.as_ref()) {
+            Some(&NodeOutputState::Match {
+                ref matches,
+                ref refutes,
+            }) => (matches.len(), refutes.len()),
+            _ => (0, 0),
+        }
+    }
+
+    /**
+     * Sets the weight decay of every node's input. See `RegisterSet::push`.
+     */
+    pub fn set_weight_decay(&mut self, decay: WeightDecay) {
+        self.weight_decay = decay;
+        for state in self.states.iter_mut() {
+            state.input.set_weight_decay(decay);
+        }
+    }
+
+    /**
+     * Enables or disables subsumption in every node's input. See `RegisterSet::set_subsumption`
+     * for when this preserves the output.
+     */
+    pub fn set_subsumption(&mut self, subsumption: bool) {
+        self.subsumption = subsumption;
+        for state in self.states.iter_mut() {
+            state.input.set_subsumption(subsumption);
+        }
+    }
+
+    fn new_input(&self, num_registers: usize) -> RegisterSet {
+        let mut input = RegisterSet::new(num_registers);
+        input.set_weight_decay(self.weight_decay);
+        input.set_subsumption(self.subsumption);
+        input
+    }
+
+    fn push_input(
+        &mut self,
+        node: NodeIndex,
+        registers: RegisterFile,
+        weight: Weight,
+        depth: usize,
+    ) {
+        let before = self.states[node.0].input.len();
+        self.states[node.0].input.push(registers, weight, depth);
+        let after = self.states[node.0].input.len();
+        self.num_facts = (self.num_facts + after).saturating_sub(before);
+        if let Some(ref mut stats) = self.stats {
+            stats[node.0].num_pushes += 1;
+        }
+    }
+
+    fn record_propagation(&mut self, node: NodeIndex) {
+        if let Some(ref mut stats) = self.stats {
+            stats[node.0].num_propagations += 1;
+        }
+    }
+
+    fn record_output_facts(&mut self, node: NodeIndex, num_facts: usize) {
+        if let Some(ref mut stats) = self.stats {
+            stats[node.0].num_output_facts += num_facts;
+        }
+    }
+
+    /**
+     * Limit the number of register states and output facts the evaluation may hold.
+     * Once the limit is exceeded the evaluation stops early and is marked as truncated.
+     */
+    pub fn set_max_facts(&mut self, max_facts: Option<usize>) {
+        self.max_facts = max_facts;
+    }
+
+    /**
+     * Limit how many match nodes a register state may pass through. Match nodes ignore input
+     * states which have already passed through `max_depth` match nodes.
+     */
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /**
+     * Updates `num_facts` after the state of `node` changed from holding `before` facts.
+     */
+    fn recount_node(&mut self, node: NodeIndex, before: usize) {
+        self.num_facts = (self.num_facts + self.states[node.0].len()).saturating_sub(before);
+    }
+
+    /**
+     * Merges `output` into the output of `node`, returning whether a new state was added.
+     */
+    fn merge_output(&mut self, node: NodeIndex, output: NodeOutputState) -> bool {
+        let before = self.states[node.0].len();
+        let found_new_state = self.states[node.0].merge_output(output);
+        self.recount_node(node, before);
+        found_new_state
+    }
+
+    /**
+     * Estimates how much state the evaluation is holding. Useful for tuning `set_max_facts` and
+     * register widths; the byte count isn't exact, but grows with the real usage.
+     */
+    pub fn approx_memory(&self) -> MemoryReport {
+        let mut report = MemoryReport {
+            approx_bytes: approx_database_bytes(&self.total_db),
+            ..Default::default()
+        };
+        for state in self.states.iter() {
+            report.num_register_states += state.input.len();
+            report.approx_bytes += approx_register_set_bytes(&state.input);
+            match state.output {
+                Some(NodeOutputState::Match {
+                    ref matches,
+                    ref refutes,
+                }) => {
+                    report.num_register_states += matches.len() + refutes.len();
+                    report.approx_bytes +=
+                        approx_register_set_bytes(matches) + approx_register_set_bytes(refutes);
+                }
+                Some(NodeOutputState::Output { ref db }) => {
+                    report.num_output_facts += db.len();
+                    report.approx_bytes += approx_database_bytes(db);
+                }
+                None => {}
+            }
+        }
+        report
+    }
+
+    fn check_max_facts(&mut self) -> bool {
+        if let Some(max_facts) = self.max_facts {
+            if !self.truncated && self.num_facts + self.total_db.len() > max_facts {
+                self.truncated = true;
+            }
+        }
+        self.truncated
+    }
+
     pub fn eval<D: MultiDiagram>(diagram: &D, input: &Database, num_registers: usize) -> Self {
         let mut eval = Self::new();
         eval.evaluate_recursively(diagram, input, num_registers);
@@ -270,22 +809,22 @@ impl Evaluation {
         weight: Weight,
         depth: usize,
     ) {
-        if node.0 >= self.states.len() {
+        if node.0 >= self.states.len() || self.check_max_facts() {
             return;
         }
-        self.states[node.0]
-            .input
-            .push(registers.clone(), weight, depth);
+        self.push_input(node, registers.clone(), weight, depth);
         match *diagram.get_node(node) {
             Node::Match {
                 predicate,
                 ref terms,
+                require_bound,
             } => {
                 let mut matches = RegisterSet::new(registers.len());
                 let mut refutes = RegisterSet::new(registers.len());
                 if propagate_match_node_into_output(
                     predicate,
                     terms,
+                    require_bound,
                     input,
                     registers,
                     weight,
@@ -309,51 +848,242 @@ impl Evaluation {
                         weight,
                     );
                 }
-                self.states[node.0].merge_output(NodeOutputState::Match { matches, refutes });
+                self.merge_output(node, NodeOutputState::Match { matches, refutes });
             }
             Node::Output {
                 predicate,
                 ref terms,
+                skip_on_unbound,
             } => {
-                if let NodeOutputState::Output { ref mut db } = *self.states[node.0]
+                // Weights of opposite sign cancel, so the output can shrink as well as grow.
+                let (num_facts_before, num_facts_after) = if let NodeOutputState::Output {
+                    ref mut db,
+                } = *self.states[node.0]
                     .output
                     .get_or_insert_with(|| NodeOutputState::Output {
                         db: Database::new(),
                     }) {
-                    propagate_output_node_into_output(predicate, terms, registers, weight, db);
+                    let num_facts_before = db.len();
+                    propagate_output_node_into_output(
+                        node,
+                        predicate,
+                        terms,
+                        skip_on_unbound,
+                        registers,
+                        weight,
+                        db,
+                        &mut self.fresh_symbols,
+                    );
+                    (num_facts_before, db.len())
                 } else {
                     panic!("node changed type?");
-                }
+                };
+                self.num_facts =
+                    (self.num_facts + num_facts_after).saturating_sub(num_facts_before);
+                self.record_output_facts(node, num_facts_after.saturating_sub(num_facts_before));
             }
-        }
-    }
-
+            Node::Aggregate {
+                predicate,
+                ref terms,
+                agg,
+            } => {
+                self.reaggregate(node, predicate, terms, agg);
+            }
+        }
+    }
+
+    /**
+     * Aggregates depend on every state that has reached the node, so they are recomputed from
+     * the node's whole input rather than merged incrementally.
+     */
+    fn reaggregate(
+        &mut self,
+        node: NodeIndex,
+        predicate: Predicate,
+        terms: &[OutputTerm],
+        agg: AggKind,
+    ) {
+        let db = propagate_aggregate_node_into_output(
+            node,
+            predicate,
+            terms,
+            agg,
+            &self.states[node.0].input,
+            &mut self.fresh_symbols,
+        );
+        if let Some(ref mut stats) = self.stats {
+            stats[node.0].num_output_facts = db.len();
+        }
+        let before = self.states[node.0].len();
+        self.states[node.0].output = Some(NodeOutputState::Output { db });
+        self.recount_node(node, before);
+    }
+
     fn grow(&mut self, num_nodes: usize, num_registers: usize) {
         for _ in self.states.len()..num_nodes {
+            let input = self.new_input(num_registers);
             self.states.push(NodeState {
-                input: RegisterSet::new(num_registers),
+                input,
                 output: None,
             });
         }
+        if let Some(ref mut stats) = self.stats {
+            stats.resize(num_nodes, NodeStats::default());
+        }
     }
 
     pub fn run_multi<D: MultiDiagram>(diagram: &D, input: &Database, num_registers: usize) -> Self {
+        let mut eval = Self::run_multi_without_total(diagram, input, num_registers);
+        eval.build_total_db();
+        eval
+    }
+
+    /**
+     * Like `run_multi`, but leaves `total_db` empty, for callers which only need the outputs of
+     * individual nodes. Call `materialize_total_db` to build it later.
+     */
+    pub fn run_multi_lazy<D: MultiDiagram>(
+        diagram: &D,
+        input: &Database,
+        num_registers: usize,
+    ) -> Self {
+        Self::run_multi_without_total(diagram, input, num_registers)
+    }
+
+    /**
+     * Like `run_multi`, but seeds each root with `initial` instead of an empty register file, so
+     * that pre-bound registers act as parameters of the diagram.
+     */
+    pub fn run_multi_with_registers<D: MultiDiagram>(
+        diagram: &D,
+        input: &Database,
+        initial: RegisterFile,
+    ) -> Self {
         let mut eval = Self::new();
-        eval.grow(diagram.len(), num_registers);
+        eval.run_roots_with_registers(diagram, input, initial, None);
+        eval.build_total_db();
+        eval
+    }
+
+    /**
+     * Like `run_multi`, but seeds each root with its weight in `root_weights`, or one if it has
+     * none, so that roots can carry a prior weight into the facts they derive.
+     */
+    pub fn run_multi_weighted<D: MultiDiagram>(
+        diagram: &D,
+        input: &Database,
+        num_registers: usize,
+        root_weights: &HashMap<NodeIndex, Weight>,
+    ) -> Self {
+        let mut eval = Self::new();
+        eval.run_roots_with_registers(
+            diagram,
+            input,
+            RegisterFile::new(num_registers),
+            Some(root_weights),
+        );
+        eval.build_total_db();
+        eval
+    }
+
+    pub fn run_multi_with_max_facts<D: MultiDiagram>(
+        diagram: &D,
+        input: &Database,
+        num_registers: usize,
+        max_facts: usize,
+    ) -> Self {
+        let mut eval = Self::new();
+        eval.set_max_facts(Some(max_facts));
+        eval.run_roots(diagram, input, num_registers);
+        eval.build_total_db();
+        eval
+    }
+
+    /**
+     * Returns the facts derived by register states which pass through at most `max_depth` match
+     * nodes. Calling this for increasing depths shows how quickly a recursive diagram grows.
+     */
+    pub fn facts_at_depth<D: MultiDiagram>(
+        diagram: &D,
+        input: &Database,
+        num_registers: usize,
+        max_depth: usize,
+    ) -> Database {
+        let mut eval = Self::new();
+        eval.set_max_depth(max_depth);
+        eval.run_roots(diagram, input, num_registers);
+        eval.build_total_db();
+        eval.total_db
+    }
+
+    /**
+     * Evaluates `diagram` over `input` together with every fact it derives, so that match nodes
+     * can read facts produced by output nodes. This is semi-naive: after the first evaluation,
+     * each round only observes the facts derived by the previous round, see `observe_all`. Stops
+     * once a round derives nothing new. If that hasn't happened after `max_depth` rounds, the
+     * evaluation is marked as truncated, since its output may be missing derivable facts.
+     */
+    pub fn run_fixpoint<D: MultiDiagram>(
+        diagram: &D,
+        input: &Database,
+        num_registers: usize,
+    ) -> Self {
+        let mut available = input.clone();
+        let mut eval = Self::run_multi(diagram, &available, num_registers);
+        let mut delta = unavailable_facts(&eval.total_db, &available);
+        let mut rounds = 0;
+        while !delta.is_empty() {
+            if rounds == eval.max_depth {
+                eval.truncated = true;
+                break;
+            }
+            rounds += 1;
+            let derived = eval.observe_all(diagram, &mut available, &delta);
+            delta = unavailable_facts(&derived, &available);
+        }
+        eval
+    }
+
+    fn run_multi_without_total<D: MultiDiagram>(
+        diagram: &D,
+        input: &Database,
+        num_registers: usize,
+    ) -> Self {
+        let mut eval = Self::new();
+        eval.run_roots(diagram, input, num_registers);
+        eval
+    }
+
+    fn run_roots<D: MultiDiagram>(&mut self, diagram: &D, input: &Database, num_registers: usize) {
+        self.run_roots_with_registers(diagram, input, RegisterFile::new(num_registers), None);
+    }
+
+    fn run_roots_with_registers<D: MultiDiagram>(
+        &mut self,
+        diagram: &D,
+        input: &Database,
+        initial: RegisterFile,
+        root_weights: Option<&HashMap<NodeIndex, Weight>>,
+    ) {
+        let num_registers = initial.len();
+        let root_weight = |root: &NodeIndex| {
+            root_weights
+                .and_then(|weights| weights.get(root).cloned())
+                .unwrap_or(Weight(1))
+        };
+        self.grow(diagram.len(), num_registers);
         for root in diagram.get_group(EdgeGroup::Roots) {
             if root.0 >= diagram.len() {
                 continue;
             }
-            eval.states[root.0]
-                .input
-                .push(RegisterFile::new(num_registers), Weight(1), 0);
+            self.push_input(*root, initial.clone(), root_weight(root), 0);
         }
         let pending: Vec<(NodeIndex, RegisterSet)> = diagram
             .get_group(EdgeGroup::Roots)
             .iter()
             .filter_map(|n| {
                 let mut regs = RegisterSet::new(num_registers);
-                regs.push(RegisterFile::new(num_registers), Weight(1), 0);
+                regs.push(initial.clone(), root_weight(n), 0);
                 if n.0 < diagram.len() {
                     Some((*n, regs))
                 } else {
@@ -361,11 +1091,168 @@ impl Evaluation {
                 }
             })
             .collect();
-        eval.run_pending(diagram, input, pending);
-        eval.build_total_db();
+        self.run_pending(diagram, input, pending);
+    }
+
+    /**
+     * Clears all state in place, keeping allocations so the evaluation can be run again.
+     */
+    pub fn reset(&mut self, num_nodes: usize, num_registers: usize) {
+        self.states.truncate(num_nodes);
+        for state in self.states.iter_mut() {
+            state.input.reset(num_registers);
+            state.output = None;
+        }
+        if let Some(ref mut stats) = self.stats {
+            stats.clear();
+        }
+        self.grow(num_nodes, num_registers);
+        self.total_db.clear();
+        self.fresh_symbols.clear();
+        self.num_facts = 0;
+        self.truncated = false;
+    }
+
+    pub fn rerun<D: MultiDiagram>(&mut self, diagram: &D, input: &Database, num_registers: usize) {
+        self.reset(diagram.len(), num_registers);
+        self.run_roots(diagram, input, num_registers);
+        self.build_total_db();
+    }
+
+    /**
+     * Like `run_multi`, but if no cycle is reachable from the roots, each node is propagated once,
+     * in `topo_order`, after all of its sources have finished. The same facts are produced, but
+     * repeated derivations of a register file are merged before reaching an output node, so
+     * output tables may hold fewer duplicate rows. Diagrams with cycles fall back to `run_multi`.
+     */
+    pub fn run_multi_ordered<D: MultiDiagram>(
+        diagram: &D,
+        input: &Database,
+        num_registers: usize,
+    ) -> Self {
+        let mut eval = Self::new();
+        eval.rerun_ordered(diagram, input, num_registers);
         eval
     }
 
+    pub fn rerun_ordered<D: MultiDiagram>(
+        &mut self,
+        diagram: &D,
+        input: &Database,
+        num_registers: usize,
+    ) {
+        self.reset(diagram.len(), num_registers);
+        if let Some(order) = diagram.topo_order() {
+            self.run_in_order(diagram, input, num_registers, &order);
+        } else {
+            self.run_roots(diagram, input, num_registers);
+        }
+        self.build_total_db();
+    }
+
+    fn run_in_order<D: MultiDiagram>(
+        &mut self,
+        diagram: &D,
+        input: &Database,
+        num_registers: usize,
+        order: &[NodeIndex],
+    ) {
+        for root in diagram.get_group(EdgeGroup::Roots) {
+            if root.0 < diagram.len() {
+                self.push_input(*root, RegisterFile::new(num_registers), Weight(1), 0);
+            }
+        }
+        for &node in order {
+            if self.check_max_facts() {
+                return;
+            }
+            self.record_propagation(node);
+            if let Node::Aggregate {
+                predicate,
+                ref terms,
+                agg,
+            } = *diagram.get_node(node)
+            {
+                self.reaggregate(node, predicate, terms, agg);
+                continue;
+            }
+            let output = propagate(
+                diagram,
+                node,
+                input,
+                &self.states[node.0].input,
+                Some(self.max_depth),
+                &mut self.fresh_symbols,
+            );
+            match output {
+                NodeOutputState::Output { ref db } => {
+                    self.record_output_facts(node, db.len());
+                }
+                NodeOutputState::Match {
+                    ref matches,
+                    ref refutes,
+                } => {
+                    for n in diagram.get_group(EdgeGroup::MatchTargets(node)) {
+                        for (r, w, d) in matches.iter() {
+                            self.push_input(*n, r.clone(), w, d);
+                        }
+                    }
+                    for n in diagram.get_group(EdgeGroup::RefuteTargets(node)) {
+                        for (r, w, d) in refutes.iter() {
+                            self.push_input(*n, r.clone(), w, d);
+                        }
+                    }
+                }
+            }
+            self.merge_output(node, output);
+        }
+    }
+
+    /**
+     * Yields the database of each output node, without merging them into a single database.
+     */
+    pub fn into_output_databases(self) -> impl Iterator<Item = Database> {
+        self.states.into_iter().filter_map(|state| {
+            if let Some(NodeOutputState::Output { db }) = state.output {
+                Some(db)
+            } else {
+                None
+            }
+        })
+    }
+
+    /**
+     * Yields the facts produced by each output node, without merging them into a single database.
+     */
+    pub fn into_output_facts(self) -> impl Iterator<Item = (OwnedFact, Weight)> {
+        self.into_output_databases().flat_map(|db| db.into_weighted_facts())
+    }
+
+    pub fn stream_multi<D: MultiDiagram>(
+        diagram: &D,
+        input: &Database,
+        num_registers: usize,
+    ) -> impl Iterator<Item = (OwnedFact, Weight)> {
+        Self::run_multi_without_total(diagram, input, num_registers).into_output_facts()
+    }
+
+    /**
+     * Like `stream_multi`, but compacts each output node's database just before yielding its
+     * facts, so repeated rows within a node are merged without copying the whole output.
+     */
+    pub fn stream_multi_compacted<D: MultiDiagram>(
+        diagram: &D,
+        input: &Database,
+        num_registers: usize,
+    ) -> impl Iterator<Item = (OwnedFact, Weight)> {
+        Self::run_multi_without_total(diagram, input, num_registers)
+            .into_output_databases()
+            .flat_map(|mut db| {
+                db.compact();
+                db.into_weighted_facts()
+            })
+    }
+
     pub fn run_pending<D: MultiDiagram>(
         &mut self,
         diagram: &D,
@@ -373,11 +1260,34 @@ impl Evaluation {
         mut pending: Vec<(NodeIndex, RegisterSet)>,
     ) {
         while let Some((node, regs)) = pending.pop() {
+            if self.check_max_facts() {
+                return;
+            }
             for (r, w, d) in regs.iter() {
-                self.states[node.0].input.push(r.clone(), w, d);
+                self.push_input(node, r.clone(), w, d);
             }
-            let output = propagate(diagram, node, input, &regs, Some(self.max_depth));
-            if self.states[node.0].merge_output(output.clone()) {
+            self.record_propagation(node);
+            if let Node::Aggregate {
+                predicate,
+                ref terms,
+                agg,
+            } = *diagram.get_node(node)
+            {
+                self.reaggregate(node, predicate, terms, agg);
+                continue;
+            }
+            let output = propagate(
+                diagram,
+                node,
+                input,
+                &regs,
+                Some(self.max_depth),
+                &mut self.fresh_symbols,
+            );
+            if let NodeOutputState::Output { ref db } = output {
+                self.record_output_facts(node, db.len());
+            }
+            if self.merge_output(node, output.clone()) {
                 if let NodeOutputState::Match {
                     ref matches,
                     ref refutes,
@@ -394,6 +1304,82 @@ impl Evaluation {
         }
     }
 
+    /**
+     * Extends this evaluation, which must be finished over `input`, with `fact`, which is inserted
+     * into `input`. Only the new fact is tested against each match node's existing input, and
+     * the resulting states are propagated as usual. Since a new fact never retracts a match or a
+     * refutation, `total_db` ends up with the same facts as a batch evaluation over the extended
+     * `input`. Aggregates are recomputed, so aggregate facts which are superseded are dropped.
+     * Returns the facts which weren't in `total_db` before.
+     */
+    pub fn observe<D: MultiDiagram>(
+        &mut self,
+        diagram: &D,
+        input: &mut Database,
+        fact: Fact,
+    ) -> Database {
+        let mut new_fact = Database::new();
+        new_fact.insert_fact(fact);
+        self.observe_all(diagram, input, &new_fact)
+    }
+
+    /**
+     * Like `observe`, but for every fact in `facts`, which shouldn't already be in `input`.
+     */
+    pub fn observe_all<D: MultiDiagram>(
+        &mut self,
+        diagram: &D,
+        input: &mut Database,
+        facts: &Database,
+    ) -> Database {
+        let mut pending = Vec::new();
+        for index in 0..cmp::min(diagram.len(), self.states.len()) {
+            let node = NodeIndex(index);
+            match *diagram.get_node(node) {
+                Node::Match { predicate, .. } if facts.num_facts_for_predicate(predicate) > 0 => {}
+                _ => continue,
+            }
+            if self.states[index].input.is_empty() {
+                continue;
+            }
+            self.record_propagation(node);
+            let output = propagate(
+                diagram,
+                node,
+                facts,
+                &self.states[index].input,
+                Some(self.max_depth),
+                &mut self.fresh_symbols,
+            );
+            if self.merge_output(node, output.clone()) {
+                if let NodeOutputState::Match { matches, refutes } = output {
+                    for n in diagram.get_group(EdgeGroup::MatchTargets(node)) {
+                        pending.push((*n, matches.clone()));
+                    }
+                    for n in diagram.get_group(EdgeGroup::RefuteTargets(node)) {
+                        pending.push((*n, refutes.clone()));
+                    }
+                }
+            }
+        }
+        for (fact, weight) in facts.weighted_facts() {
+            input.insert_fact_with_weight(fact, weight);
+        }
+        self.run_pending(diagram, input, pending);
+
+        let previous_total = mem::replace(&mut self.total_db, Database::new());
+        self.build_total_db();
+        let previous_facts: HashSet<Fact> = previous_total.all_facts().collect();
+        let mut derived = Database::new();
+        let mut seen = HashSet::new();
+        for fact in self.total_db.all_facts() {
+            if !previous_facts.contains(&fact) && seen.insert(fact) {
+                derived.insert_fact(fact);
+            }
+        }
+        derived
+    }
+
     pub fn build_total_db(&mut self) {
         for db in self.states.iter().filter_map(|state| {
             if let &Some(NodeOutputState::Output { ref db }) = &state.output {
@@ -408,6 +1394,221 @@ impl Evaluation {
         }
     }
 
+    /**
+     * Rebuilds `total_db` from the output of every node, discarding whatever it held before.
+     */
+    pub fn materialize_total_db(&mut self) {
+        self.total_db.clear();
+        self.build_total_db();
+    }
+
+    pub fn fact_weight(&self, fact: Fact) -> Weight {
+        let mut total = Weight(0);
+        for state in self.states.iter() {
+            if let Some(NodeOutputState::Output { ref db }) = state.output {
+                total = total.saturating_add(db.weight(fact));
+            }
+        }
+        total
+    }
+
+    /**
+     * Explains each way `fact` was emitted by an output node, tracing register files back through
+     * the match nodes which produced them to the input facts they tested. Facts emitted by
+     * aggregate nodes aren't explained. Each state is only explained once, and then copied
+     * wherever else it's needed.
+     */
+    pub fn explain<D: MultiDiagram>(
+        &self,
+        diagram: &D,
+        input: &Database,
+        fact: Fact,
+    ) -> DerivationTree {
+        let mut sources = Vec::new();
+        let mut path = Vec::new();
+        let mut explained = HashMap::new();
+        // Every output node's input has already been seen, so this allocates no new symbols.
+        let mut fresh = self.fresh_symbols.clone();
+        for (index, state) in self.states.iter().enumerate().take(diagram.len()) {
+            let node = NodeIndex(index);
+            if let Node::Output {
+                predicate,
+                ref terms,
+                skip_on_unbound,
+            } = *diagram.get_node(node)
+            {
+                if predicate != fact.predicate {
+                    continue;
+                }
+                for (registers, _, _) in state.input.iter() {
+                    let values = output_values(node, terms, skip_on_unbound, registers, &mut fresh);
+                    if values.as_ref().map(|v| &v[..]) == Some(fact.values) {
+                        let (tree, _) = self.explain_state(
+                            diagram,
+                            input,
+                            node,
+                            registers,
+                            &mut path,
+                            &mut explained,
+                        );
+                        sources.push((*tree).clone());
+                    }
+                }
+            }
+        }
+        DerivationTree::Fact {
+            fact: OwnedFact {
+                predicate: fact.predicate,
+                values: fact.values.to_owned(),
+            },
+            sources,
+        }
+    }
+
+    /**
+     * Explains `node` being reached with `registers`. Also returns whether the explanation
+     * contains a back reference, since such explanations depend on `path` and so can't be reused
+     * through `explained`.
+     */
+    fn explain_state<D: MultiDiagram>(
+        &self,
+        diagram: &D,
+        input: &Database,
+        node: NodeIndex,
+        registers: &RegisterFile,
+        path: &mut Vec<(NodeIndex, RegisterFile)>,
+        explained: &mut HashMap<(NodeIndex, RegisterFile), Rc<DerivationTree>>,
+    ) -> (Rc<DerivationTree>, bool) {
+        if path.iter().any(|&(n, ref r)| n == node && r == registers) {
+            let tree = DerivationTree::BackReference {
+                node,
+                registers: registers.clone(),
+            };
+            return (Rc::new(tree), true);
+        }
+        if let Some(tree) = explained.get(&(node, registers.clone())) {
+            return (tree.clone(), false);
+        }
+        path.push((node, registers.clone()));
+        let mut has_back_reference = false;
+        let mut causes = Vec::new();
+        let is_initial = self.states[node.0]
+            .input
+            .iter()
+            .any(|(r, _, depth)| depth == 0 && r == registers);
+        if is_initial && diagram.edge_exists(Edge::Root(node)) {
+            causes.push(DerivationTree::Root);
+        }
+        let sources = diagram
+            .get_group(EdgeGroup::MatchSources(node))
+            .iter()
+            .map(|&s| (s, true))
+            .chain(
+                diagram
+                    .get_group(EdgeGroup::RefuteSources(node))
+                    .iter()
+                    .map(|&s| (s, false)),
+            );
+        for (source, want_match) in sources {
+            if let Node::Match {
+                predicate,
+                ref terms,
+                require_bound,
+            } = *diagram.get_node(source)
+            {
+                for (source_registers, _, depth) in self.states[source.0].input.iter() {
+                    if depth >= self.max_depth
+                        || (require_bound && !constraints_bound(terms, source_registers))
+                    {
+                        continue;
+                    }
+                    for fact in input.facts_for_predicate(predicate) {
+                        let (result, matched) = match_values(terms, source_registers, fact.values);
+                        if matched == want_match && result == *registers {
+                            let (source_tree, source_has_back_reference) = self.explain_state(
+                                diagram,
+                                input,
+                                source,
+                                source_registers,
+                                path,
+                                explained,
+                            );
+                            has_back_reference |= source_has_back_reference;
+                            causes.push(DerivationTree::Step {
+                                fact: OwnedFact {
+                                    predicate,
+                                    values: fact.values.to_owned(),
+                                },
+                                matched,
+                                source: source_tree,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        path.pop();
+        let tree = Rc::new(DerivationTree::State {
+            node,
+            registers: registers.clone(),
+            causes,
+        });
+        if !has_back_reference {
+            explained.insert((node, registers.clone()), tree.clone());
+        }
+        (tree, has_back_reference)
+    }
+
+    /**
+     * Iterates over the output facts of `predicate`, see `Database::facts_for_predicate`.
+     */
+    pub fn facts_for_predicate(&self, predicate: Predicate) -> PredicateIter {
+        self.total_db.facts_for_predicate(predicate)
+    }
+
+    pub fn total_weight(&self) -> Weight {
+        self.total_db
+            .weighted_facts()
+            .fold(Weight(0), |total, (_, weight)| total.saturating_add(weight))
+    }
+
+    pub fn weight_by_predicate(&self) -> HashMap<Predicate, Weight> {
+        self.total_db
+            .weighted_facts()
+            .fold(HashMap::new(), |mut weights, (fact, weight)| {
+                {
+                    let total = weights.entry(fact.predicate).or_insert(Weight(0));
+                    *total = total.saturating_add(weight);
+                }
+                weights
+            })
+    }
+
+    /**
+     * Returns the facts which are in this evaluation's output but not in `previous`'s, and the
+     * facts which are in `previous`'s output but no longer in this one's.
+     */
+    pub fn diff(&self, previous: &Evaluation) -> (Database, Database) {
+        let added: HashSet<Fact> = self.total_db
+            .all_facts()
+            .filter(|fact| !previous.total_db.contains(*fact))
+            .collect();
+        let removed: HashSet<Fact> = previous
+            .total_db
+            .all_facts()
+            .filter(|fact| !self.total_db.contains(*fact))
+            .collect();
+        let mut added_db = Database::new();
+        for fact in added {
+            added_db.insert_fact(fact);
+        }
+        let mut removed_db = Database::new();
+        for fact in removed {
+            removed_db.insert_fact(fact);
+        }
+        (added_db, removed_db)
+    }
+
     pub fn rerun_from<D: MultiDiagram>(
         &self,
         diagram: &D,
@@ -429,8 +1630,9 @@ impl Evaluation {
                 continue;
             }
             invalidated.insert(node);
+            let fresh = eval.new_input(num_registers);
             eval.states[node.0] = NodeState {
-                input: RegisterSet::new(num_registers),
+                input: fresh,
                 output: None,
             };
             for n in diagram
@@ -479,8 +1681,1100 @@ impl Evaluation {
             }
             pending.push((*node, input.clone()));
         }
+        eval.num_facts = eval.states.iter().map(NodeState::len).sum();
         eval.run_pending(diagram, input, pending);
         eval.build_total_db();
         return Some(eval);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::{database_literal, symbols};
+    use parse::parse_diagram;
+
+    #[test]
+    fn rerun_after_reset_matches_fresh_run() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0, _ -> %1) {
+          output @1(%1, %0)
+        }
+        "#,
+            2,
+        ).unwrap();
+        let first = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1), Value::Symbol(2)]),
+        ]);
+        let second = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(3), Value::Symbol(4)]),
+        ]);
+        let mut eval = Evaluation::run_multi(&diagram, &first, 2);
+        eval.rerun(&diagram, &second, 2);
+        assert_eq!(
+            eval.total_db,
+            Evaluation::run_multi(&diagram, &second, 2).total_db
+        );
+    }
+
+    #[test]
+    fn can_count_and_sum_by_group() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0, _ -> %1) {
+          count @1(%0);
+          sum %1 @2(%0)
+        }
+        "#,
+            2,
+        ).unwrap();
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1), Value::Symbol(2)]),
+            (Predicate(0), vec![Value::Symbol(1), Value::Symbol(3)]),
+            (Predicate(0), vec![Value::Symbol(2), Value::Symbol(4)]),
+        ]);
+        let output = Evaluation::run_multi(&diagram, &input, 2).total_db;
+        let facts: HashSet<_> = output.all_facts().collect();
+        assert_eq!(
+            facts,
+            [
+                Fact {
+                    predicate: Predicate(1),
+                    values: &[Value::Symbol(1), Value::Symbol(2)],
+                },
+                Fact {
+                    predicate: Predicate(1),
+                    values: &[Value::Symbol(2), Value::Symbol(1)],
+                },
+                Fact {
+                    predicate: Predicate(2),
+                    values: &[Value::Symbol(1), Value::Symbol(5)],
+                },
+                Fact {
+                    predicate: Predicate(2),
+                    values: &[Value::Symbol(2), Value::Symbol(4)],
+                },
+            ].iter()
+                .cloned()
+                .collect()
+        );
+    }
+
+    #[test]
+    fn aggregates_skip_missing_registers_and_clamp_totals() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0, _ -> %1) {
+          count @1(%0, %100);
+          sum %100 @2(%0);
+          sum %1 @3(%0)
+        }
+        "#,
+            2,
+        ).unwrap();
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1), Value::Symbol(2)]),
+        ]);
+        let output = Evaluation::run_multi(&diagram, &input, 2).total_db;
+        assert_eq!(output.num_facts_for_predicate(Predicate(1)), 0);
+        assert_eq!(output.num_facts_for_predicate(Predicate(2)), 0);
+        assert_eq!(output.num_facts_for_predicate(Predicate(3)), 1);
+
+        // A count of 2 - 1 with a sum of 2 * 2 - 5.
+        let mut registers = RegisterSet::new(1);
+        for &(value, weight) in &[(2, 2), (5, -1)] {
+            let mut register_file = RegisterFile::new(1);
+            register_file[0] = Some(Value::Symbol(value));
+            registers.push(register_file, Weight(weight), 0);
+        }
+        let output = propagate_aggregate_node_into_output(
+            NodeIndex(0),
+            Predicate(3),
+            &[],
+            AggKind::Sum(0),
+            &registers,
+            &mut FreshSymbols::new(),
+        );
+        assert_eq!(
+            output.all_facts().collect::<Vec<_>>(),
+            vec![Fact {
+                predicate: Predicate(3),
+                values: &[Value::Symbol(0)],
+            }]
+        );
+    }
+
+    #[test]
+    fn max_facts_truncates_fan_out() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) {
+          @0(_ -> %1) {
+            output @1(%0, %1)
+          }
+        }
+        "#,
+            2,
+        ).unwrap();
+        let input = database_literal(
+            (0..10)
+                .map(|i| (Predicate(0), vec![Value::Symbol(i)]))
+                .collect(),
+        );
+        let full = Evaluation::run_multi(&diagram, &input, 2);
+        assert!(!full.truncated());
+        assert_eq!(full.total_db.len(), 100);
+        let limited = Evaluation::run_multi_with_max_facts(&diagram, &input, 2, 50);
+        assert!(limited.truncated());
+        assert!(limited.total_db.len() < 100);
+    }
+
+    #[test]
+    fn fact_count_tracks_node_states() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) {
+          @0(_ -> %1) {
+            output @1(%0, %1)
+          }
+        }
+        "#,
+            2,
+        ).unwrap();
+        let input = database_literal(
+            (0..5)
+                .map(|i| (Predicate(0), vec![Value::Symbol(i)]))
+                .collect(),
+        );
+        let count = |eval: &Evaluation| eval.states.iter().map(NodeState::len).sum::<usize>();
+        let multi = Evaluation::run_multi(&diagram, &input, 2);
+        assert_eq!(multi.num_facts, count(&multi));
+        assert_eq!(multi.num_facts, 1 + 5 + 5 + 25 + 25 + 25);
+        let recursive = Evaluation::eval(&diagram, &input, 2);
+        assert_eq!(recursive.num_facts, count(&recursive));
+        let mut reset = multi.clone();
+        reset.reset(diagram.len(), 2);
+        assert_eq!(reset.num_facts, 0);
+    }
+
+    #[test]
+    fn can_diff_evaluations() {
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1), Value::Symbol(2)]),
+            (Predicate(0), vec![Value::Symbol(2), Value::Symbol(2)]),
+        ]);
+        let (previous_diagram, _) = parse_diagram(
+            r#"
+        root: @0(:1 -> %0, _ -> %1) {
+          output @1(%0, %1)
+        }
+        "#,
+            2,
+        ).unwrap();
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(:2 -> %0, _ -> %1) {
+          output @1(%0, %1)
+        }
+        "#,
+            2,
+        ).unwrap();
+        let previous = Evaluation::run_multi(&previous_diagram, &input, 2);
+        let eval = Evaluation::run_multi(&diagram, &input, 2);
+        let (added, removed) = eval.diff(&previous);
+        assert_eq!(
+            added,
+            database_literal(vec![
+                (Predicate(1), vec![Value::Symbol(2), Value::Symbol(2)]),
+            ])
+        );
+        assert_eq!(
+            removed,
+            database_literal(vec![
+                (Predicate(1), vec![Value::Symbol(1), Value::Symbol(2)]),
+            ])
+        );
+        let (added, removed) = eval.diff(&eval);
+        assert_eq!(added.len(), 0);
+        assert_eq!(removed.len(), 0);
+    }
+
+    #[test]
+    fn can_use_scratch_registers() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0, _ -> %1) {
+          @1(%1) {
+            output @2(%0)
+          }
+        }
+        "#,
+            1,
+        ).unwrap();
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1), Value::Symbol(2)]),
+            (Predicate(0), vec![Value::Symbol(3), Value::Symbol(4)]),
+            (Predicate(1), vec![Value::Symbol(4)]),
+        ]);
+        let output = Evaluation::run_multi(&diagram, &input, 1).total_db;
+        assert_eq!(
+            output,
+            database_literal(vec![(Predicate(2), vec![Value::Symbol(3)])])
+        );
+    }
+
+    #[test]
+    fn fact_weight_sums_over_output_nodes() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) {
+          output @1(%0);
+          output @1(%0)
+        } {
+          output @1(:1)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1)]),
+            (Predicate(0), vec![Value::Symbol(2)]),
+        ]);
+        let eval = Evaluation::run_multi(&diagram, &input, 1);
+        let fact = Fact {
+            predicate: Predicate(1),
+            values: &[Value::Symbol(1)],
+        };
+        assert_eq!(eval.fact_weight(fact), Weight(2));
+        let fact = Fact {
+            predicate: Predicate(1),
+            values: &[Value::Symbol(3)],
+        };
+        assert_eq!(eval.fact_weight(fact), Weight(0));
+    }
+
+    #[test]
+    fn skip_on_unbound_suppresses_facts_with_unset_registers() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) {
+          output! @1(%0, %1);
+          output @2(%0, %1);
+          @3(_ -> %1) {
+            output! @1(%0, %1)
+          }
+        }
+        "#,
+            2,
+        ).unwrap();
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1)]),
+            (Predicate(3), vec![Value::Symbol(5)]),
+        ]);
+        let output = Evaluation::run_multi(&diagram, &input, 2).total_db;
+        assert_eq!(
+            output,
+            database_literal(vec![
+                (Predicate(1), vec![Value::Symbol(1), Value::Symbol(5)]),
+                (Predicate(2), vec![Value::Symbol(1), Value::Nil]),
+            ])
+        );
+    }
+
+    #[test]
+    fn initial_registers_filter_first_match() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(%0, _ -> %1) {
+          output @1(%1)
+        }
+        "#,
+            2,
+        ).unwrap();
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1), Value::Symbol(2)]),
+            (Predicate(0), vec![Value::Symbol(3), Value::Symbol(4)]),
+        ]);
+        let mut initial = RegisterFile::new(2);
+        initial[0] = Some(Value::Symbol(3));
+        let output = Evaluation::run_multi_with_registers(&diagram, &input, initial).total_db;
+        assert_eq!(
+            output,
+            database_literal(vec![(Predicate(1), vec![Value::Symbol(4)])])
+        );
+    }
+
+    #[test]
+    fn can_summarize_weights() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) {
+          output @1(%0);
+          output @2(:1)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1)]),
+            (Predicate(0), vec![Value::Symbol(2)]),
+        ]);
+        let eval = Evaluation::run_multi(&diagram, &input, 1);
+        assert_eq!(eval.total_weight(), Weight(4));
+        assert_eq!(
+            eval.weight_by_predicate(),
+            [(Predicate(1), Weight(2)), (Predicate(2), Weight(2))]
+                .iter()
+                .cloned()
+                .collect()
+        );
+    }
+
+    #[test]
+    fn weight_summaries_saturate() {
+        let mut eval = Evaluation::new();
+        for &symbol in &[1, 2] {
+            eval.total_db.insert_fact_with_weight(
+                Fact {
+                    predicate: Predicate(0),
+                    values: &[Value::Symbol(symbol)],
+                },
+                Weight(i32::MAX),
+            );
+        }
+        assert_eq!(eval.total_weight(), Weight(i32::MAX));
+        assert_eq!(eval.weight_by_predicate()[&Predicate(0)], Weight(i32::MAX));
+    }
+
+    #[test]
+    fn fresh_terms_are_distinct_per_register_file() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) {
+          output @1(!, %0)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1)]),
+            (Predicate(0), vec![Value::Symbol(2)]),
+            (Predicate(0), vec![Value::Symbol(1)]),
+        ]);
+        let output = Evaluation::run_multi(&diagram, &input, 1).total_db;
+        let mut fresh: Vec<_> = output.all_facts().map(|f| f.values[0].clone()).collect();
+        fresh.sort_by_key(|value| match *value {
+            Value::Symbol(symbol) => symbol,
+            Value::Nil => 0,
+        });
+        assert_eq!(
+            fresh,
+            vec![
+                Value::Symbol(FIRST_FRESH_SYMBOL),
+                Value::Symbol(FIRST_FRESH_SYMBOL + 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn can_explain_fact() {
+        let (diagram, context) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) {
+          out: output @1(%0)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let root = diagram.get_group(EdgeGroup::Roots)[0];
+        let out = context.node_name_to_info["out"].index;
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1)]),
+            (Predicate(0), vec![Value::Symbol(2)]),
+        ]);
+        let eval = Evaluation::run_multi(&diagram, &input, 1);
+        let fact = Fact {
+            predicate: Predicate(1),
+            values: &[Value::Symbol(1)],
+        };
+        let mut registers = RegisterFile::new(1);
+        registers[0] = Some(Value::Symbol(1));
+        assert_eq!(
+            eval.explain(&diagram, &input, fact),
+            DerivationTree::Fact {
+                fact: OwnedFact {
+                    predicate: Predicate(1),
+                    values: vec![Value::Symbol(1)],
+                },
+                sources: vec![DerivationTree::State {
+                    node: out,
+                    registers,
+                    causes: vec![DerivationTree::Step {
+                        fact: OwnedFact {
+                            predicate: Predicate(0),
+                            values: vec![Value::Symbol(1)],
+                        },
+                        matched: true,
+                        source: Rc::new(DerivationTree::State {
+                            node: root,
+                            registers: RegisterFile::new(1),
+                            causes: vec![DerivationTree::Root],
+                        }),
+                    }],
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn explaining_cycles_uses_back_references() {
+        let (diagram, context) = parse_diagram(
+            r#"
+        root: { loop }
+        loop: @0(_) {
+          loop;
+          out: output @1(:1)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let looping = context.node_name_to_info["loop"].index;
+        let out = context.node_name_to_info["out"].index;
+        let input = database_literal(vec![(Predicate(0), vec![Value::Symbol(1)])]);
+        let eval = Evaluation::run_multi(&diagram, &input, 1);
+        let fact = Fact {
+            predicate: Predicate(1),
+            values: &[Value::Symbol(1)],
+        };
+        let step = |source| DerivationTree::Step {
+            fact: OwnedFact {
+                predicate: Predicate(0),
+                values: vec![Value::Symbol(1)],
+            },
+            matched: true,
+            source: Rc::new(source),
+        };
+        let loop_state = DerivationTree::State {
+            node: looping,
+            registers: RegisterFile::new(1),
+            causes: vec![
+                DerivationTree::Root,
+                step(DerivationTree::BackReference {
+                    node: looping,
+                    registers: RegisterFile::new(1),
+                }),
+            ],
+        };
+        assert_eq!(
+            eval.explain(&diagram, &input, fact),
+            DerivationTree::Fact {
+                fact: OwnedFact {
+                    predicate: Predicate(1),
+                    values: vec![Value::Symbol(1)],
+                },
+                sources: vec![DerivationTree::State {
+                    node: out,
+                    registers: RegisterFile::new(1),
+                    causes: vec![step(loop_state)],
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn explaining_shares_repeated_states() {
+        let mut src = String::from("root: { n0 }\n");
+        for i in 0..40 {
+            src.push_str(&format!("n{}: @0(_) {{ n{} }}\n", i, i + 1));
+        }
+        src.push_str("n40: output @1(:1)");
+        let (diagram, _) = parse_diagram(&src, 1).unwrap();
+        let input = symbols(Predicate(0), &[&[1], &[2]]);
+        let mut eval = Evaluation::new();
+        eval.set_max_depth(40);
+        eval.run_roots(&diagram, &input, 1);
+        let fact = Fact {
+            predicate: Predicate(1),
+            values: &[Value::Symbol(1)],
+        };
+        let tree = eval.explain(&diagram, &input, fact);
+        let mut state = match tree {
+            DerivationTree::Fact { ref sources, .. } => &sources[0],
+            _ => panic!("Expected a fact"),
+        };
+        let mut depth = 0;
+        while let DerivationTree::State { ref causes, .. } = *state {
+            if causes == &[DerivationTree::Root] {
+                break;
+            }
+            let sources: Vec<_> = causes
+                .iter()
+                .map(|cause| match *cause {
+                    DerivationTree::Step { ref source, .. } => source,
+                    _ => panic!("Expected a step"),
+                })
+                .collect();
+            assert_eq!(sources.len(), 2);
+            assert!(Rc::ptr_eq(sources[0], sources[1]));
+            state = sources[0];
+            depth += 1;
+        }
+        assert_eq!(depth, 40);
+    }
+
+    #[test]
+    fn can_record_node_stats() {
+        let (diagram, context) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) {
+          out: output @1(%0)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let root = diagram.get_group(EdgeGroup::Roots)[0];
+        let out = context.node_name_to_info["out"].index;
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1)]),
+            (Predicate(0), vec![Value::Symbol(2)]),
+        ]);
+        let mut eval = Evaluation::with_stats();
+        eval.rerun(&diagram, &input, 1);
+        assert_eq!(eval.node_stats()[out.0].num_output_facts, 2);
+        assert_eq!(eval.node_stats()[out.0].num_pushes, 2);
+        assert_eq!(eval.node_stats()[root.0].num_output_facts, 0);
+        assert!(eval.node_stats()[root.0].num_pushes >= 1);
+        assert_eq!(Evaluation::run_multi(&diagram, &input, 1).node_stats().len(), 0);
+    }
+
+    #[test]
+    fn memory_report_grows_with_state() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) {
+          output @1(%0)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let small = database_literal(vec![(Predicate(0), vec![Value::Symbol(1)])]);
+        let large = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1)]),
+            (Predicate(0), vec![Value::Symbol(2)]),
+            (Predicate(0), vec![Value::Symbol(3)]),
+        ]);
+        assert_eq!(Evaluation::new().approx_memory(), MemoryReport::default());
+        let small_report = Evaluation::run_multi(&diagram, &small, 1).approx_memory();
+        let large_report = Evaluation::run_multi(&diagram, &large, 1).approx_memory();
+        assert_eq!(small_report.num_output_facts, 1);
+        assert_eq!(large_report.num_output_facts, 3);
+        assert!(small_report.num_register_states < large_report.num_register_states);
+        assert!(small_report.approx_bytes < large_report.approx_bytes);
+    }
+
+    #[test]
+    fn require_bound_suppresses_unbound_register_constraints() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(%0) {
+          output @1(:1)
+        } {
+          output @2(:1)
+        }
+        root: @0!(%0) {
+          output @3(:1)
+        } {
+          output @4(:1)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let input = database_literal(vec![(Predicate(0), vec![Value::Symbol(1)])]);
+        let output = Evaluation::run_multi(&diagram, &input, 1).total_db;
+        assert_eq!(
+            output,
+            database_literal(vec![(Predicate(2), vec![Value::Symbol(1)])])
+        );
+    }
+
+    #[test]
+    fn ordered_evaluation_propagates_each_node_once() {
+        let (diagram, context) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) {
+          @1(%0) {
+            joined: @2(%0) {
+              out: output @3(%0)
+            }
+          } {
+            joined
+          }
+        }
+        "#,
+            1,
+        ).unwrap();
+        let joined = context.node_name_to_info["joined"].index;
+        let out = context.node_name_to_info["out"].index;
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1)]),
+            (Predicate(0), vec![Value::Symbol(2)]),
+            (Predicate(1), vec![Value::Symbol(1)]),
+            (Predicate(2), vec![Value::Symbol(1)]),
+            (Predicate(2), vec![Value::Symbol(2)]),
+        ]);
+        let mut fixpoint = Evaluation::with_stats();
+        fixpoint.rerun(&diagram, &input, 1);
+        let mut ordered = Evaluation::with_stats();
+        ordered.rerun_ordered(&diagram, &input, 1);
+        assert!(ordered.total_db.semantically_eq(&fixpoint.total_db));
+        assert!(ordered.total_db.semantically_eq(&database_literal(vec![
+            (Predicate(3), vec![Value::Symbol(1)]),
+            (Predicate(3), vec![Value::Symbol(2)]),
+        ])));
+        assert_eq!(fixpoint.node_stats()[joined.0].num_propagations, 2);
+        assert_eq!(fixpoint.node_stats()[out.0].num_propagations, 2);
+        for stats in ordered.node_stats() {
+            assert_eq!(stats.num_propagations, 1);
+        }
+    }
+
+    #[test]
+    fn out_of_range_register_constraint_refutes() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(%1000000) {
+          output @1(%1000000)
+        } {
+          output @2(%1000000)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let input = database_literal(vec![(Predicate(0), vec![Value::Symbol(1)])]);
+        assert_eq!(
+            Evaluation::run_multi(&diagram, &input, 1).total_db,
+            database_literal(vec![(Predicate(2), vec![Value::Nil])])
+        );
+    }
+
+    #[test]
+    fn out_of_range_register_target_refutes() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %1000000000) {
+          output @1(:1)
+        } {
+          output @2(:2)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let input = database_literal(vec![(Predicate(0), vec![Value::Symbol(1)])]);
+        assert_eq!(
+            Evaluation::run_multi(&diagram, &input, 1).total_db,
+            database_literal(vec![(Predicate(2), vec![Value::Symbol(2)])])
+        );
+    }
+
+    #[test]
+    fn not_constant_term_excludes_value() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(!:2, _ -> %0) {
+          output @1(%0)
+        } {
+          output @2(%0)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let input = symbols(Predicate(0), &[&[1, 10], &[2, 20], &[3, 30]]);
+        let output = Evaluation::run_multi(&diagram, &input, 1).total_db;
+        let mut expected = symbols(Predicate(1), &[&[10], &[30]]);
+        expected.insert_fact(Fact {
+            predicate: Predicate(2),
+            values: &[Value::Symbol(20)],
+        });
+        assert!(output.semantically_eq(&expected));
+    }
+
+    #[test]
+    fn conditional_target_keeps_first_binding() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ ->? %0, _ ->? %0) {
+          output @1(%0)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let input = symbols(Predicate(0), &[&[1, 2], &[3, 4]]);
+        let output = Evaluation::run_multi(&diagram, &input, 1).total_db;
+        assert!(output.semantically_eq(&symbols(Predicate(1), &[&[1], &[3]])));
+
+        let mut initial = RegisterFile::new(1);
+        initial[0] = Some(Value::Symbol(5));
+        let output = Evaluation::run_multi_with_registers(&diagram, &input, initial).total_db;
+        assert!(output.semantically_eq(&symbols(Predicate(1), &[&[5]])));
+    }
+
+    #[test]
+    fn facts_grow_with_depth() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) {
+          step: @1(%0, _ -> %0) {
+            output @2(%0);
+            step
+          }
+        }
+        "#,
+            1,
+        ).unwrap();
+        let mut input = symbols(Predicate(1), &[&[1, 2], &[2, 3], &[3, 4]]);
+        input.insert_fact(Fact {
+            predicate: Predicate(0),
+            values: &[Value::Symbol(1)],
+        });
+        let mut previous = Evaluation::facts_at_depth(&diagram, &input, 1, 0);
+        assert_eq!(previous.len(), 0);
+        for depth in 1..6 {
+            let facts = Evaluation::facts_at_depth(&diagram, &input, 1, depth);
+            assert!(previous.all_facts().all(|fact| facts.contains(fact)));
+            previous = facts;
+        }
+        assert!(
+            Evaluation::facts_at_depth(&diagram, &input, 1, 2)
+                .semantically_eq(&symbols(Predicate(2), &[&[2]]))
+        );
+        assert!(previous.semantically_eq(&symbols(Predicate(2), &[&[2], &[3], &[4]])));
+    }
+
+    #[test]
+    fn match_stats_find_refuting_node() {
+        let (diagram, context) = parse_diagram(
+            r#"
+        root: @0(:1, _ -> %0) {
+          second: @0(%0, :9) {
+            output @1(%0)
+          }
+        }
+        "#,
+            1,
+        ).unwrap();
+        let root = diagram.get_group(EdgeGroup::Roots)[0];
+        let second = context.node_name_to_info["second"].index;
+        let input = symbols(Predicate(0), &[&[1, 2], &[2, 3], &[3, 4]]);
+        let eval = Evaluation::run_multi(&diagram, &input, 1);
+        assert_eq!(eval.total_db.len(), 0);
+        assert_eq!(eval.match_stats(root), (1, 2));
+        assert_eq!(eval.match_stats(second), (0, 1));
+        assert_eq!(eval.match_stats(NodeIndex(100)), (0, 0));
+    }
+
+    #[test]
+    fn weight_decay_bounds_recursive_weights() {
+        let (diagram, context) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) {
+          a: @0(%0) { a } { a }
+        }
+        "#,
+            1,
+        ).unwrap();
+        let a = context.node_name_to_info["a"].index;
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1)]),
+            (Predicate(0), vec![Value::Symbol(2)]),
+        ]);
+        let input_weight = |eval: &Evaluation| -> i32 {
+            eval.states[a.0].input.iter().map(|(_, w, _)| w.0).sum()
+        };
+        let mut eval = Evaluation::new();
+        eval.evaluate_recursively(&diagram, &input, 1);
+        assert!(input_weight(&eval) > 100);
+        let mut decayed = Evaluation::new();
+        decayed.set_weight_decay(WeightDecay::by_depth(4).unwrap());
+        decayed.evaluate_recursively(&diagram, &input, 1);
+        assert_eq!(decayed.states[a.0].input.len(), 2);
+        assert!(input_weight(&decayed) <= 4);
+    }
+
+    #[test]
+    fn subsumption_preserves_monotone_output() {
+        let (diagram, context) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) {
+          shared: @1(%0) {
+            output @2(%0)
+          }
+        }
+        root: @0(_ -> %0) {
+          @3(_ -> %1) { shared }
+        }
+        "#,
+            2,
+        ).unwrap();
+        let shared = context.node_name_to_info["shared"].index;
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1)]),
+            (Predicate(0), vec![Value::Symbol(2)]),
+            (Predicate(1), vec![Value::Symbol(1)]),
+            (Predicate(3), vec![Value::Symbol(5)]),
+        ]);
+        let mut plain = Evaluation::new();
+        plain.rerun(&diagram, &input, 2);
+        let mut subsumed = Evaluation::new();
+        subsumed.set_subsumption(true);
+        subsumed.rerun(&diagram, &input, 2);
+        assert_eq!(plain.states[shared.0].input.len(), 4);
+        assert_eq!(subsumed.states[shared.0].input.len(), 2);
+        assert!(plain.total_db.semantically_eq(&subsumed.total_db));
+        assert!(
+            subsumed
+                .total_db
+                .semantically_eq(&database_literal(vec![(Predicate(2), vec![Value::Symbol(1)])]))
+        );
+    }
+
+    #[test]
+    fn can_evaluate_single_node() {
+        let (diagram, context) = parse_diagram(
+            r#"
+        root: @0(%0, _ -> %1) {
+          out: output @1(%1, :7)
+        }
+        "#,
+            2,
+        ).unwrap();
+        let root = diagram.get_group(EdgeGroup::Roots)[0];
+        let out = context.node_name_to_info["out"].index;
+        let database = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1), Value::Symbol(2)]),
+            (Predicate(0), vec![Value::Symbol(3), Value::Symbol(4)]),
+        ]);
+        let mut bound = RegisterFile::new(2);
+        bound[0] = Some(Value::Symbol(1));
+        let mut input = RegisterSet::new(2);
+        input.push(bound.clone(), Weight(1), 0);
+        let mut fresh = FreshSymbols::new();
+
+        let mut matched = bound.clone();
+        matched[1] = Some(Value::Symbol(2));
+        let mut refuted = bound.clone();
+        refuted[1] = Some(Value::Symbol(4));
+        let mut expected_matches = RegisterSet::new(2);
+        expected_matches.push(matched.clone(), Weight(1), 1);
+        let mut expected_refutes = RegisterSet::new(2);
+        expected_refutes.push(refuted, Weight(1), 1);
+        assert_eq!(
+            evaluate_node(&diagram, root, &database, &input, None, &mut fresh),
+            NodeOutputState::Match {
+                matches: expected_matches,
+                refutes: expected_refutes,
+            }
+        );
+        assert_eq!(
+            evaluate_node(&diagram, root, &database, &input, Some(0), &mut fresh),
+            NodeOutputState::Match {
+                matches: RegisterSet::new(2),
+                refutes: RegisterSet::new(2),
+            }
+        );
+
+        let mut matched_input = RegisterSet::new(2);
+        matched_input.push(matched, Weight(1), 1);
+        let output = vec![(Predicate(1), vec![Value::Symbol(2), Value::Symbol(7)])];
+        assert_eq!(
+            evaluate_node(&diagram, out, &database, &matched_input, None, &mut fresh),
+            NodeOutputState::Output {
+                db: database_literal(output),
+            }
+        );
+    }
+
+    #[test]
+    fn roots_can_be_seeded_with_weights() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) {
+          output @1(%0)
+        }
+        root: @0(_ -> %0) {
+          output @2(%0)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let roots = diagram.get_group(EdgeGroup::Roots).to_owned();
+        let input = database_literal(vec![(Predicate(0), vec![Value::Symbol(1)])]);
+        let weight_of = |eval: &Evaluation, predicate| {
+            eval.fact_weight(Fact {
+                predicate,
+                values: &[Value::Symbol(1)],
+            })
+        };
+        let root_weights = [(roots[0], Weight(3))].iter().cloned().collect();
+        let eval = Evaluation::run_multi_weighted(&diagram, &input, 1, &root_weights);
+        assert_eq!(weight_of(&eval, Predicate(1)), Weight(3));
+        assert_eq!(weight_of(&eval, Predicate(2)), Weight(1));
+        let root_weights = [(roots[0], Weight(2)), (roots[1], Weight(5))]
+            .iter()
+            .cloned()
+            .collect();
+        let eval = Evaluation::run_multi_weighted(&diagram, &input, 1, &root_weights);
+        assert_eq!(weight_of(&eval, Predicate(1)), Weight(2));
+        assert_eq!(weight_of(&eval, Predicate(2)), Weight(5));
+        let unweighted = Evaluation::run_multi(&diagram, &input, 1);
+        assert!(eval.total_db.semantically_eq(&unweighted.total_db));
+    }
+
+    #[test]
+    fn opposite_root_weights_cancel_shared_output() {
+        let (diagram, context) = parse_diagram(
+            r#"
+        root: a: @0(_ -> %0) { out }
+        root: b: @1(_ -> %0) { out }
+        out: output @2(:5)
+        "#,
+            1,
+        ).unwrap();
+        let node = |name: &str| context.node_name_to_info[name].index;
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1)]),
+            (Predicate(1), vec![Value::Symbol(2)]),
+        ]);
+        let root_weights = [(node("a"), Weight(1)), (node("b"), Weight(-1))]
+            .iter()
+            .cloned()
+            .collect();
+        let eval = Evaluation::run_multi_weighted(&diagram, &input, 1, &root_weights);
+        let fact = Fact {
+            predicate: Predicate(2),
+            values: &[Value::Symbol(5)],
+        };
+        assert_eq!(eval.fact_weight(fact), Weight(0));
+        assert!(!eval.total_db.contains(fact));
+        assert_eq!(eval.num_facts, eval.states.iter().map(NodeState::len).sum::<usize>());
+    }
+
+    #[test]
+    fn fact_count_follows_shrinking_inputs() {
+        let node = NodeIndex(0);
+        let registers = |values: &[Option<u64>]| {
+            let mut file = RegisterFile::new(2);
+            for (index, value) in values.iter().enumerate() {
+                file[index] = value.map(Value::Symbol);
+            }
+            file
+        };
+        let mut eval = Evaluation::new();
+        eval.set_subsumption(true);
+        eval.grow(1, 2);
+        let count = |eval: &Evaluation| eval.states.iter().map(NodeState::len).sum::<usize>();
+        eval.push_input(node, registers(&[Some(1), Some(2)]), Weight(1), 0);
+        eval.push_input(node, registers(&[Some(1), Some(3)]), Weight(1), 0);
+        assert_eq!(eval.num_facts, 2);
+        eval.push_input(node, registers(&[Some(1), None]), Weight(1), 0);
+        assert_eq!(eval.num_facts, 1);
+        assert_eq!(eval.num_facts, count(&eval));
+        eval.push_input(node, registers(&[Some(1), None]), Weight(-1), 0);
+        assert_eq!(eval.num_facts, 0);
+        assert_eq!(eval.num_facts, count(&eval));
+    }
+
+    #[test]
+    fn fixpoint_matches_derived_facts() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0, _ -> %1) {
+          output @1(%0, %1)
+        }
+        root: @1(_ -> %0, _ -> %1) {
+          @0(%1, _ -> %2) {
+            output @1(%0, %2)
+          }
+        }
+        "#,
+            3,
+        ).unwrap();
+        let input = symbols(Predicate(0), &[&[1, 2], &[2, 3], &[3, 4]]);
+        assert_eq!(Evaluation::run_multi(&diagram, &input, 3).total_db.len(), 3);
+        let eval = Evaluation::run_fixpoint(&diagram, &input, 3);
+        let paths = symbols(Predicate(1), &[&[1, 2], &[2, 3], &[3, 4], &[1, 3], &[2, 4], &[1, 4]]);
+        assert!(eval.total_db.semantically_eq(&paths));
+        assert!(!eval.truncated());
+
+        let chain: Vec<[u64; 2]> = (1..13).map(|i| [i, i + 1]).collect();
+        let chain: Vec<&[u64]> = chain.iter().map(|edge| &edge[..]).collect();
+        let eval = Evaluation::run_fixpoint(&diagram, &symbols(Predicate(0), &chain), 3);
+        assert!(eval.truncated());
+        assert!(!eval.total_db.contains(Fact {
+            predicate: Predicate(1),
+            values: &[Value::Symbol(1), Value::Symbol(13)],
+        }));
+    }
+
+    #[test]
+    fn lazy_evaluation_materializes_same_total() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0, _ -> %1) {
+          output @1(%0);
+          output @2(%1, %0)
+        }
+        "#,
+            2,
+        ).unwrap();
+        let input = symbols(Predicate(0), &[&[1, 2], &[3, 4]]);
+        let eager = Evaluation::run_multi(&diagram, &input, 2);
+        let mut lazy = Evaluation::run_multi_lazy(&diagram, &input, 2);
+        assert_eq!(lazy.total_db.len(), 0);
+        lazy.materialize_total_db();
+        assert!(lazy.total_db.semantically_eq(&eager.total_db));
+        lazy.materialize_total_db();
+        assert_eq!(lazy.total_db.len(), eager.total_db.len());
+    }
+
+    #[test]
+    fn observing_facts_matches_batch_evaluation() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0, _ -> %1) {
+          @0(%1, _ -> %2) {
+            output @1(%0, %2)
+          } {
+            output @2(%0)
+          }
+        }
+        "#,
+            3,
+        ).unwrap();
+        let facts = vec![
+            vec![Value::Symbol(1), Value::Symbol(2)],
+            vec![Value::Symbol(2), Value::Symbol(3)],
+            vec![Value::Symbol(3), Value::Symbol(1)],
+            vec![Value::Symbol(2), Value::Symbol(4)],
+        ];
+        let mut input = Database::new();
+        let mut eval = Evaluation::run_multi(&diagram, &input, 3);
+        let mut all_derived = Database::new();
+        for values in facts.iter() {
+            let fact = Fact {
+                predicate: Predicate(0),
+                values,
+            };
+            let derived = eval.observe(&diagram, &mut input, fact);
+            let batch = Evaluation::run_multi(&diagram, &input, 3).total_db;
+            assert!(eval.total_db.semantically_eq(&batch));
+            for fact in derived.all_facts() {
+                assert!(!all_derived.contains(fact));
+                all_derived.insert_fact(fact);
+            }
+        }
+        let batch = Evaluation::run_multi(&diagram, &input, 3).total_db;
+        assert!(all_derived.semantically_eq(&batch));
+        assert!(batch.len() > 0);
+    }
+}
@@ -7,6 +7,15 @@ pub struct Fact<'a> {
     pub values: &'a [Value],
 }
 
+impl<'a> Fact<'a> {
+    pub fn to_owned_fact(&self) -> OwnedFact {
+        OwnedFact {
+            predicate: self.predicate,
+            values: self.values.to_vec(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct OwnedFact {
     pub predicate: Predicate,
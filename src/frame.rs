@@ -1,10 +1,120 @@
 use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 
+use database::Database;
 use predicate::Predicate;
 use value::Value;
 
+/**
+ * A predicate appeared with different numbers of terms.
+ */
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum FrameError {
+    WrongNumberOfTerms(Predicate),
+}
+
 #[derive(Debug, Clone)]
 pub struct Frame {
     pub values: HashSet<Value>,
     pub num_terms_for_predicate: HashMap<Predicate, usize>,
 }
+
+impl Frame {
+    pub fn new() -> Self {
+        Frame {
+            values: HashSet::new(),
+            num_terms_for_predicate: HashMap::new(),
+        }
+    }
+
+    /**
+     * Creates a frame containing `Value::Symbol(s)` for each `s` in `symbols`, and no predicates.
+     */
+    pub fn with_symbol_range(symbols: Range<u64>) -> Self {
+        Frame {
+            values: symbols.map(Value::Symbol).collect(),
+            num_terms_for_predicate: HashMap::new(),
+        }
+    }
+
+    pub fn from_samples(samples: &[(Database, Database)]) -> Result<Self, FrameError> {
+        let mut frame = Frame::new();
+        for &(ref input, ref output) in samples {
+            frame.add_database(input)?;
+            frame.add_database(output)?;
+        }
+        Ok(frame)
+    }
+
+    /**
+     * Adds the values and predicates of `db`. Fails without changing the frame if a predicate in
+     * `db` has a different number of terms than it already has in the frame or elsewhere in `db`.
+     */
+    pub fn add_database(&mut self, db: &Database) -> Result<(), FrameError> {
+        let mut num_terms_for_predicate = self.num_terms_for_predicate.clone();
+        for fact in db.all_facts() {
+            let num_terms = *num_terms_for_predicate
+                .entry(fact.predicate)
+                .or_insert(fact.values.len());
+            if num_terms != fact.values.len() {
+                return Err(FrameError::WrongNumberOfTerms(fact.predicate));
+            }
+        }
+        self.num_terms_for_predicate = num_terms_for_predicate;
+        for fact in db.all_facts() {
+            self.values.extend(fact.values.iter().cloned());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::database_literal;
+
+    #[test]
+    fn can_derive_frame_from_samples() {
+        let samples = vec![
+            (
+                database_literal(vec![(Predicate(0), vec![Value::Symbol(0), Value::Symbol(1)])]),
+                database_literal(vec![(Predicate(1), vec![Value::Symbol(1)])]),
+            ),
+            (
+                database_literal(vec![(Predicate(0), vec![Value::Symbol(2), Value::Symbol(1)])]),
+                database_literal(vec![(Predicate(1), vec![Value::Symbol(2)])]),
+            ),
+        ];
+        let frame = Frame::from_samples(&samples).unwrap();
+        let expected = Frame {
+            values: [Value::Symbol(0), Value::Symbol(1), Value::Symbol(2)]
+                .iter()
+                .cloned()
+                .collect(),
+            num_terms_for_predicate: [(Predicate(0), 2), (Predicate(1), 1)]
+                .iter()
+                .cloned()
+                .collect(),
+        };
+        assert_eq!(frame.values, expected.values);
+        assert_eq!(
+            frame.num_terms_for_predicate,
+            expected.num_terms_for_predicate
+        );
+    }
+    #[test]
+    fn rejects_samples_with_inconsistent_arity() {
+        let samples = vec![(
+            database_literal(vec![(Predicate(0), vec![Value::Symbol(0), Value::Symbol(1)])]),
+            database_literal(vec![(Predicate(0), vec![Value::Symbol(1)])]),
+        )];
+        assert_eq!(
+            Frame::from_samples(&samples).err(),
+            Some(FrameError::WrongNumberOfTerms(Predicate(0)))
+        );
+        let mut frame = Frame::from_samples(&samples[..0]).unwrap();
+        assert!(frame.add_database(&samples[0].0).is_ok());
+        assert!(frame.add_database(&samples[0].1).is_err());
+        assert_eq!(frame.values.len(), 2);
+    }
+}
@@ -37,6 +37,7 @@ pub struct UniformMutationContext<'f, 's, 'd, D: 'd + MultiDiagram> {
     frame: &'f Frame,
     space: &'s DiagramSpace,
     diagram: &'d D,
+    predicates: Vec<Predicate>,
 }
 
 pub trait GenMutation {
@@ -61,10 +62,15 @@ fn nonzero_u64(value: u64) -> u64 {
 
 impl<'f, 's, 'd, D: 'd + MultiDiagram> UniformMutationContext<'f, 's, 'd, D> {
     pub fn new(frame: &'f Frame, space: &'s DiagramSpace, diagram: &'d D) -> Self {
+        // Sorted so that the choice of predicate only depends on the rng.
+        let mut predicates: Vec<Predicate> =
+            frame.num_terms_for_predicate.keys().cloned().collect();
+        predicates.sort_by_key(|p| p.0);
         UniformMutationContext {
             frame,
             space,
             diagram,
+            predicates,
         }
     }
 
@@ -147,7 +153,11 @@ impl<'f, 's, 'd, D: 'd + MultiDiagram> UniformMutationContext<'f, 's, 'd, D> {
     }
 
     fn gen_predicate<R: Rng>(&self, rng: &mut R) -> Predicate {
-        Predicate(rng.gen_range(0, self.frame.num_terms_for_predicate.len() as u64))
+        assert!(
+            self.predicates.len() != 0,
+            "frame should have at least one predicate"
+        );
+        self.predicates[rng.gen_range(0, self.predicates.len())]
     }
 
     fn get_num_terms(&self, predicate: Predicate) -> usize {
@@ -187,6 +197,7 @@ impl<'f, 's, 'd, D: 'd + MultiDiagram> UniformMutationContext<'f, 's, 'd, D> {
                     output.push(MatchTerm {
                         constraint: MatchTermConstraint::Register(register),
                         target: None,
+                        if_unbound: false,
                     });
                 }
                 1 => {
@@ -194,12 +205,14 @@ impl<'f, 's, 'd, D: 'd + MultiDiagram> UniformMutationContext<'f, 's, 'd, D> {
                     output.push(MatchTerm {
                         constraint: MatchTermConstraint::Constant(value),
                         target: None,
+                        if_unbound: false,
                     });
                 }
                 2 => {
                     output.push(MatchTerm {
                         constraint: MatchTermConstraint::Free,
                         target: None,
+                        if_unbound: false,
                     });
                 }
                 _ => unreachable!(),
@@ -240,7 +253,7 @@ impl<'f, 's, 'd, D: 'd + MultiDiagram> UniformMutationContext<'f, 's, 'd, D> {
         state: &mut IndividualMutationState,
         rng: &mut R,
     ) -> Option<Mutation> {
-        match rng.gen_range(0, 11) {
+        match rng.gen_range(0, 14) {
             0 => Some(Mutation::SetConstraintRegister {
                 term: self.gen_term(rng, state)?,
                 register: self.gen_register(rng),
@@ -294,6 +307,23 @@ impl<'f, 's, 'd, D: 'd + MultiDiagram> UniformMutationContext<'f, 's, 'd, D> {
                     terms: self.gen_match_terms(rng, predicate),
                 })
             }
+            11 => Some(Mutation::SwapTargets {
+                node: self.gen_match_node(rng, state)?,
+            }),
+            12 => {
+                // Re-rooting discards most of the diagram's behavior, so rarely propose it.
+                if rng.gen_weighted_bool(8) {
+                    Some(Mutation::SetRoot {
+                        node: self.gen_node(rng, state)?,
+                    })
+                } else {
+                    None
+                }
+            }
+            13 => Some(Mutation::ReplaceSubtree {
+                at: self.pick_edge(rng, state)?,
+                with: self.gen_node(rng, state)?,
+            }),
             _ => unreachable!(),
         }
     }
@@ -308,3 +338,57 @@ impl<'f, 's, 'd, D: 'd + MultiDiagram> GenMutation for UniformMutationContext<'f
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph_diagram::GraphDiagram;
+    use rand::SeedableRng;
+    use rand::XorShiftRng;
+
+    #[test]
+    fn only_generates_predicates_in_frame() {
+        let mut rng = XorShiftRng::from_seed([0xde, 0xad, 0xbe, 0xef]);
+        let frame = Frame {
+            values: [Value::Symbol(0), Value::Symbol(1)].iter().cloned().collect(),
+            num_terms_for_predicate: [(Predicate(3), 1), (Predicate(7), 2)]
+                .iter()
+                .cloned()
+                .collect(),
+        };
+        let space = DiagramSpace {
+            num_nodes: 2,
+            num_terms: 2,
+            num_registers: 2,
+        };
+        let diagram = GraphDiagram::new(2);
+        let context = UniformMutationContext::new(&frame, &space, &diagram);
+        for _ in 0..100 {
+            let predicate = context.gen_predicate(&mut rng);
+            assert!(frame.num_terms_for_predicate.contains_key(&predicate));
+            context.gen_match_terms(&mut rng, predicate);
+            context.gen_output_terms(&mut rng, predicate);
+        }
+    }
+
+    #[test]
+    fn symbol_range_frame_only_generates_symbols_in_range() {
+        let mut rng = XorShiftRng::from_seed([0xde, 0xad, 0xbe, 0xef]);
+        let frame = Frame::with_symbol_range(2..5);
+        assert_eq!(frame.values.len(), 3);
+        assert!(frame.num_terms_for_predicate.is_empty());
+        let space = DiagramSpace {
+            num_nodes: 2,
+            num_terms: 2,
+            num_registers: 2,
+        };
+        let diagram = GraphDiagram::new(2);
+        let context = UniformMutationContext::new(&frame, &space, &diagram);
+        for _ in 0..100 {
+            match context.gen_value(&mut rng) {
+                Value::Symbol(s) => assert!(2 <= s && s < 5),
+                value => panic!("unexpected value {:?}", value),
+            }
+        }
+    }
+}
@@ -1,15 +1,26 @@
-use std::collections::{hash_map, HashMap};
+use std::collections::{hash_map, HashMap, HashSet};
+use std::fmt;
 
+use context::Remap;
 use database::Database;
-use diagram::{Diagram, Edge, EdgeGroup, MultiDiagram, Node};
+use diagram::{check_edge, AggKind, Diagram, Edge, EdgeError, EdgeGroup, MatchTermConstraint,
+              MultiDiagram, Node, OutputTerm};
 use evaluation::Evaluation;
+use fact::OwnedFact;
 use fixgraph::{EdgeIndex, FixGraph};
+use gen_mutation::IndividualMutationState;
+use mutate::apply_mutation;
+use mutation::Mutation;
 use node_index::NodeIndex;
+use predicate::Predicate;
+use registers::MAX_SCRATCH_REGISTERS;
+use weight::Weight;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
-struct Edges {
-    on_match: Vec<NodeIndex>,
-    on_refute: Vec<NodeIndex>,
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct Edges {
+    pub on_match: Vec<NodeIndex>,
+    pub on_refute: Vec<NodeIndex>,
 }
 
 impl Edges {
@@ -38,6 +49,127 @@ impl GraphNode {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegisterUsage {
+    pub registers: HashSet<usize>,
+    pub max_register: Option<usize>,
+}
+
+impl RegisterUsage {
+    fn new() -> Self {
+        RegisterUsage {
+            registers: HashSet::new(),
+            max_register: None,
+        }
+    }
+
+    /**
+     * Collects every register read or written by `node`.
+     */
+    pub fn of_node(node: &Node) -> Self {
+        let mut usage = RegisterUsage::new();
+        usage.add_node(node);
+        usage
+    }
+
+    fn add(&mut self, register: usize) {
+        self.registers.insert(register);
+        if self.max_register.map(|max| max < register).unwrap_or(true) {
+            self.max_register = Some(register);
+        }
+    }
+
+    fn add_output_terms(&mut self, terms: &[OutputTerm]) {
+        for term in terms {
+            if let OutputTerm::Register(register) = *term {
+                self.add(register);
+            }
+        }
+    }
+
+    fn add_node(&mut self, node: &Node) {
+        match *node {
+            Node::Match { ref terms, .. } => {
+                for term in terms {
+                    if let MatchTermConstraint::Register(register) = term.constraint {
+                        self.add(register);
+                    }
+                    if let Some(register) = term.target {
+                        self.add(register);
+                    }
+                }
+            }
+            Node::Output { ref terms, .. } => {
+                self.add_output_terms(terms);
+            }
+            Node::Aggregate {
+                ref terms, agg, ..
+            } => {
+                self.add_output_terms(terms);
+                if let AggKind::Sum(register) = agg {
+                    self.add(register);
+                }
+            }
+        }
+    }
+}
+
+/**
+ * The differences between two reachable diagrams. Nodes and edges of the old diagram are given
+ * with its indices, and those of the new diagram with the new diagram's indices.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiagramDiff {
+    pub removed_nodes: Vec<(NodeIndex, Node)>,
+    pub added_nodes: Vec<(NodeIndex, Node)>,
+    /**
+     * Pairs of old and new nodes with the same kind and predicate, which otherwise differ.
+     */
+    pub changed_nodes: Vec<((NodeIndex, Node), (NodeIndex, Node))>,
+    pub removed_edges: Vec<Edge>,
+    pub added_edges: Vec<Edge>,
+}
+
+impl DiagramDiff {
+    pub fn is_empty(&self) -> bool {
+        self.removed_nodes.is_empty() && self.added_nodes.is_empty()
+            && self.changed_nodes.is_empty() && self.removed_edges.is_empty()
+            && self.added_edges.is_empty()
+    }
+}
+
+// Writes one line per change, prefixed with - or +, with nodes in the parser's syntax.
+impl fmt::Display for DiagramDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(index, ref node) in self.removed_nodes.iter() {
+            writeln!(f, "- {}: {}", index.0, node)?;
+        }
+        for &((old, ref old_node), (new, ref new_node)) in self.changed_nodes.iter() {
+            writeln!(f, "- {}: {}", old.0, old_node)?;
+            writeln!(f, "+ {}: {}", new.0, new_node)?;
+        }
+        for &(index, ref node) in self.added_nodes.iter() {
+            writeln!(f, "+ {}: {}", index.0, node)?;
+        }
+        for edge in self.removed_edges.iter() {
+            writeln!(f, "- {}", edge)?;
+        }
+        for edge in self.added_edges.iter() {
+            writeln!(f, "+ {}", edge)?;
+        }
+        Ok(())
+    }
+}
+
+fn same_kind_and_predicate(a: &Node, b: &Node) -> bool {
+    match (a, b) {
+        (&Node::Match { predicate: a, .. }, &Node::Match { predicate: b, .. })
+        | (&Node::Output { predicate: a, .. }, &Node::Output { predicate: b, .. })
+        | (&Node::Aggregate { predicate: a, .. }, &Node::Aggregate { predicate: b, .. }) => a == b,
+        _ => false,
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct GraphDiagram {
     num_registers: usize,
@@ -58,6 +190,517 @@ impl GraphDiagram {
         Evaluation::run_multi(self, input, self.num_registers).total_db
     }
 
+    /**
+     * Evaluates the diagram on `input`, keeping only the output facts of `predicate`.
+     */
+    pub fn evaluate_predicate(&self, input: &Database, predicate: Predicate) -> Vec<OwnedFact> {
+        Evaluation::run_multi(self, input, self.num_registers)
+            .facts_for_predicate(predicate)
+            .into_owned()
+    }
+
+    /**
+     * Evaluates the diagram on `input`, yielding the facts of one output node at a time. Each
+     * node's repeated facts are merged into one with their total weight, but a fact emitted by
+     * several output nodes is yielded once for each of them, as `evaluate` keeps a row for each.
+     */
+    pub fn evaluate_iter(&self, input: &Database) -> impl Iterator<Item = (OwnedFact, Weight)> {
+        Evaluation::stream_multi_compacted(self, input, self.num_registers)
+    }
+
+    /**
+     * Copies the nodes reachable from `node`, and the edges between them, into a new diagram
+     * rooted at `node`'s copy. Copies keep the relative order of the originals. Returns the new
+     * diagram and the index of each copied node's copy.
+     */
+    pub fn subgraph_from(&self, node: NodeIndex) -> (GraphDiagram, HashMap<NodeIndex, NodeIndex>) {
+        let mut reachable = HashSet::new();
+        let mut to_visit = vec![node];
+        while let Some(node) = to_visit.pop() {
+            if node.0 >= self.len() || !reachable.insert(node) {
+                continue;
+            }
+            to_visit.extend(self.out_edges(node).into_iter().map(Edge::target));
+        }
+        let mut nodes: Vec<NodeIndex> = reachable.into_iter().collect();
+        nodes.sort_by_key(|node| node.0);
+        let mut subgraph = GraphDiagram::new(self.num_registers);
+        let mut remap = HashMap::new();
+        for &old in nodes.iter() {
+            remap.insert(old, subgraph.insert_node(self.get_node(old).clone()));
+        }
+        for &old in nodes.iter() {
+            for edge in self.out_edges(old) {
+                let source = remap[&old];
+                let target = remap[&edge.target()];
+                subgraph.insert_edge(match edge {
+                    Edge::Refute { .. } => Edge::Refute { source, target },
+                    _ => Edge::Match { source, target },
+                });
+            }
+        }
+        if let Some(&root) = remap.get(&node) {
+            subgraph.insert_edge(Edge::Root(root));
+        }
+        (subgraph, remap)
+    }
+
+    fn sorted_reachable_nodes(&self) -> Vec<NodeIndex> {
+        let mut nodes: Vec<NodeIndex> = self.reachable_nodes().into_iter().collect();
+        nodes.sort_by_key(|node| node.0);
+        nodes
+    }
+
+    fn edges_between(&self, nodes: &[NodeIndex]) -> Vec<Edge> {
+        let mut edges: Vec<Edge> = self.roots.iter().map(|&root| Edge::Root(root)).collect();
+        for &node in nodes {
+            edges.extend(self.out_edges(node));
+        }
+        edges
+    }
+
+    /**
+     * Compares the reachable parts of `self` and `other`. Identical nodes are paired up first, in
+     * index order, and then nodes with the same kind and predicate, which are reported as changed.
+     * Edges are compared through that pairing.
+     */
+    pub fn diff(&self, other: &GraphDiagram) -> DiagramDiff {
+        let old_nodes = self.sorted_reachable_nodes();
+        let new_nodes = other.sorted_reachable_nodes();
+        let mut old_to_new = HashMap::new();
+        let mut paired_new = HashSet::new();
+        let mut changed_nodes = Vec::new();
+        for &exact in [true, false].iter() {
+            for &old in old_nodes.iter() {
+                if old_to_new.contains_key(&old) {
+                    continue;
+                }
+                let old_node = self.get_node(old);
+                let found = new_nodes.iter().cloned().find(|new| {
+                    let new_node = other.get_node(*new);
+                    !paired_new.contains(new) && if exact {
+                        old_node == new_node
+                    } else {
+                        same_kind_and_predicate(old_node, new_node)
+                    }
+                });
+                if let Some(new) = found {
+                    old_to_new.insert(old, new);
+                    paired_new.insert(new);
+                    if !exact {
+                        changed_nodes.push((
+                            (old, old_node.clone()),
+                            (new, other.get_node(new).clone()),
+                        ));
+                    }
+                }
+            }
+        }
+        let removed_nodes = old_nodes
+            .iter()
+            .filter(|old| !old_to_new.contains_key(old))
+            .map(|&old| (old, self.get_node(old).clone()))
+            .collect();
+        let added_nodes = new_nodes
+            .iter()
+            .filter(|new| !paired_new.contains(new))
+            .map(|&new| (new, other.get_node(new).clone()))
+            .collect();
+
+        let map_edge = |edge: Edge| -> Option<Edge> {
+            match edge {
+                Edge::Root(node) => Some(Edge::Root(*old_to_new.get(&node)?)),
+                Edge::Match { source, target } => Some(Edge::Match {
+                    source: *old_to_new.get(&source)?,
+                    target: *old_to_new.get(&target)?,
+                }),
+                Edge::Refute { source, target } => Some(Edge::Refute {
+                    source: *old_to_new.get(&source)?,
+                    target: *old_to_new.get(&target)?,
+                }),
+            }
+        };
+        let old_edges = self.edges_between(&old_nodes);
+        let new_edges = other.edges_between(&new_nodes);
+        let mapped_old_edges: HashSet<Edge> =
+            old_edges.iter().filter_map(|&edge| map_edge(edge)).collect();
+        let removed_edges = old_edges
+            .iter()
+            .cloned()
+            .filter(|&edge| {
+                map_edge(edge)
+                    .map(|mapped| !new_edges.contains(&mapped))
+                    .unwrap_or(true)
+            })
+            .collect();
+        let added_edges = new_edges
+            .iter()
+            .cloned()
+            .filter(|edge| !mapped_old_edges.contains(edge))
+            .collect();
+        DiagramDiff {
+            removed_nodes,
+            added_nodes,
+            changed_nodes,
+            removed_edges,
+            added_edges,
+        }
+    }
+
+    /**
+     * Greedily removes each reachable node, in index order, keeping the removal if the diagram
+     * still produces a database semantically equal to the matching entry of `outputs` for every
+     * entry of `inputs`. Removed nodes are disconnected, not deleted, so indices stay valid.
+     * Returns the removed nodes. Panics if `inputs` and `outputs` have different lengths.
+     */
+    pub fn minimize(&mut self, inputs: &[Database], outputs: &[Database]) -> Vec<NodeIndex> {
+        assert_eq!(
+            inputs.len(),
+            outputs.len(),
+            "Each input needs exactly one expected output"
+        );
+        let mut candidates: Vec<NodeIndex> = self.reachable_nodes().into_iter().collect();
+        candidates.sort_by_key(|node| node.0);
+        let mut removed = Vec::new();
+        for node in candidates {
+            let mut candidate = self.clone();
+            let mutation = Mutation::RemoveNode { node };
+            if apply_mutation(&mut candidate, mutation, &mut IndividualMutationState::new())
+                .is_none()
+            {
+                continue;
+            }
+            let reproduces_outputs = inputs
+                .iter()
+                .zip(outputs.iter())
+                .all(|(input, output)| candidate.evaluate(input).semantically_eq(output));
+            if reproduces_outputs {
+                *self = candidate;
+                removed.push(node);
+            }
+        }
+        removed
+    }
+
+    /**
+     * Splits the diagram into its register count, roots, and each node with its outgoing and
+     * incoming edges, in that order.
+     */
+    pub fn into_parts(self) -> (usize, Vec<NodeIndex>, Vec<(Node, Edges, Edges)>) {
+        let nodes = self.graph
+            .into_iter()
+            .map(|graph_node| (graph_node.node, graph_node.out_edges, graph_node.in_edges))
+            .collect();
+        (self.num_registers, self.roots, nodes)
+    }
+
+    /**
+     * Rebuilds a diagram from the output of `into_parts`. Returns `None` if any edge refers to a
+     * node which doesn't exist, appears twice, or is missing from the opposite node's edges.
+     */
+    pub fn from_parts(
+        num_registers: usize,
+        roots: Vec<NodeIndex>,
+        nodes: Vec<(Node, Edges, Edges)>,
+    ) -> Option<Self> {
+        let len = nodes.len();
+        let has_duplicates = |group: &[NodeIndex]| {
+            group
+                .iter()
+                .enumerate()
+                .any(|(i, n)| group[..i].contains(n))
+        };
+        if roots.iter().any(|n| n.0 >= len) || has_duplicates(&roots[..]) {
+            return None;
+        }
+        let graph: Vec<GraphNode> = nodes
+            .into_iter()
+            .map(|(node, out_edges, in_edges)| GraphNode {
+                node,
+                out_edges,
+                in_edges,
+            })
+            .collect();
+        let mut num_out_edges = 0;
+        let mut num_in_edges = 0;
+        for (index, graph_node) in graph.iter().enumerate() {
+            let node = NodeIndex(index);
+            for group in [
+                &graph_node.out_edges.on_match,
+                &graph_node.out_edges.on_refute,
+                &graph_node.in_edges.on_match,
+                &graph_node.in_edges.on_refute,
+            ].iter()
+            {
+                if group.iter().any(|n| n.0 >= len) || has_duplicates(&group[..]) {
+                    return None;
+                }
+            }
+            for &target in graph_node.out_edges.on_match.iter() {
+                if !graph[target.0].in_edges.on_match.contains(&node) {
+                    return None;
+                }
+            }
+            for &target in graph_node.out_edges.on_refute.iter() {
+                if !graph[target.0].in_edges.on_refute.contains(&node) {
+                    return None;
+                }
+            }
+            num_out_edges += graph_node.out_edges.on_match.len();
+            num_out_edges += graph_node.out_edges.on_refute.len();
+            num_in_edges += graph_node.in_edges.on_match.len();
+            num_in_edges += graph_node.in_edges.on_refute.len();
+        }
+        // Every outgoing edge has a matching incoming edge, so equal counts rule out extra
+        // incoming edges.
+        if num_out_edges != num_in_edges {
+            return None;
+        }
+        Some(GraphDiagram {
+            num_registers,
+            roots,
+            graph,
+        })
+    }
+
+    /**
+     * Collects every register read or written by any node, whether or not the node is reachable.
+     * Scratch registers (those at or above `num_registers`) are included.
+     */
+    pub fn register_usage(&self) -> RegisterUsage {
+        let mut usage = RegisterUsage::new();
+        for graph_node in self.graph.iter() {
+            usage.add_node(&graph_node.node);
+        }
+        usage
+    }
+
+    /**
+     * Renumbers the registers in use to a dense range, shrinking `num_registers` to the number of
+     * live registers, which is returned. Scratch registers stay above `num_registers`, since they
+     * don't take part in deduplicating register files. Registers beyond the scratch registers are
+     * left as they are, so they stay out of range.
+     */
+    pub fn compact_registers(&mut self) -> usize {
+        let mut used: Vec<usize> = self.register_usage().registers.into_iter().collect();
+        used.sort();
+        let num_registers = self.num_registers;
+        let (live, scratch): (Vec<usize>, Vec<usize>) =
+            used.into_iter().partition(|&r| r < num_registers);
+        let num_live = live.len();
+        let mut reg_map = HashMap::new();
+        for (register, new_register) in live.into_iter().zip(0..) {
+            reg_map.insert(register, new_register);
+        }
+        let scratch = scratch
+            .into_iter()
+            .take_while(|&r| r < num_registers + MAX_SCRATCH_REGISTERS);
+        for (register, new_register) in scratch.zip(num_live..) {
+            reg_map.insert(register, new_register);
+        }
+        self.map_registers(|register| *reg_map.get(&register).unwrap_or(&register));
+        self.num_registers = num_live;
+        num_live
+    }
+
+    /**
+     * Renames each register in `reg_map` to its value, leaving other registers unchanged. Returns
+     * false, leaving the diagram unchanged, if two registers in use would be renamed to the same
+     * register.
+     */
+    pub fn remap_registers(&mut self, reg_map: &HashMap<usize, usize>) -> bool {
+        let renamed = |register: usize| reg_map.get(&register).cloned().unwrap_or(register);
+        let mut targets = HashSet::new();
+        for register in self.register_usage().registers {
+            if !targets.insert(renamed(register)) {
+                return false;
+            }
+        }
+        self.map_registers(renamed);
+        true
+    }
+
+    /**
+     * Replaces each `OutputTerm::MatchColumn` with a register holding that column of the fact
+     * matched by the node's only source, setting the source's term to target a new register if it
+     * has no target. New registers are numbered above every register in use, including scratch
+     * registers, and `num_registers` grows to include them. Returns false, leaving the diagram
+     * unchanged, if any node with such a term is a root, has more than one source, is a refute
+     * target, or refers to a column its source doesn't have.
+     */
+    pub fn resolve_match_columns(&mut self) -> bool {
+        let mut next_register = match self.register_usage().max_register {
+            Some(max_register) if max_register >= self.num_registers => max_register + 1,
+            _ => self.num_registers,
+        };
+        let mut uses = Vec::new();
+        for (index, graph_node) in self.graph.iter().enumerate() {
+            let terms = match graph_node.node {
+                Node::Output { ref terms, .. } | Node::Aggregate { ref terms, .. } => terms,
+                Node::Match { .. } => continue,
+            };
+            for (term, output_term) in terms.iter().enumerate() {
+                if let OutputTerm::MatchColumn(column) = *output_term {
+                    let node = NodeIndex(index);
+                    let in_edges = &graph_node.in_edges;
+                    if self.roots.contains(&node) || in_edges.on_match.len() != 1
+                        || !in_edges.on_refute.is_empty()
+                    {
+                        return false;
+                    }
+                    let source = in_edges.on_match[0];
+                    let source_target = match self.graph[source.0].node {
+                        Node::Match { ref terms, .. } if column < terms.len() => &terms[column],
+                        _ => return false,
+                    };
+                    if source_target.if_unbound {
+                        return false;
+                    }
+                    uses.push((node, term, source, column));
+                }
+            }
+        }
+        for (node, term, source, column) in uses {
+            let register = match self.graph[source.0].node {
+                Node::Match { ref mut terms, .. } => match terms[column].target {
+                    Some(register) => register,
+                    None => {
+                        let register = next_register;
+                        next_register += 1;
+                        self.num_registers = next_register;
+                        terms[column].target = Some(register);
+                        register
+                    }
+                },
+                _ => unreachable!(),
+            };
+            match self.graph[node.0].node {
+                Node::Output { ref mut terms, .. } | Node::Aggregate { ref mut terms, .. } => {
+                    terms[term] = OutputTerm::Register(register);
+                }
+                Node::Match { .. } => unreachable!(),
+            }
+        }
+        true
+    }
+
+    fn map_registers<F: Fn(usize) -> usize>(&mut self, f: F) {
+        let map = |register: &mut usize| *register = f(*register);
+        for graph_node in self.graph.iter_mut() {
+            match graph_node.node {
+                Node::Match { ref mut terms, .. } => {
+                    for term in terms.iter_mut() {
+                        if let MatchTermConstraint::Register(ref mut register) = term.constraint {
+                            map(register);
+                        }
+                        if let Some(ref mut register) = term.target {
+                            map(register);
+                        }
+                    }
+                }
+                Node::Output { ref mut terms, .. } => {
+                    for term in terms.iter_mut() {
+                        if let OutputTerm::Register(ref mut register) = *term {
+                            map(register);
+                        }
+                    }
+                }
+                Node::Aggregate {
+                    ref mut terms,
+                    ref mut agg,
+                    ..
+                } => {
+                    for term in terms.iter_mut() {
+                        if let OutputTerm::Register(ref mut register) = *term {
+                            map(register);
+                        }
+                    }
+                    if let AggKind::Sum(ref mut register) = *agg {
+                        map(register);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &GraphDiagram, remap: &Remap) {
+        assert!(self.len() == remap.node_offset);
+        if other.num_registers > self.num_registers {
+            self.num_registers = other.num_registers;
+        }
+        for graph_node in other.graph.iter() {
+            let mut node = graph_node.node.clone();
+            match node {
+                Node::Match {
+                    ref mut predicate, ..
+                }
+                | Node::Output {
+                    ref mut predicate, ..
+                }
+                | Node::Aggregate {
+                    ref mut predicate, ..
+                } => {
+                    *predicate = remap.predicate(*predicate);
+                }
+            }
+            self.insert_node(node);
+        }
+        for (index, graph_node) in other.graph.iter().enumerate() {
+            let source = remap.node(NodeIndex(index));
+            for &target in graph_node.out_edges.on_match.iter() {
+                self.insert_edge(Edge::Match {
+                    source,
+                    target: remap.node(target),
+                });
+            }
+            for &target in graph_node.out_edges.on_refute.iter() {
+                self.insert_edge(Edge::Refute {
+                    source,
+                    target: remap.node(target),
+                });
+            }
+        }
+        for &root in other.roots.iter() {
+            self.insert_edge(Edge::Root(remap.node(root)));
+        }
+        for (&from, &to) in remap.redirects.iter() {
+            self.redirect_edges(from, to);
+        }
+    }
+
+    /**
+     * Moves every edge into `from` so that it goes into `to` instead.
+     */
+    fn redirect_edges(&mut self, from: NodeIndex, to: NodeIndex) {
+        let mut edges: Vec<(Edge, Edge)> = Vec::new();
+        if self.roots.contains(&from) {
+            edges.push((Edge::Root(from), Edge::Root(to)));
+        }
+        for &source in self.match_source_group(from).iter() {
+            edges.push((
+                Edge::Match {
+                    source,
+                    target: from,
+                },
+                Edge::Match { source, target: to },
+            ));
+        }
+        for &source in self.refute_source_group(from).iter() {
+            edges.push((
+                Edge::Refute {
+                    source,
+                    target: from,
+                },
+                Edge::Refute { source, target: to },
+            ));
+        }
+        for (old, new) in edges {
+            self.remove_edge(old);
+            // Already being connected to `to` is fine.
+            let _ = self.try_insert_edge(new);
+        }
+    }
+
     pub fn match_source_group(&self, node: NodeIndex) -> &Vec<NodeIndex> {
         &self.graph[node.0].in_edges.on_match
     }
@@ -91,6 +734,25 @@ impl GraphDiagram {
     }
 }
 
+/**
+ * Checks that `a` and `b` produce semantically equal databases for every entry of `inputs`.
+ * Otherwise, returns the index of the first input on which they differ, and the output of each.
+ */
+pub fn behaviorally_equal(
+    a: &GraphDiagram,
+    b: &GraphDiagram,
+    inputs: &[Database],
+) -> Result<(), (usize, Database, Database)> {
+    for (i, input) in inputs.iter().enumerate() {
+        let a_output = a.evaluate(input);
+        let b_output = b.evaluate(input);
+        if !a_output.semantically_eq(&b_output) {
+            return Err((i, a_output, b_output));
+        }
+    }
+    Ok(())
+}
+
 fn remove_from_group(group: &mut Vec<NodeIndex>, node: NodeIndex) {
     let position = group
         .iter()
@@ -106,6 +768,24 @@ fn insert_into_group(group: &mut Vec<NodeIndex>, node: NodeIndex) {
     group.push(node);
 }
 
+fn try_insert_into_group(group: &mut Vec<NodeIndex>, node: NodeIndex) -> bool {
+    if group.iter().any(|n| *n == node) {
+        false
+    } else {
+        group.push(node);
+        true
+    }
+}
+
+fn try_remove_from_group(group: &mut Vec<NodeIndex>, node: NodeIndex) -> bool {
+    if let Some(position) = group.iter().position(|n| *n == node) {
+        group.swap_remove(position);
+        true
+    } else {
+        false
+    }
+}
+
 impl MultiDiagram for GraphDiagram {
     fn insert_node(&mut self, node: Node) -> NodeIndex {
         let result = NodeIndex(self.graph.len());
@@ -217,14 +897,67 @@ impl MultiDiagram for GraphDiagram {
         assert!(!self.edge_exists(edge));
     }
 
+    fn try_insert_edge(&mut self, edge: Edge) -> Result<(), EdgeError> {
+        check_edge(self.len(), edge, true)?;
+        // The source and target groups are kept in sync, so only the forward group is scanned.
+        let inserted = match edge {
+            Edge::Root(node) => try_insert_into_group(&mut self.roots, node),
+            Edge::Match { source, target } => {
+                let inserted = try_insert_into_group(self.match_target_group_mut(source), target);
+                if inserted {
+                    self.match_source_group_mut(target).push(source);
+                }
+                inserted
+            }
+            Edge::Refute { source, target } => {
+                let inserted = try_insert_into_group(self.refute_target_group_mut(source), target);
+                if inserted {
+                    self.refute_source_group_mut(target).push(source);
+                }
+                inserted
+            }
+        };
+        if inserted {
+            Ok(())
+        } else {
+            Err(EdgeError::AlreadyPresent(edge))
+        }
+    }
+
+    fn try_remove_edge(&mut self, edge: Edge) -> Result<(), EdgeError> {
+        check_edge(self.len(), edge, true)?;
+        let removed = match edge {
+            Edge::Root(node) => try_remove_from_group(&mut self.roots, node),
+            Edge::Match { source, target } => {
+                let removed = try_remove_from_group(self.match_target_group_mut(source), target);
+                if removed {
+                    remove_from_group(self.match_source_group_mut(target), source);
+                }
+                removed
+            }
+            Edge::Refute { source, target } => {
+                let removed = try_remove_from_group(self.refute_target_group_mut(source), target);
+                if removed {
+                    remove_from_group(self.refute_source_group_mut(target), source);
+                }
+                removed
+            }
+        };
+        if removed {
+            Ok(())
+        } else {
+            Err(EdgeError::NotPresent(edge))
+        }
+    }
+
     fn len(&self) -> usize {
         self.graph.len()
     }
 }
 
 impl Diagram for GraphDiagram {
-    fn get_root(&self) -> NodeIndex {
-        self.roots[0]
+    fn get_root(&self) -> Option<NodeIndex> {
+        self.roots.get(0).cloned()
     }
 
     fn set_root(&mut self, root: NodeIndex) {
@@ -306,11 +1039,108 @@ mod tests {
     use std::collections::HashSet;
 
     use super::*;
+    use context::Context;
+    use database::{database_literal, symbols};
     use diagram::{MatchTerm, MatchTermConstraint, OutputTerm};
     use fact::Fact;
-    use predicate::Predicate;
+    use parse::{node_literal, parse_diagram};
     use value::Value;
 
+    #[test]
+    fn get_root_without_roots_is_none() {
+        let mut diagram = GraphDiagram::new(0);
+        assert_eq!(diagram.get_root(), None);
+        let node = diagram.insert_node(Node::Output {
+            predicate: Predicate(0),
+            terms: vec![],
+            skip_on_unbound: false,
+        });
+        assert_eq!(diagram.get_root(), None);
+        diagram.set_root(node);
+        assert_eq!(diagram.get_root(), Some(node));
+    }
+
+    #[test]
+    fn try_set_targets_reject_bad_edges() {
+        let mut diagram = GraphDiagram::new(0);
+        let source = diagram.insert_node(Node::Match {
+            predicate: Predicate(0),
+            terms: vec![],
+            require_bound: false,
+        });
+        let target = diagram.insert_node(Node::Output {
+            predicate: Predicate(1),
+            terms: vec![],
+            skip_on_unbound: false,
+        });
+        let missing = NodeIndex(2);
+        assert_eq!(
+            diagram.try_set_on_match(source, missing, true),
+            Err(EdgeError::OutOfBounds(Edge::Match {
+                source,
+                target: missing,
+            }))
+        );
+        assert_eq!(
+            diagram.try_set_on_refute(missing, target, true),
+            Err(EdgeError::OutOfBounds(Edge::Refute {
+                source: missing,
+                target,
+            }))
+        );
+        assert_eq!(
+            diagram.try_set_on_match(source, source, false),
+            Err(EdgeError::SelfLoop(Edge::Match {
+                source,
+                target: source,
+            }))
+        );
+        assert_eq!(diagram.get_on_match(source), None);
+        assert_eq!(diagram.try_set_on_match(source, target, false), Ok(()));
+        assert_eq!(diagram.try_set_on_refute(source, source, true), Ok(()));
+        assert_eq!(diagram.get_on_match(source), Some(target));
+        assert_eq!(diagram.get_on_refute(source), Some(source));
+    }
+
+    #[test]
+    fn try_edge_operations_report_presence() {
+        let mut diagram = GraphDiagram::new(0);
+        let source = diagram.insert_node(Node::Match {
+            predicate: Predicate(0),
+            terms: vec![],
+            require_bound: false,
+        });
+        let target = diagram.insert_node(Node::Output {
+            predicate: Predicate(1),
+            terms: vec![],
+            skip_on_unbound: false,
+        });
+        let edge = Edge::Refute { source, target };
+        assert_eq!(diagram.try_remove_edge(edge), Err(EdgeError::NotPresent(edge)));
+        assert_eq!(diagram.try_insert_edge(edge), Ok(()));
+        assert_eq!(
+            diagram.try_insert_edge(edge),
+            Err(EdgeError::AlreadyPresent(edge))
+        );
+        assert!(diagram.edge_exists(edge));
+        assert_eq!(diagram.get_group(EdgeGroup::RefuteSources(target)), &[source]);
+        assert_eq!(diagram.try_remove_edge(edge), Ok(()));
+        assert!(!diagram.edge_exists(edge));
+        assert_eq!(diagram.get_group(EdgeGroup::RefuteSources(target)).len(), 0);
+        assert_eq!(diagram.try_insert_edge(Edge::Root(source)), Ok(()));
+        assert_eq!(diagram.try_remove_edge(Edge::Root(source)), Ok(()));
+        assert_eq!(diagram.get_root(), None);
+        let missing = Edge::Match {
+            source: target,
+            target: NodeIndex(2),
+        };
+        assert_eq!(diagram.try_insert_edge(missing), Err(EdgeError::OutOfBounds(missing)));
+        assert_eq!(diagram.try_remove_edge(missing), Err(EdgeError::OutOfBounds(missing)));
+        let missing = Edge::Root(NodeIndex(2));
+        assert_eq!(diagram.try_insert_edge(missing), Err(EdgeError::OutOfBounds(missing)));
+        assert_eq!(diagram.try_remove_edge(missing), Err(EdgeError::OutOfBounds(missing)));
+    }
+
     #[test]
     fn can_evaluate_constant_diagram() {
         let mut diagram = GraphDiagram::new(0);
@@ -320,6 +1150,7 @@ mod tests {
                 OutputTerm::Constant(Value::Symbol(1)),
                 OutputTerm::Constant(Value::Symbol(2)),
             ],
+            skip_on_unbound: false,
         };
         let root = diagram.insert_node(output_node);
         diagram.set_root(root);
@@ -337,6 +1168,102 @@ mod tests {
         assert_eq!(result_facts.next(), None);
     }
 
+    #[test]
+    fn can_stream_filtering_diagram() {
+        let mut diagram = GraphDiagram::new(2);
+        let match_ones_node = Node::Match {
+            predicate: Predicate(0),
+            terms: vec![
+                MatchTerm {
+                    constraint: MatchTermConstraint::Constant(Value::Symbol(1)),
+                    target: Some(0),
+                    if_unbound: false,
+                },
+                MatchTerm {
+                    constraint: MatchTermConstraint::Free,
+                    target: Some(1),
+                    if_unbound: false,
+                },
+            ],
+            require_bound: false,
+        };
+        let output_node = Node::Output {
+            predicate: Predicate(1),
+            terms: vec![OutputTerm::Register(0), OutputTerm::Register(1)],
+            skip_on_unbound: false,
+        };
+        let root = diagram.insert_node(match_ones_node);
+        diagram.set_root(root);
+        let output = diagram.insert_node(output_node);
+        diagram.set_on_match(root, output);
+        let mut database = Database::new();
+        for values in [
+            [Value::Symbol(1), Value::Symbol(2)],
+            [Value::Symbol(2), Value::Symbol(3)],
+            [Value::Symbol(1), Value::Symbol(3)],
+        ].iter()
+        {
+            database.insert_fact(Fact {
+                predicate: Predicate(0),
+                values,
+            });
+        }
+        let result_facts: HashSet<_> = diagram.evaluate_iter(&database).collect();
+        assert_eq!(
+            result_facts,
+            [
+                (
+                    OwnedFact {
+                        predicate: Predicate(1),
+                        values: vec![Value::Symbol(1), Value::Symbol(2)],
+                    },
+                    Weight(1),
+                ),
+                (
+                    OwnedFact {
+                        predicate: Predicate(1),
+                        values: vec![Value::Symbol(1), Value::Symbol(3)],
+                    },
+                    Weight(1),
+                ),
+            ].iter()
+                .cloned()
+                .collect()
+        );
+    }
+
+    #[test]
+    fn evaluate_iter_merges_facts_within_each_output_node() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0, _ -> %1) {
+          output @1(%0);
+          output @1(%1)
+        }
+        "#,
+            2,
+        ).unwrap();
+        let input = symbols(Predicate(0), &[&[1, 2], &[1, 3]]);
+        let mut facts: Vec<_> = diagram
+            .evaluate_iter(&input)
+            .map(|(fact, weight)| (fact.values, weight))
+            .collect();
+        facts.sort_by_key(|&(ref values, _)| format!("{:?}", values));
+        assert_eq!(
+            facts,
+            vec![
+                (vec![Value::Symbol(1)], Weight(2)),
+                (vec![Value::Symbol(2)], Weight(1)),
+                (vec![Value::Symbol(3)], Weight(1)),
+            ]
+        );
+        let evaluated = diagram.evaluate(&input);
+        assert!(facts.iter().all(|&(ref values, _)| evaluated.contains(Fact {
+            predicate: Predicate(1),
+            values,
+        })));
+    }
+
     #[test]
     fn can_evaluate_copying_diagram() {
         let mut diagram = GraphDiagram::new(2);
@@ -346,16 +1273,20 @@ mod tests {
                 MatchTerm {
                     constraint: MatchTermConstraint::Free,
                     target: Some(0),
+                    if_unbound: false,
                 },
                 MatchTerm {
                     constraint: MatchTermConstraint::Free,
                     target: Some(1),
+                    if_unbound: false,
                 },
             ],
+            require_bound: false,
         };
         let output_node = Node::Output {
             predicate: Predicate(1),
             terms: vec![OutputTerm::Register(0), OutputTerm::Register(1)],
+            skip_on_unbound: false,
         };
         let root = diagram.insert_node(match_anything_node);
         diagram.set_root(root);
@@ -390,16 +1321,20 @@ mod tests {
                 MatchTerm {
                     constraint: MatchTermConstraint::Constant(Value::Symbol(1)),
                     target: Some(0),
+                    if_unbound: false,
                 },
                 MatchTerm {
                     constraint: MatchTermConstraint::Free,
                     target: Some(1),
+                    if_unbound: false,
                 },
             ],
+            require_bound: false,
         };
         let output_node = Node::Output {
             predicate: Predicate(1),
             terms: vec![OutputTerm::Register(0), OutputTerm::Register(1)],
+            skip_on_unbound: false,
         };
         let root = diagram.insert_node(match_ones_node);
         diagram.set_root(root);
@@ -443,8 +1378,7 @@ mod tests {
         );
     }
 
-    #[test]
-    fn can_evaluate_nested_filtering_diagram() {
+    fn nested_filtering_diagram() -> GraphDiagram {
         let mut diagram = GraphDiagram::new(2);
         let match_ones_node = Node::Match {
             predicate: Predicate(0),
@@ -452,12 +1386,15 @@ mod tests {
                 MatchTerm {
                     constraint: MatchTermConstraint::Constant(Value::Symbol(1)),
                     target: Some(0),
+                    if_unbound: false,
                 },
                 MatchTerm {
                     constraint: MatchTermConstraint::Free,
                     target: Some(1),
+                    if_unbound: false,
                 },
             ],
+            require_bound: false,
         };
         let match_anything_node = Node::Match {
             predicate: Predicate(0),
@@ -465,16 +1402,20 @@ mod tests {
                 MatchTerm {
                     constraint: MatchTermConstraint::Free,
                     target: None,
+                    if_unbound: false,
                 },
                 MatchTerm {
                     constraint: MatchTermConstraint::Free,
                     target: Some(1),
+                    if_unbound: false,
                 },
             ],
+            require_bound: false,
         };
         let output_node = Node::Output {
             predicate: Predicate(1),
             terms: vec![OutputTerm::Register(0), OutputTerm::Register(1)],
+            skip_on_unbound: false,
         };
         let root = diagram.insert_node(match_ones_node);
         diagram.set_root(root);
@@ -482,6 +1423,12 @@ mod tests {
         let output = diagram.insert_node(output_node);
         diagram.set_on_match(root, anything);
         diagram.set_on_match(anything, output);
+        diagram
+    }
+
+    #[test]
+    fn can_evaluate_nested_filtering_diagram() {
+        let diagram = nested_filtering_diagram();
         let mut database = Database::new();
         let input_facts = [
             Fact {
@@ -522,4 +1469,381 @@ mod tests {
                 .collect()
         );
     }
+
+    #[test]
+    fn register_usage_of_nested_filtering_diagram() {
+        let usage = nested_filtering_diagram().register_usage();
+        assert_eq!(usage.registers, [0, 1].iter().cloned().collect());
+        assert_eq!(usage.max_register, Some(1));
+        assert_eq!(
+            GraphDiagram::new(3).register_usage(),
+            RegisterUsage {
+                registers: HashSet::new(),
+                max_register: None,
+            }
+        );
+    }
+
+    #[test]
+    fn compacting_registers_preserves_output() {
+        let (mut diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %5, _ -> %2) {
+          @1(%2, _ -> %7) {
+            output @2(%5, %7)
+          }
+        }
+        "#,
+            8,
+        ).unwrap();
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1), Value::Symbol(2)]),
+            (Predicate(0), vec![Value::Symbol(3), Value::Symbol(4)]),
+            (Predicate(1), vec![Value::Symbol(2), Value::Symbol(5)]),
+            (Predicate(1), vec![Value::Symbol(2), Value::Symbol(6)]),
+        ]);
+        let before = diagram.evaluate(&input);
+        assert_eq!(diagram.compact_registers(), 3);
+        assert_eq!(diagram.get_num_registers(), 3);
+        assert_eq!(diagram.register_usage().max_register, Some(2));
+        assert!(diagram.evaluate(&input).semantically_eq(&before));
+    }
+
+    #[test]
+    fn compacting_registers_keeps_registers_out_of_range() {
+        let (mut diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %1) {
+          output @1(%1)
+        }
+        root: @0(_ -> %70) {
+          output @2(:1)
+        }
+        "#,
+            2,
+        ).unwrap();
+        let input = symbols(Predicate(0), &[&[1], &[2]]);
+        let before = diagram.evaluate(&input);
+        assert!(before.semantically_eq(&symbols(Predicate(1), &[&[1], &[2]])));
+        assert_eq!(diagram.compact_registers(), 1);
+        assert!(diagram.register_usage().registers.contains(&70));
+        assert!(diagram.evaluate(&input).semantically_eq(&before));
+    }
+
+    #[test]
+    fn parts_round_trip() {
+        let diagram = nested_filtering_diagram();
+        let (num_registers, roots, nodes) = diagram.clone().into_parts();
+        assert_eq!(num_registers, 2);
+        assert_eq!(nodes.len(), 3);
+        assert_eq!(
+            GraphDiagram::from_parts(num_registers, roots.clone(), nodes.clone()),
+            Some(diagram)
+        );
+        let mut missing_in_edge = nodes.clone();
+        missing_in_edge[1].2.on_match.clear();
+        assert_eq!(
+            GraphDiagram::from_parts(num_registers, roots.clone(), missing_in_edge),
+            None
+        );
+        let mut extra_in_edge = nodes.clone();
+        extra_in_edge[0].2.on_refute.push(NodeIndex(2));
+        assert_eq!(
+            GraphDiagram::from_parts(num_registers, roots.clone(), extra_in_edge),
+            None
+        );
+        assert_eq!(
+            GraphDiagram::from_parts(num_registers, vec![NodeIndex(3)], nodes),
+            None
+        );
+    }
+
+    #[test]
+    fn minimize_removes_redundant_nodes() {
+        let (mut diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0, _ -> %1) {
+          @0(_, _) {
+            output @1(%0, %1)
+          }
+        }
+        "#,
+            2,
+        ).unwrap();
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1), Value::Symbol(2)]),
+            (Predicate(0), vec![Value::Symbol(3), Value::Symbol(4)]),
+        ]);
+        let output = diagram.evaluate(&input);
+        let removed = diagram.minimize(&[input.clone()], &[output.clone()]);
+        assert_eq!(removed, vec![NodeIndex(1)]);
+        assert_eq!(diagram.reachable_nodes().len(), 2);
+        assert!(diagram.evaluate(&input).semantically_eq(&output));
+    }
+
+    #[test]
+    #[should_panic]
+    fn minimize_rejects_missing_outputs() {
+        let (mut diagram, _) = parse_diagram("root: @0(_ -> %0) { output @1(%0) }", 1).unwrap();
+        let input = symbols(Predicate(0), &[&[1]]);
+        diagram.minimize(&[input.clone(), input], &[Database::new()]);
+    }
+
+    #[test]
+    fn behavioral_equality_reports_first_difference() {
+        let (nested, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0, :1) {
+          output @1(%0)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let (filtered, _) = parse_diagram(
+            r#"
+        root: @0(_, :1) {
+          @0(_ -> %0, _) {
+            @0(%0, :1) {
+              output @1(%0)
+            }
+          }
+        }
+        "#,
+            1,
+        ).unwrap();
+        let (unfiltered, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0, _) {
+          output @1(%0)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let inputs = vec![
+            symbols(Predicate(0), &[&[2, 1]]),
+            symbols(Predicate(0), &[&[2, 1], &[3, 4]]),
+        ];
+        assert_eq!(behaviorally_equal(&nested, &filtered, &inputs), Ok(()));
+        let (i, a, b) = behaviorally_equal(&nested, &unfiltered, &inputs).unwrap_err();
+        assert_eq!(i, 1);
+        assert!(a.semantically_eq(&symbols(Predicate(1), &[&[2]])));
+        assert!(b.semantically_eq(&symbols(Predicate(1), &[&[2], &[3]])));
+    }
+
+    #[test]
+    fn match_columns_resolve_to_registers() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_, :1, _) {
+          output @1($2, $0)
+        }
+        "#,
+            0,
+        ).unwrap();
+        assert_eq!(diagram.get_num_registers(), 2);
+        let input = symbols(Predicate(0), &[&[2, 1, 3], &[4, 5, 6]]);
+        assert!(
+            diagram
+                .evaluate(&input)
+                .semantically_eq(&symbols(Predicate(1), &[&[3, 2]]))
+        );
+
+        assert!(parse_diagram("root: output @1($0)", 0).is_err());
+        assert!(parse_diagram("root: @0(_) { output @1($1) }", 0).is_err());
+        assert!(parse_diagram("root: @0(_) { a } { a } a: output @1($0)", 0).is_err());
+    }
+
+    #[test]
+    fn match_columns_resolve_above_scratch_registers() {
+        let (diagram, _) = parse_diagram("root: @0(_ -> %1, _) { output @1(%1, $1) }", 1).unwrap();
+        assert_eq!(diagram.get_num_registers(), 3);
+        let input = symbols(Predicate(0), &[&[1, 2], &[3, 4]]);
+        assert!(
+            diagram
+                .evaluate(&input)
+                .semantically_eq(&symbols(Predicate(1), &[&[1, 2], &[3, 4]]))
+        );
+    }
+
+    #[test]
+    fn can_evaluate_single_predicate() {
+        let (diagram, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0, _ -> %1) {
+          output @1(%1, %0)
+        }
+        root: @0(_ -> %0, _) {
+          output @2(%0)
+        }
+        "#,
+            2,
+        ).unwrap();
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1), Value::Symbol(2)]),
+            (Predicate(0), vec![Value::Symbol(3), Value::Symbol(4)]),
+        ]);
+        let flipped = diagram.evaluate_predicate(&input, Predicate(1));
+        assert_eq!(flipped.len(), 2);
+        assert!(flipped.contains(&OwnedFact {
+            predicate: Predicate(1),
+            values: vec![Value::Symbol(2), Value::Symbol(1)],
+        }));
+        assert!(flipped.contains(&OwnedFact {
+            predicate: Predicate(1),
+            values: vec![Value::Symbol(4), Value::Symbol(3)],
+        }));
+        assert!(diagram.evaluate_predicate(&input, Predicate(3)).is_empty());
+        let eval = Evaluation::run_multi(&diagram, &input, 2);
+        assert_eq!(eval.facts_for_predicate(Predicate(2)).count(), 2);
+        assert_eq!(eval.facts_for_predicate(Predicate(0)).count(), 0);
+    }
+
+    #[test]
+    fn remapping_registers_preserves_evaluation() {
+        let (mut diagram, context) = parse_diagram(
+            r#"
+        root: @0(_ -> %0, _ -> %1) {
+          flip: output @1(%1, %0)
+        }
+        "#,
+            2,
+        ).unwrap();
+        let root = diagram.get_group(EdgeGroup::Roots)[0];
+        let flip = context.node_name_to_info["flip"].index;
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1), Value::Symbol(2)]),
+            (Predicate(0), vec![Value::Symbol(3), Value::Symbol(4)]),
+        ]);
+        let output = diagram.evaluate(&input);
+        let original = diagram.clone();
+
+        let identity = [(0, 0), (1, 1)].iter().cloned().collect();
+        assert!(diagram.remap_registers(&identity));
+        assert_eq!(diagram, original);
+
+        let merge = [(0, 1)].iter().cloned().collect();
+        assert!(!diagram.remap_registers(&merge));
+        assert_eq!(diagram, original);
+
+        let swap = [(0, 1), (1, 0)].iter().cloned().collect();
+        assert!(diagram.remap_registers(&swap));
+        assert_eq!(*diagram.get_node(root), node_literal("@0(_ -> %1, _ -> %0)"));
+        assert_eq!(*diagram.get_node(flip), node_literal("output @1(%0, %1)"));
+        assert!(diagram.evaluate(&input).semantically_eq(&output));
+    }
+
+    #[test]
+    fn diff_reports_node_and_edge_changes() {
+        let (old, old_context) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) {
+          a: output @1(%0)
+        } {
+          b: output @2(:1)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let (new, new_context) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) {
+          a: output @1(%0)
+        } {
+          c: @3(%0) {
+            d: output @4(%0)
+          }
+        }
+        "#,
+            1,
+        ).unwrap();
+        assert!(old.diff(&old).is_empty());
+        let old_root = old.get_group(EdgeGroup::Roots)[0];
+        let new_root = new.get_group(EdgeGroup::Roots)[0];
+        let node = |context: &Context, name: &str| context.node_name_to_info[name].index;
+        let diff = old.diff(&new);
+        assert_eq!(
+            diff.removed_nodes,
+            vec![(node(&old_context, "b"), old.get_node(node(&old_context, "b")).clone())]
+        );
+        let mut added: Vec<NodeIndex> = diff.added_nodes.iter().map(|&(n, _)| n).collect();
+        added.sort_by_key(|n| n.0);
+        let mut expected_added = vec![node(&new_context, "c"), node(&new_context, "d")];
+        expected_added.sort_by_key(|n| n.0);
+        assert_eq!(added, expected_added);
+        assert_eq!(diff.changed_nodes, vec![]);
+        assert_eq!(
+            diff.removed_edges,
+            vec![Edge::Refute {
+                source: old_root,
+                target: node(&old_context, "b"),
+            }]
+        );
+        assert_eq!(diff.added_edges.len(), 2);
+        assert!(diff.added_edges.contains(&Edge::Refute {
+            source: new_root,
+            target: node(&new_context, "c"),
+        }));
+        let text = format!("{}", diff);
+        let b = node(&old_context, "b").0;
+        assert!(text.contains(&format!("- {}: output @2(:1)\n", b)));
+        let c = node(&new_context, "c").0;
+        assert!(text.contains(&format!("+ {}: @3(%0)\n", c)));
+        assert!(text.contains(&format!("+ {} -refute-> {}\n", new_root.0, c)));
+
+        let (changed, _) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) {
+          a: output @1(%0)
+        } {
+          b: output @2(:2)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let diff = old.diff(&changed);
+        assert_eq!(diff.changed_nodes.len(), 1);
+        assert_eq!((diff.changed_nodes[0].0).0, node(&old_context, "b"));
+        assert!(diff.removed_edges.is_empty());
+        assert!(diff.added_edges.is_empty());
+    }
+
+    #[test]
+    fn subgraph_evaluates_like_its_root() {
+        let (diagram, context) = parse_diagram(
+            r#"
+        root: @0(_ -> %0, _ -> %1) {
+          other: output @1(%1)
+        }
+        root: sub: @0(_ -> %0, :2) {
+          @0(%0, _ -> %1) {
+            output @2(%0, %1)
+          } {
+            other
+          }
+        }
+        "#,
+            2,
+        ).unwrap();
+        let sub = context.node_name_to_info["sub"].index;
+        let other = context.node_name_to_info["other"].index;
+        let (subgraph, remap) = diagram.subgraph_from(sub);
+        assert_eq!(subgraph.len(), 4);
+        assert_eq!(subgraph.get_group(EdgeGroup::Roots), &[remap[&sub]]);
+        assert!(remap.contains_key(&other));
+
+        let mut restricted = diagram.clone();
+        for root in diagram.get_group(EdgeGroup::Roots) {
+            if *root != sub {
+                restricted.remove_edge(Edge::Root(*root));
+            }
+        }
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1), Value::Symbol(2)]),
+            (Predicate(0), vec![Value::Symbol(2), Value::Symbol(3)]),
+            (Predicate(0), vec![Value::Symbol(3), Value::Symbol(2)]),
+        ]);
+        let expected = restricted.evaluate(&input);
+        assert!(expected.len() > 0);
+        assert!(subgraph.evaluate(&input).semantically_eq(&expected));
+    }
 }
@@ -19,6 +19,14 @@ impl Index {
         }
     }
 
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
     pub fn add_row(&mut self, row: &[Value], row_index: usize) -> bool {
         if row[self.column] == self.value {
             assert!(
@@ -34,6 +42,23 @@ impl Index {
         }
     }
 
+    /**
+     * Forgets `row_index`, and renumbers `last_row`, which must be the table's last row, to
+     * `row_index`, as `Table::remove_row` does.
+     */
+    pub fn remove_row(&mut self, row_index: usize, last_row: usize) {
+        if let Ok(position) = self.row_indices.binary_search(&row_index) {
+            self.row_indices.remove(position);
+        }
+        if row_index != last_row && self.row_indices.last() == Some(&last_row) {
+            self.row_indices.pop();
+            let position = self.row_indices
+                .binary_search(&row_index)
+                .unwrap_err();
+            self.row_indices.insert(position, row_index);
+        }
+    }
+
     pub fn iter(&self) -> IndexIter {
         IndexIter {
             inner: self.row_indices.iter().peekable(),
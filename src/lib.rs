@@ -2,28 +2,35 @@
 #![allow(unused_imports)]
 extern crate evolution_strategies;
 extern crate rand;
+#[cfg(feature = "serialize")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(all(test, feature = "serialize"))]
+extern crate serde_json;
 extern crate unicode_xid;
 
-mod context;
-mod database;
-mod diagram;
-mod evaluation;
-mod fact;
+pub mod context;
+pub mod database;
+pub mod diagram;
+pub mod evaluation;
+pub mod fact;
 mod fixgraph;
-mod frame;
+pub mod frame;
 mod gen_mutation;
-mod graph_diagram;
+pub mod graph_diagram;
 mod index;
 mod mutate;
-mod mutation;
-mod node_index;
-mod parse;
-mod predicate;
-mod rand_utils;
-mod registers;
-mod simple_query;
-mod step_problem;
+pub mod mutation;
+pub mod name_table;
+pub mod node_index;
+pub mod parse;
+pub mod predicate;
+pub mod rand_utils;
+pub mod registers;
+pub mod session;
+pub mod simple_query;
+pub mod step_problem;
 mod table;
 mod tiny_map;
-mod value;
-mod weight;
+pub mod value;
+pub mod weight;
@@ -4,6 +4,7 @@ use gen_mutation::IndividualMutationState;
 use mutation::{Mutation, Term};
 use node_index::NodeIndex;
 use std::iter;
+use value::Value;
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct MutationResult {
@@ -63,6 +64,9 @@ pub fn apply_mutation<D: Diagram>(
             term: Term(node, term),
             register,
         } => {
+            if register.map(|r| r >= diagram.get_num_registers()).unwrap_or(false) {
+                return None;
+            }
             if let &mut Node::Match { ref mut terms, .. } = diagram.get_node_mut(node) {
                 if term < terms.len() {
                     terms[term].target = register;
@@ -88,6 +92,15 @@ pub fn apply_mutation<D: Diagram>(
             let refute_sources = without_node(diagram.get_group(EdgeGroup::RefuteSources(node)));
             let refute_targets = without_node(diagram.get_group(EdgeGroup::RefuteTargets(node)));
 
+            if was_root
+                && diagram.get_group(EdgeGroup::Roots).len() == 1
+                && match_targets.len() == 0
+                && refute_targets.len() == 0
+            {
+                // Removing the last root without re-rooting its targets would leave no roots.
+                return None;
+            }
+
             for target in match_targets
                 .iter()
                 .cloned()
@@ -206,6 +219,10 @@ pub fn apply_mutation<D: Diagram>(
                 | Node::Match {
                     predicate: ref mut p,
                     ..
+                }
+                | Node::Aggregate {
+                    predicate: ref mut p,
+                    ..
                 } => {
                     *p = predicate;
                     Some(MutationResult {
@@ -220,7 +237,11 @@ pub fn apply_mutation<D: Diagram>(
             predicate,
             terms,
         } => {
-            let node = Node::Output { predicate, terms };
+            let node = Node::Output {
+                predicate,
+                terms,
+                skip_on_unbound: false,
+            };
             let node_index = state.insert_node(diagram, node);
             let edge = group.edge_to(node_index);
             diagram.insert_edge(edge);
@@ -234,7 +255,11 @@ pub fn apply_mutation<D: Diagram>(
             predicate,
             terms,
         } => {
-            let node = Node::Match { predicate, terms };
+            let node = Node::Match {
+                predicate,
+                terms,
+                require_bound: false,
+            };
             let node_index = state.insert_node(diagram, node);
             let edge_group_in = edge.forward_group();
             diagram.insert_edge(edge_group_in.edge_to(node_index));
@@ -251,12 +276,78 @@ pub fn apply_mutation<D: Diagram>(
                 node_to_restart: edge.source(),
             })
         }
+        Mutation::SwapTargets { node } => {
+            if !diagram.get_node(node).is_match() {
+                return None;
+            }
+            let match_targets = diagram.get_group(EdgeGroup::MatchTargets(node)).to_owned();
+            let refute_targets = diagram.get_group(EdgeGroup::RefuteTargets(node)).to_owned();
+            for edge in diagram.out_edges(node) {
+                diagram.remove_edge(edge);
+            }
+            for target in refute_targets {
+                diagram.insert_edge(Edge::Match {
+                    source: node,
+                    target,
+                });
+            }
+            for target in match_targets {
+                diagram.insert_edge(Edge::Refute {
+                    source: node,
+                    target,
+                });
+            }
+            changed_node(node)
+        }
+        Mutation::SetRoot { node } => {
+            diagram.set_root(node);
+            Some(MutationResult {
+                phenotype_could_have_changed: true,
+                node_to_restart: None,
+            })
+        }
+        Mutation::SetNumTerms { node, num_terms } => match *diagram.get_node_mut(node) {
+            Node::Match { ref mut terms, .. } => {
+                terms.resize(
+                    num_terms,
+                    MatchTerm {
+                        constraint: MatchTermConstraint::Free,
+                        target: None,
+                        if_unbound: false,
+                    },
+                );
+                changed_node(node)
+            }
+            Node::Output { ref mut terms, .. } => {
+                terms.resize(num_terms, OutputTerm::Constant(Value::Symbol(0)));
+                changed_node(node)
+            }
+            Node::Aggregate { .. } => None,
+        },
+        Mutation::ReplaceSubtree { at, with } => {
+            if with == at.target() || with.0 >= diagram.len() || !diagram.edge_exists(at) {
+                return None;
+            }
+            let grafted = at.forward_group().edge_to(with);
+            if diagram.edge_exists(grafted) {
+                return None;
+            }
+            diagram.remove_edge(at);
+            diagram.insert_edge(grafted);
+            Some(MutationResult {
+                phenotype_could_have_changed: true,
+                node_to_restart: at.source(),
+            })
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
+
     use super::*;
+    use database::database_literal;
     use diagram::{EdgeGroup, MatchTerm, MatchTermConstraint, OutputTerm};
     use graph_diagram::GraphDiagram;
     use parse::{node_literal, parse_diagram};
@@ -277,7 +368,7 @@ mod tests {
         "#,
             2,
         );
-        let root = diagram.get_root();
+        let root = diagram.get_root().unwrap();
         apply_mutation(
             &mut diagram,
             Mutation::SetConstraintRegister {
@@ -302,7 +393,7 @@ mod tests {
         "#,
             2,
         );
-        let root = diagram.get_root();
+        let root = diagram.get_root().unwrap();
         apply_mutation(
             &mut diagram,
             Mutation::SetConstraintConstant {
@@ -327,7 +418,7 @@ mod tests {
         "#,
             2,
         );
-        let root = diagram.get_root();
+        let root = diagram.get_root().unwrap();
         apply_mutation(
             &mut diagram,
             Mutation::SetConstraintFree {
@@ -351,7 +442,7 @@ mod tests {
         "#,
             2,
         );
-        let root = diagram.get_root();
+        let root = diagram.get_root().unwrap();
         apply_mutation(
             &mut diagram,
             Mutation::SetTarget {
@@ -363,6 +454,34 @@ mod tests {
         assert_eq!(*diagram.get_node(root), node_literal("@0(_, _ -> %1)"));
     }
 
+    #[test]
+    fn set_target_out_of_range() {
+        let mut diagram = diagram_literal(
+            r#"
+        root: @0(_ -> %0, _ -> %1) {
+          output @1(%0, %1)
+        }
+        "#,
+            2,
+        );
+        let root = diagram.get_root().unwrap();
+        assert_eq!(
+            apply_mutation(
+                &mut diagram,
+                Mutation::SetTarget {
+                    term: Term(root, 0),
+                    register: Some(2),
+                },
+                &mut IndividualMutationState::new(),
+            ),
+            None
+        );
+        assert_eq!(
+            *diagram.get_node(root),
+            node_literal("@0(_ -> %0, _ -> %1)")
+        );
+    }
+
     #[test]
     fn remove_node_not_passthrough() {
         let (mut diagram, context) = parse_diagram(
@@ -376,7 +495,7 @@ mod tests {
             2,
         ).unwrap();
         println!("original diagram = {:#?}", diagram);
-        let root = diagram.get_root();
+        let root = diagram.get_root().unwrap();
         let a = context.node_name_to_info.get("a").unwrap().index;
         let mutation_result = apply_mutation(
             &mut diagram,
@@ -407,7 +526,7 @@ mod tests {
         "#,
             2,
         );
-        let root = diagram.get_root();
+        let root = diagram.get_root().unwrap();
         assert_eq!(
             apply_mutation(
                 &mut diagram,
@@ -421,6 +540,26 @@ mod tests {
         );
     }
 
+    #[test]
+    fn remove_node_sole_root() {
+        let mut diagram = diagram_literal(
+            r#"
+        root: output @1(:2, :2)
+        "#,
+            2,
+        );
+        let root = diagram.get_root().unwrap();
+        assert_eq!(
+            apply_mutation(
+                &mut diagram,
+                Mutation::RemoveNode { node: root },
+                &mut IndividualMutationState::new(),
+            ),
+            None
+        );
+        assert_eq!(diagram.get_root(), Some(root));
+    }
+
     #[test]
     fn set_edge_root() {
         let (mut diagram, context) = parse_diagram(
@@ -556,7 +695,7 @@ mod tests {
         "#,
             2,
         );
-        let root = diagram.get_root();
+        let root = diagram.get_root().unwrap();
         assert_eq!(
             apply_mutation(
                 &mut diagram,
@@ -582,7 +721,7 @@ mod tests {
         "#,
             2,
         );
-        let root = diagram.get_root();
+        let root = diagram.get_root().unwrap();
         assert_eq!(
             apply_mutation(
                 &mut diagram,
@@ -608,7 +747,7 @@ mod tests {
         "#,
             2,
         );
-        let root = diagram.get_root();
+        let root = diagram.get_root().unwrap();
         assert_eq!(
             apply_mutation(
                 &mut diagram,
@@ -636,7 +775,7 @@ mod tests {
         "#,
             2,
         );
-        let root = diagram.get_root();
+        let root = diagram.get_root().unwrap();
         assert_eq!(
             apply_mutation(
                 &mut diagram,
@@ -681,7 +820,198 @@ mod tests {
             &Node::Output {
                 predicate: Predicate(1),
                 terms: vec![OutputTerm::Constant(Value::Symbol(2))],
+                skip_on_unbound: false,
             }
         );
     }
+
+    #[test]
+    fn swap_targets() {
+        let (mut diagram, context) = parse_diagram(
+            r#"
+        root: @0(:1 -> %0) {
+          a: output @1(%0)
+        } {
+          b: output @2(%0)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let root = diagram.get_root().unwrap();
+        let a = context.node_name_to_info.get("a").unwrap().index;
+        let b = context.node_name_to_info.get("b").unwrap().index;
+        assert_eq!(
+            apply_mutation(
+                &mut diagram,
+                Mutation::SwapTargets { node: root },
+                &mut IndividualMutationState::new(),
+            ),
+            Some(MutationResult {
+                phenotype_could_have_changed: true,
+                node_to_restart: Some(root),
+            })
+        );
+        assert_eq!(diagram.get_on_match(root), Some(b));
+        assert_eq!(diagram.get_on_refute(root), Some(a));
+        let input = database_literal(vec![
+            (Predicate(0), vec![Value::Symbol(1)]),
+            (Predicate(0), vec![Value::Symbol(2)]),
+        ]);
+        let output: HashSet<_> = diagram
+            .evaluate(&input)
+            .all_facts()
+            .map(|f| (f.predicate, f.values.to_owned()))
+            .collect();
+        assert_eq!(
+            output,
+            [
+                (Predicate(1), vec![Value::Symbol(2)]),
+                (Predicate(2), vec![Value::Symbol(1)]),
+            ].iter()
+                .cloned()
+                .collect()
+        );
+    }
+
+    #[test]
+    fn set_root() {
+        let (mut diagram, context) = parse_diagram(
+            r#"
+        root: @0(:1 -> %0) {
+          a: output @1(:3)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let a = context.node_name_to_info.get("a").unwrap().index;
+        let input = database_literal(vec![(Predicate(0), vec![Value::Symbol(2)])]);
+        assert_eq!(diagram.evaluate(&input).len(), 0);
+        assert_eq!(
+            apply_mutation(
+                &mut diagram,
+                Mutation::SetRoot { node: a },
+                &mut IndividualMutationState::new(),
+            ),
+            Some(MutationResult {
+                phenotype_could_have_changed: true,
+                node_to_restart: None,
+            })
+        );
+        assert_eq!(diagram.get_group(EdgeGroup::Roots), &[a]);
+        let output: Vec<_> = diagram
+            .evaluate(&input)
+            .all_facts()
+            .map(|f| (f.predicate, f.values.to_owned()))
+            .collect();
+        assert_eq!(output, vec![(Predicate(1), vec![Value::Symbol(3)])]);
+    }
+
+    #[test]
+    fn set_num_terms_grow() {
+        let mut diagram = diagram_literal(
+            r#"
+        root: @0(_ -> %0) {
+          a: output @1(%0)
+        }
+        "#,
+            1,
+        );
+        let root = diagram.get_root().unwrap();
+        let a = diagram.get_group(EdgeGroup::MatchTargets(root))[0];
+        for &node in [root, a].iter() {
+            assert_eq!(
+                apply_mutation(
+                    &mut diagram,
+                    Mutation::SetNumTerms { node, num_terms: 3 },
+                    &mut IndividualMutationState::new(),
+                ),
+                Some(MutationResult {
+                    phenotype_could_have_changed: true,
+                    node_to_restart: Some(node),
+                })
+            );
+        }
+        assert_eq!(*diagram.get_node(root), node_literal("@0(_ -> %0, _, _)"));
+        assert_eq!(*diagram.get_node(a), node_literal("output @1(%0, :0, :0)"));
+    }
+
+    #[test]
+    fn set_num_terms_shrink() {
+        let mut diagram = diagram_literal(
+            r#"
+        root: @0(_ -> %0, :1 -> %1) {
+          a: output @1(%0, %1)
+        }
+        "#,
+            2,
+        );
+        let root = diagram.get_root().unwrap();
+        let a = diagram.get_group(EdgeGroup::MatchTargets(root))[0];
+        for &node in [root, a].iter() {
+            apply_mutation(
+                &mut diagram,
+                Mutation::SetNumTerms { node, num_terms: 1 },
+                &mut IndividualMutationState::new(),
+            );
+        }
+        assert_eq!(*diagram.get_node(root), node_literal("@0(_ -> %0)"));
+        assert_eq!(*diagram.get_node(a), node_literal("output @1(%0)"));
+    }
+
+    #[test]
+    fn replace_subtree() {
+        let mut diagram = diagram_literal(
+            r#"
+        root: @0(_ -> %0) {
+          a: output @1(%0)
+        } {
+          b: @1(_ -> %0) {
+            c: output @2(%0)
+          }
+        }
+        "#,
+            1,
+        );
+        let root = diagram.get_root().unwrap();
+        let a = diagram.get_group(EdgeGroup::MatchTargets(root))[0];
+        let b = diagram.get_group(EdgeGroup::RefuteTargets(root))[0];
+        let at = Edge::Match {
+            source: root,
+            target: a,
+        };
+        assert_eq!(
+            apply_mutation(
+                &mut diagram,
+                Mutation::ReplaceSubtree { at, with: b },
+                &mut IndividualMutationState::new(),
+            ),
+            Some(MutationResult {
+                phenotype_could_have_changed: true,
+                node_to_restart: Some(root),
+            })
+        );
+        assert_eq!(diagram.get_group(EdgeGroup::MatchTargets(root)), &[b]);
+        assert_eq!(diagram.get_group(EdgeGroup::RefuteTargets(root)), &[b]);
+        assert_eq!(diagram.get_group(EdgeGroup::MatchSources(a)).len(), 0);
+        let at = Edge::Refute {
+            source: root,
+            target: b,
+        };
+        apply_mutation(
+            &mut diagram,
+            Mutation::ReplaceSubtree { at, with: a },
+            &mut IndividualMutationState::new(),
+        );
+        assert_eq!(diagram.get_group(EdgeGroup::RefuteTargets(root)), &[a]);
+        assert_eq!(diagram.get_group(EdgeGroup::MatchTargets(root)), &[b]);
+        // The edge no longer exists, so it can't be replaced again.
+        assert_eq!(
+            apply_mutation(
+                &mut diagram,
+                Mutation::ReplaceSubtree { at, with: a },
+                &mut IndividualMutationState::new(),
+            ),
+            None
+        );
+    }
 }
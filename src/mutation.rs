@@ -77,4 +77,26 @@ pub enum Mutation {
         predicate: Predicate,
         terms: Vec<MatchTerm>,
     },
+    SwapTargets {
+        node: NodeIndex,
+    },
+    SetRoot {
+        node: NodeIndex,
+    },
+    /**
+     * Resizes the terms of a match or output node. This doesn't change the node's predicate, so it
+     * should be paired with a `SetPredicate` to a predicate with `num_terms` terms.
+     */
+    SetNumTerms {
+        node: NodeIndex,
+        num_terms: usize,
+    },
+    /**
+     * Redirects the existing edge `at` to `with`, grafting `with` and everything below it in
+     * place of the edge's original target.
+     */
+    ReplaceSubtree {
+        at: Edge,
+        with: NodeIndex,
+    },
 }
@@ -30,4 +30,71 @@ impl NameTable {
     pub fn get_name(&self, index: usize) -> Option<&str> {
         self.index_to_name.get(&index).map(|s| &s[..])
     }
+
+    /**
+     * Returns the index of `name` without interning it.
+     */
+    pub fn lookup(&self, name: &str) -> Option<usize> {
+        self.name_to_index.get(name).cloned()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.name_to_index.contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.next_index
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.next_index == 0
+    }
+
+    /**
+     * Iterates over every interned name, in index order.
+     */
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (usize, &'a str)> + 'a {
+        (0..self.next_index).map(move |index| (index, &self.index_to_name[&index][..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_does_not_intern() {
+        let mut table = NameTable::new();
+        assert_eq!(table.lookup("edge"), None);
+        assert!(!table.contains("edge"));
+        assert_eq!(table.get("edge"), 0);
+        assert_eq!(table.lookup("edge"), Some(0));
+        assert!(table.contains("edge"));
+        assert_eq!(table.lookup("path"), None);
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn len_counts_distinct_names() {
+        let mut table = NameTable::new();
+        assert_eq!(table.len(), 0);
+        assert!(table.is_empty());
+        table.get("edge");
+        table.get("path");
+        table.get("edge");
+        assert_eq!(table.len(), 2);
+        assert!(!table.is_empty());
+    }
+
+    #[test]
+    fn iter_visits_names_in_index_order() {
+        let mut table = NameTable::new();
+        table.get("path");
+        table.get("edge");
+        table.get("path");
+        let names: Vec<(usize, &str)> = table.iter().collect();
+        assert_eq!(names, vec![(0, "path"), (1, "edge")]);
+        assert_eq!(table.get_name(1), Some("edge"));
+        assert_eq!(NameTable::new().iter().count(), 0);
+    }
 }
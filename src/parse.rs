@@ -1,13 +1,17 @@
 use std;
+use std::collections::HashSet;
 use std::str::FromStr;
 use unicode_xid::UnicodeXID;
 
 use context::{Context, NodeInfo};
-use diagram::{Edge, EdgeGroup, MatchTerm, MatchTermConstraint, MultiDiagram, Node, OutputTerm};
-use graph_diagram::GraphDiagram;
+use database::Database;
+use diagram::{AggKind, Edge, EdgeGroup, MatchTerm, MatchTermConstraint, MultiDiagram, Node,
+              OutputTerm};
+use fact::{Fact, OwnedFact};
+use graph_diagram::{GraphDiagram, RegisterUsage};
 use node_index::NodeIndex;
 use predicate::Predicate;
-use value::Value;
+use value::{Value, FIRST_FRESH_SYMBOL};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Error<'a> {
@@ -151,10 +155,9 @@ fn skip_whitespace(src: &str) -> &str {
         if some_char_is(c, char::is_whitespace) {
             rest = cs.as_str();
         } else if c == Some('#') {
+            // Skip the rest of the line, including the newline ending the comment, if any.
+            while some_char_is(cs.next(), |c| c != '\n') {}
             rest = cs.as_str();
-            while some_char_is(cs.next(), |c| c != '\n') {
-                rest = cs.as_str();
-            }
         } else {
             break;
         }
@@ -162,9 +165,78 @@ fn skip_whitespace(src: &str) -> &str {
     return rest;
 }
 
-struct ParseContext<'d, 'c, D: 'd + MultiDiagram> {
+const DEFAULT_MAX_NESTING: usize = 128;
+
+struct ParseContext<'s, 'd, 'c, D: 'd + MultiDiagram> {
     diagram: &'d mut D,
     context: &'c mut Context,
+    nesting: usize,
+    max_nesting: usize,
+    facts: Vec<OwnedFact>,
+    // The whole source being parsed, used to find the comments above a node.
+    source: &'s str,
+    // Set by a `registers N` declaration, after which every register must be below `N`.
+    declared_registers: Option<usize>,
+}
+
+impl<'s, 'd, 'c, D: 'd + MultiDiagram> ParseContext<'s, 'd, 'c, D> {
+    fn new(diagram: &'d mut D, context: &'c mut Context) -> Self {
+        ParseContext {
+            diagram,
+            context,
+            nesting: 0,
+            max_nesting: DEFAULT_MAX_NESTING,
+            facts: Vec::new(),
+            source: "",
+            declared_registers: None,
+        }
+    }
+}
+
+/**
+ * Returns the full-line `#` comments directly above `src`, which should be a suffix of `source`,
+ * without their `#`. Only whitespace may precede `src` on its own line.
+ */
+fn leading_comments<'s>(source: &'s str, src: &str) -> Vec<&'s str> {
+    let start = source.as_ptr() as usize;
+    let position = src.as_ptr() as usize;
+    if position < start || position > start + source.len() {
+        return Vec::new();
+    }
+    let mut lines = source[..position - start].split('\n').rev();
+    if lines.next().map(|line| line.trim() != "").unwrap_or(true) {
+        return Vec::new();
+    }
+    let mut comments: Vec<&'s str> = lines
+        .map(|line| line.trim())
+        .take_while(|line| line.starts_with('#'))
+        .map(|line| &line[1..])
+        .collect();
+    comments.reverse();
+    comments
+}
+
+fn record_comments<D: MultiDiagram>(src: &str, context: &mut ParseContext<D>, node: NodeIndex) {
+    if let Some(ref mut node_comments) = context.context.node_comments {
+        let comments = leading_comments(context.source, src);
+        if !comments.is_empty() {
+            node_comments.insert(node, comments.iter().map(|&c| c.to_owned()).collect());
+        }
+    }
+}
+
+fn nested<'a, 'b, T, D, F>(src: &'a str, context: &'b mut ParseContext<D>, f: F) -> Result<'a, T>
+where
+    D: MultiDiagram,
+    F: FnOnce(&'a str, &mut ParseContext<D>) -> Result<'a, T>,
+{
+    if context.nesting >= context.max_nesting {
+        return err_msg("Exceeded maximum nesting depth", src);
+    }
+    context.nesting += 1;
+    let result = f(src, context);
+    context.nesting -= 1;
+    result
 }
 
 fn group_element<'a, 'b, D: MultiDiagram>(
@@ -172,22 +244,33 @@ fn group_element<'a, 'b, D: MultiDiagram>(
     context: &'b mut ParseContext<D>,
 ) -> Result<'a, NodeIndex> {
     let rest = skip_whitespace(src);
-    if let Ok((node_index, rest)) = node(rest, context) {
-        return Ok((node_index, rest));
+    // A bare name refers to a node. Anything else must be a node, and its errors are reported
+    // rather than retried as a name.
+    if let Ok((name, after_name)) = lowercase_identifier(rest) {
+        let after_name = skip_whitespace(after_name);
+        if character(after_name, ';').is_ok() || character(after_name, '}').is_ok() {
+            return Ok((
+                context
+                    .context
+                    .reserve_node_name(name, context.diagram)
+                    .index,
+                after_name,
+            ));
+        }
     }
-    let (name, rest) = lowercase_identifier(rest)?;
-    return Ok((
-        context
-            .context
-            .reserve_node_name(name, context.diagram)
-            .index,
-        rest,
-    ));
+    node(rest, context)
 }
 
 fn arm<'a, 'b, D: MultiDiagram>(
     src: &'a str,
     context: &'b mut ParseContext<D>,
+) -> Result<'a, Option<NodeIndex>> {
+    nested(src, context, arm_body)
+}
+
+fn arm_body<'a, 'b, D: MultiDiagram>(
+    src: &'a str,
+    context: &'b mut ParseContext<D>,
 ) -> Result<'a, Option<NodeIndex>> {
     let rest = skip_whitespace(src);
     let (_, rest) = character(rest, '{')?;
@@ -218,6 +301,13 @@ fn arm<'a, 'b, D: MultiDiagram>(
 fn group<'a, 'b, D: MultiDiagram>(
     src: &'a str,
     context: &'b mut ParseContext<D>,
+) -> Result<'a, Vec<NodeIndex>> {
+    nested(src, context, group_body)
+}
+
+fn group_body<'a, 'b, D: MultiDiagram>(
+    src: &'a str,
+    context: &'b mut ParseContext<D>,
 ) -> Result<'a, Vec<NodeIndex>> {
     let rest = skip_whitespace(src);
     let (_, rest) = character(rest, '{')?;
@@ -225,13 +315,12 @@ fn group<'a, 'b, D: MultiDiagram>(
     let mut items = Vec::new();
     loop {
         rest = skip_whitespace(rest);
-        if let Ok((item, r)) = group_element(rest, context) {
-            items.push(item);
-            rest = r;
-        } else {
+        if character(rest, '}').is_ok() {
             break;
         }
-        rest = skip_whitespace(rest);
+        let (item, r) = group_element(rest, context)?;
+        items.push(item);
+        rest = skip_whitespace(r);
         if let Ok((_, r)) = character(rest, ';') {
             rest = r;
         } else {
@@ -253,8 +342,8 @@ fn reserve_predicate<'a, 'b, D: MultiDiagram>(
         ParsedPredicate::Name(predicate_name) => context.context.reserve_predicate(predicate_name),
         ParsedPredicate::Number(predicate) => Predicate(predicate),
     };
-    if let Some(num_terms) = context.context.get_num_terms_for_predicate(predicate) {
-        if num_terms != num_terms {
+    if let Some(expected_num_terms) = context.context.get_num_terms_for_predicate(predicate) {
+        if num_terms != expected_num_terms {
             return err_msg("Wrong number of terms for predicate", src);
         }
     } else {
@@ -286,29 +375,107 @@ fn parse_predicate<'a, 'b, D: MultiDiagram>(
     }
 }
 
+fn keyword<'a, 'b>(src: &'a str, keyword: &'b str) -> EmptyResult<'a> {
+    let rest = prefix(src, keyword)?;
+    if some_char_is(rest.chars().next(), UnicodeXID::is_xid_continue) {
+        return Err(err_from_str("Keyword is a prefix of an identifier", src));
+    }
+    Ok(rest)
+}
+
+fn check_declared_registers<'a, D: MultiDiagram>(
+    src: &'a str,
+    context: &ParseContext<D>,
+    node: &Node,
+) -> std::result::Result<(), Error<'a>> {
+    if let Some(num_registers) = context.declared_registers {
+        if let Some(max_register) = RegisterUsage::of_node(node).max_register {
+            if max_register >= num_registers {
+                return Err(err_from_str(
+                    "Node uses a register beyond the declared number of registers",
+                    src,
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn insert_leaf_node<'a, 'b, D: MultiDiagram>(
+    src: &'a str,
+    context: &'b mut ParseContext<D>,
+    name: Option<&'a str>,
+    node: Node,
+) -> std::result::Result<NodeIndex, Error<'a>> {
+    check_declared_registers(src, context, &node)?;
+    if let Some(name) = name {
+        let NodeInfo { defined, index } = context.context.reserve_node_name(name, context.diagram);
+        if defined {
+            return Err(err_from_str("Node with this name was already defined", src));
+        }
+        *context.diagram.get_node_mut(index) = node;
+        context.context.define_node_name(name);
+        Ok(index)
+    } else {
+        Ok(context.diagram.insert_node(node))
+    }
+}
+
 fn output_node<'a, 'b, D: MultiDiagram>(
     src: &'a str,
     context: &'b mut ParseContext<D>,
     name: Option<&'a str>,
 ) -> Result<'a, NodeIndex> {
     let rest = prefix(src, "output")?;
+    let (skip_on_unbound, rest) = match character(rest, '!') {
+        Ok((_, rest)) => (true, rest),
+        Err(_) => (false, rest),
+    };
     let rest = skip_whitespace(rest);
     let (predicate, rest) = parse_predicate(rest, context)?;
     let rest = skip_whitespace(rest);
     let (terms, rest) = output_terms(rest, context)?;
     let predicate = reserve_predicate(src, context, predicate, terms.len())?.0;
-    let node = Node::Output { predicate, terms };
-    let node_index;
-    if let Some(name) = name {
-        let NodeInfo { defined, index } = context.context.reserve_node_name(name, context.diagram);
-        node_index = index;
-        if defined {
-            return err_msg("Node with this name was already defined", src);
-        }
-        *context.diagram.get_node_mut(index) = node;
+    let node = Node::Output {
+        predicate,
+        terms,
+        skip_on_unbound,
+    };
+    let node_index = insert_leaf_node(src, context, name, node)?;
+    Ok((node_index, rest))
+}
+
+fn aggregate_kind<'a, 'b, D: MultiDiagram>(
+    src: &'a str,
+    context: &'b mut ParseContext<D>,
+) -> Result<'a, AggKind> {
+    if let Ok(rest) = keyword(src, "count") {
+        Ok((AggKind::Count, rest))
     } else {
-        node_index = context.diagram.insert_node(node);
+        let rest = keyword(src, "sum")?;
+        let (reg, rest) = register(rest, context)?;
+        Ok((AggKind::Sum(reg), rest))
     }
+}
+
+fn aggregate_node<'a, 'b, D: MultiDiagram>(
+    src: &'a str,
+    context: &'b mut ParseContext<D>,
+    name: Option<&'a str>,
+) -> Result<'a, NodeIndex> {
+    let (agg, rest) = aggregate_kind(src, context)?;
+    let rest = skip_whitespace(rest);
+    let (predicate, rest) = parse_predicate(rest, context)?;
+    let rest = skip_whitespace(rest);
+    let (terms, rest) = output_terms(rest, context)?;
+    // The aggregated value is appended after the output terms.
+    let predicate = reserve_predicate(src, context, predicate, terms.len() + 1)?.0;
+    let node = Node::Aggregate {
+        predicate,
+        terms,
+        agg,
+    };
+    let node_index = insert_leaf_node(src, context, name, node)?;
     Ok((node_index, rest))
 }
 
@@ -318,15 +485,30 @@ fn match_node<'a, 'b, D: MultiDiagram>(
     name: Option<&'a str>,
 ) -> Result<'a, NodeIndex> {
     let (predicate, rest) = parse_predicate(src, context)?;
+    let (require_bound, rest) = match character(rest, '!') {
+        Ok((_, rest)) => (true, rest),
+        Err(_) => (false, rest),
+    };
     let (terms, rest) = match_terms(rest, context)?;
+    if has_duplicate_target(&terms) {
+        return err_msg("Match node writes the same register more than once", src);
+    }
     let (match_targets, rest) = group(rest, context)?;
-    let (refute_targets, rest) = if let Ok((t, r)) = group(rest, context) {
-        (t, r)
+    let (refute_targets, rest) = if character(skip_whitespace(rest), '{').is_ok() {
+        group(rest, context)?
     } else {
         (vec![], rest)
     };
+    if has_duplicate_node(&match_targets) || has_duplicate_node(&refute_targets) {
+        return err_msg("Match node has the same target twice in one group", src);
+    }
     let predicate = reserve_predicate(src, context, predicate, terms.len())?.0;
-    let node = Node::Match { predicate, terms };
+    let node = Node::Match {
+        predicate,
+        terms,
+        require_bound,
+    };
+    check_declared_registers(src, context, &node)?;
     if let Some(name) = name {
         let NodeInfo {
             defined,
@@ -336,6 +518,7 @@ fn match_node<'a, 'b, D: MultiDiagram>(
             return err_msg("Node with this name was already defined", src);
         }
         *context.diagram.get_node_mut(source) = node;
+        context.context.define_node_name(name);
         for target in match_targets {
             context.diagram.insert_edge(Edge::Match { source, target });
         }
@@ -361,12 +544,29 @@ fn node_without_name<'a, 'b, D: MultiDiagram>(
     name: Option<&'a str>,
 ) -> Result<'a, NodeIndex> {
     let rest = skip_whitespace(src);
-    if let Ok((node, rest)) = output_node(rest, context, name) {
-        return Ok((node, rest));
-    };
+    if starts_with_node_keyword(rest, "output") {
+        return output_node(rest, context, name);
+    }
+    if starts_with_node_keyword(rest, "count") || starts_with_node_keyword(rest, "sum") {
+        return aggregate_node(rest, context, name);
+    }
     return match_node(src, context, name);
 }
 
+/**
+ * Returns whether `src` starts with `keyword` used as a keyword, rather than as the name of a
+ * predicate being matched.
+ */
+fn starts_with_node_keyword(src: &str, keyword_str: &str) -> bool {
+    match keyword(src, keyword_str) {
+        Ok(rest) => {
+            let rest = character(rest, '!').map(|(_, r)| r).unwrap_or(rest);
+            character(skip_whitespace(rest), '(').is_err()
+        }
+        Err(_) => false,
+    }
+}
+
 fn root_statement<'a, 'b, D: MultiDiagram>(
     src: &'a str,
     context: &'b mut ParseContext<D>,
@@ -374,31 +574,32 @@ fn root_statement<'a, 'b, D: MultiDiagram>(
     let rest = prefix(src, "root")?;
     let rest = skip_whitespace(rest);
     let rest = character(rest, ':')?.1;
-    group(rest, context)
-        .map(|(roots, rest)| {
-            for root in roots {
-                context.diagram.insert_edge(Edge::Root(root));
+    if let Ok((roots, r)) = group(rest, context) {
+        for root in roots {
+            if context.diagram.try_insert_edge(Edge::Root(root)).is_err() {
+                return Err(err_from_str("Node is already a root", rest));
             }
-            rest
-        })
-        .or_else(|_| {
-            node(rest, context).map(|(root, rest)| {
-                context.diagram.insert_edge(Edge::Root(root));
-                rest
-            })
-        })
+        }
+        return Ok(r);
+    }
+    let (root, rest) = node(rest, context)?;
+    record_comments(src, context, root);
+    context.diagram.insert_edge(Edge::Root(root));
+    Ok(rest)
 }
 
 fn node<'a, 'b, D: MultiDiagram>(
     src: &'a str,
     context: &'b mut ParseContext<D>,
 ) -> Result<'a, NodeIndex> {
-    let rest = skip_whitespace(src);
-    if let Ok((name, rest)) = node_name(rest, context) {
-        node_without_name(rest, context, Some(name))
+    let start = skip_whitespace(src);
+    let (node, rest) = if let Ok((name, rest)) = node_name(start, context) {
+        node_without_name(rest, context, Some(name))?
     } else {
-        node_without_name(rest, context, None)
-    }
+        node_without_name(start, context, None)?
+    };
+    record_comments(start, context, node);
+    Ok((node, rest))
 }
 
 fn node_name<'a, 'b, D: MultiDiagram>(
@@ -418,9 +619,11 @@ fn named_node<'a, 'b, D: MultiDiagram>(
     src: &'a str,
     context: &'b mut ParseContext<D>,
 ) -> Result<'a, NodeIndex> {
-    let rest = skip_whitespace(src);
-    let (name, rest) = node_name(rest, context)?;
-    node_without_name(rest, context, Some(name))
+    let start = skip_whitespace(src);
+    let (name, rest) = node_name(start, context)?;
+    let (node, rest) = node_without_name(rest, context, Some(name))?;
+    record_comments(start, context, node);
+    Ok((node, rest))
 }
 
 fn toplevel_statement<'a, 'b, D: MultiDiagram>(
@@ -428,8 +631,15 @@ fn toplevel_statement<'a, 'b, D: MultiDiagram>(
     context: &'b mut ParseContext<D>,
 ) -> EmptyResult<'a> {
     let rest = skip_whitespace(src);
-    return root_statement(rest, context)
-        .or_else(|_| named_node(rest, context).map(|(_, rest)| rest));
+    if keyword(rest, "assert").is_ok() {
+        let (fact, rest) = assertion(rest, context)?;
+        context.facts.push(fact);
+        return Ok(rest);
+    }
+    if keyword(rest, "root").is_ok() {
+        return root_statement(rest, context);
+    }
+    return named_node(rest, context).map(|(_, rest)| rest);
 }
 
 fn arg_list<'a, I, F: FnMut(&'a str) -> Result<'a, I>>(
@@ -459,6 +669,20 @@ fn arg_list<'a, I, F: FnMut(&'a str) -> Result<'a, I>>(
     return Ok((items, rest));
 }
 
+fn has_duplicate_node(nodes: &[NodeIndex]) -> bool {
+    let mut seen = HashSet::new();
+    nodes.iter().any(|node| !seen.insert(*node))
+}
+
+fn has_duplicate_target(terms: &[MatchTerm]) -> bool {
+    let mut targets = HashSet::new();
+    terms
+        .iter()
+        .filter(|term| !term.if_unbound)
+        .filter_map(|term| term.target)
+        .any(|target| !targets.insert(target))
+}
+
 fn match_terms<'a, 'b, D: MultiDiagram>(
     src: &'a str,
     context: &'b mut ParseContext<D>,
@@ -481,18 +705,35 @@ fn match_term<'a, 'b, D: MultiDiagram>(
     } else if let Ok((v, r)) = value(rest, context) {
         constraint = MatchTermConstraint::Constant(v);
         rest = r;
+    } else if let Ok((_, r)) = character(rest, '!') {
+        let (v, r) = value(r, context)?;
+        constraint = MatchTermConstraint::NotConstant(v);
+        rest = r;
     } else {
         return err_msg("could not parse match term", src);
     }
     let mut rest = skip_whitespace(rest);
     let mut target = None;
+    let mut if_unbound = false;
     if let Ok(r) = prefix(rest, "->") {
-        rest = skip_whitespace(r);
+        rest = r;
+        if let Ok((_, r)) = character(rest, '?') {
+            if_unbound = true;
+            rest = r;
+        }
+        rest = skip_whitespace(rest);
         let (reg, r) = register(rest, context)?;
         target = Some(reg);
         rest = r;
     }
-    Ok((MatchTerm { constraint, target }, rest))
+    Ok((
+        MatchTerm {
+            constraint,
+            target,
+            if_unbound,
+        },
+        rest,
+    ))
 }
 
 fn output_terms<'a, 'b, D: MultiDiagram>(
@@ -507,10 +748,17 @@ fn output_term<'a, 'b, D: MultiDiagram>(
     context: &'b mut ParseContext<D>,
 ) -> Result<'a, OutputTerm> {
     let rest = skip_whitespace(src);
-    if let Ok((reg, rest)) = register(rest, context) {
+    if let Ok((_, rest)) = character(rest, '!') {
+        Ok((OutputTerm::Fresh, rest))
+    } else if let Ok((_, rest)) = character(rest, '_') {
+        Ok((OutputTerm::Constant(Value::Nil), rest))
+    } else if let Ok((reg, rest)) = register(rest, context) {
         Ok((OutputTerm::Register(reg), rest))
     } else if let Ok((v, rest)) = value(rest, context) {
         Ok((OutputTerm::Constant(v), rest))
+    } else if let Ok((_, rest)) = character(rest, '$') {
+        let (column, rest) = unsigned_decimal_integer(rest)?;
+        Ok((OutputTerm::MatchColumn(column as usize), rest))
     } else {
         err_msg("could not parse match term", src)
     }
@@ -533,67 +781,228 @@ fn value<'a, 'b, D: MultiDiagram>(
     let rest = skip_whitespace(src);
     let (_, rest) = character(rest, ':')?;
     let (symbol, rest) = unsigned_decimal_integer(rest)?;
+    if symbol >= FIRST_FRESH_SYMBOL {
+        return err_msg("Symbol is reserved for fresh symbols", src);
+    }
     Ok((Value::Symbol(symbol), rest))
 }
 
+/**
+ * Parses an optional `registers N` declaration at the start of `src`.
+ */
+fn registers_declaration(src: &str) -> (Option<usize>, &str) {
+    let rest = skip_whitespace(src);
+    if let Ok(rest) = keyword(rest, "registers") {
+        if let Ok((num_registers, rest)) = unsigned_decimal_integer(skip_whitespace(rest)) {
+            return (Some(num_registers as usize), rest);
+        }
+    }
+    (None, src)
+}
+
 fn parse_diagram_inner<'a, 'b, D: MultiDiagram>(
     src: &'a str,
     context: &'b mut ParseContext<D>,
 ) -> Result<'a, ()> {
-    let mut rest = src;
+    let (declared_registers, rest) = registers_declaration(src);
+    context.declared_registers = declared_registers;
+    let mut rest = skip_whitespace(rest);
     while rest != "" {
+        // Every statement starts with a keyword or a name, so anything else is leftover input
+        // rather than a statement with a mistake inside it.
+        if !some_char_is(rest.chars().next(), UnicodeXID::is_xid_start) {
+            return err_msg("Unexpected input after the last statement", rest);
+        }
         let r = toplevel_statement(rest, context)?;
         rest = skip_whitespace(r);
     }
     Ok(((), rest))
 }
 
+fn resolve_match_columns<'a>(
+    diagram: &mut GraphDiagram,
+    src: &'a str,
+) -> std::result::Result<(), Error<'a>> {
+    if diagram.resolve_match_columns() {
+        Ok(())
+    } else {
+        Err(err_from_str(
+            "Match column used by a node without a unique source match node",
+            src,
+        ))
+    }
+}
+
 pub fn parse_diagram(
     src: &str,
     num_registers: usize,
 ) -> std::result::Result<(GraphDiagram, Context), Error> {
+    parse_diagram_with_max_nesting(src, num_registers, DEFAULT_MAX_NESTING)
+}
+
+pub fn parse_diagram_with_max_nesting(
+    src: &str,
+    num_registers: usize,
+    max_nesting: usize,
+) -> std::result::Result<(GraphDiagram, Context), Error> {
+    let num_registers = registers_declaration(src).0.unwrap_or(num_registers);
     let mut d = GraphDiagram::new(num_registers);
     let mut c = Context::new();
-    let result;
-    {
-        let mut context = ParseContext {
-            diagram: &mut d,
-            context: &mut c,
-        };
-        result = parse_diagram_inner(src, &mut context);
-    }
-    match result {
-        Ok(_) => Ok((d, c)),
-        Err(e) => Err(e),
+    let facts = parse_program_inner(src, &mut d, &mut c, max_nesting)?;
+    if !facts.is_empty() {
+        return Err(err_from_str("Assertions are not allowed in a diagram", src));
     }
+    check_node_names_defined(src, &c)?;
+    resolve_match_columns(&mut d, src)?;
+    Ok((d, c))
 }
 
+/**
+ * Parses more statements into `diagram`. Unlike `parse_diagram`, this doesn't resolve match
+ * columns or check that referenced node names are defined, since a later update may still add
+ * them. Call `GraphDiagram::resolve_match_columns` before evaluating the result, since unresolved
+ * match columns evaluate like unbound registers.
+ */
 pub fn update_diagram<'a, 'b, 'c, D: MultiDiagram>(
     src: &'a str,
     diagram: &'b mut D,
-    context: &'a mut Context,
+    context: &'c mut Context,
 ) -> std::result::Result<(), Error<'a>> {
-    let result;
-    {
-        let mut context = ParseContext { diagram, context };
-        result = parse_diagram_inner(src, &mut context);
+    let facts = parse_program_inner(src, diagram, context, DEFAULT_MAX_NESTING)?;
+    if !facts.is_empty() {
+        return Err(err_from_str("Assertions are not allowed in a diagram", src));
+    }
+    Ok(())
+}
+
+/**
+ * Parses a mix of diagram statements and `assert p(:1, :2)` statements, returning the asserted
+ * facts as a database alongside the diagram.
+ */
+pub fn parse_program(
+    src: &str,
+    num_registers: usize,
+) -> std::result::Result<(GraphDiagram, Context, Database), Error> {
+    let num_registers = registers_declaration(src).0.unwrap_or(num_registers);
+    let mut d = GraphDiagram::new(num_registers);
+    let mut c = Context::new();
+    let mut db = Database::new();
+    update_program(src, &mut d, &mut c, &mut db)?;
+    check_node_names_defined(src, &c)?;
+    resolve_match_columns(&mut d, src)?;
+    Ok((d, c, db))
+}
+
+/**
+ * Like `update_diagram`, but also accepts `assert` statements, whose facts are inserted into
+ * `database`. Match columns are likewise left unresolved.
+ */
+pub fn update_program<'a, 'b, 'c, 'd, D: MultiDiagram>(
+    src: &'a str,
+    diagram: &'b mut D,
+    context: &'c mut Context,
+    database: &'d mut Database,
+) -> std::result::Result<(), Error<'a>> {
+    let facts = parse_program_inner(src, diagram, context, DEFAULT_MAX_NESTING)?;
+    for fact in facts.iter() {
+        database.insert_fact(Fact {
+            predicate: fact.predicate,
+            values: &fact.values,
+        });
     }
-    match result {
-        Ok(_) => Ok(()),
-        Err(e) => Err(e),
+    Ok(())
+}
+
+fn parse_program_inner<'a, 'b, 'c, D: MultiDiagram>(
+    src: &'a str,
+    diagram: &'b mut D,
+    context: &'c mut Context,
+    max_nesting: usize,
+) -> std::result::Result<Vec<OwnedFact>, Error<'a>> {
+    let mut context = ParseContext::new(diagram, context);
+    context.max_nesting = max_nesting;
+    context.source = src;
+    parse_diagram_inner(src, &mut context)?;
+    Ok(context.facts)
+}
+
+/**
+ * Checks that every node name referenced in `context` was defined. Names can be referenced before
+ * they're defined, possibly by a later call to `update_diagram`, so this is only checked once the
+ * whole source has been parsed. Reports the earliest reference in `src`.
+ */
+fn check_node_names_defined<'a>(
+    src: &'a str,
+    context: &Context,
+) -> std::result::Result<(), Error<'a>> {
+    let first_undefined = context
+        .node_name_to_info
+        .iter()
+        .filter(|&(_, info)| !info.defined)
+        .filter_map(|(name, _)| find_node_reference(src, name))
+        .max_by_key(|rest| rest.len());
+    match first_undefined {
+        Some(rest) => Err(err_from_str("Node name was referenced but never defined", rest)),
+        None => Ok(()),
     }
 }
 
+/**
+ * Finds the first use of `name` as a node reference in `src`. Uses of `name` as a predicate, which
+ * are followed by an argument list, and uses in comments are skipped.
+ */
+fn find_node_reference<'a>(src: &'a str, name: &str) -> Option<&'a str> {
+    src.match_indices(name)
+        .map(|(index, _)| index)
+        .find(|&index| {
+            let before = src[..index].chars().next_back();
+            let rest = &src[index + name.len()..];
+            let line_start = src[..index].rfind('\n').map_or(0, |i| i + 1);
+            let after_bang = character(rest, '!').map(|(_, r)| r).unwrap_or(rest);
+            !some_char_is(before, UnicodeXID::is_xid_continue)
+                && !some_char_is(rest.chars().next(), UnicodeXID::is_xid_continue)
+                && character(skip_whitespace(after_bang), '(').is_err()
+                && !src[line_start..index].contains('#')
+        })
+        .map(|index| &src[index..])
+}
+
+fn assertion<'a, 'b, D: MultiDiagram>(
+    src: &'a str,
+    context: &'b mut ParseContext<D>,
+) -> Result<'a, OwnedFact> {
+    let rest = skip_whitespace(src);
+    let rest = keyword(rest, "assert")?;
+    let (parsed_predicate, rest) = parse_predicate(rest, context)?;
+    let (values, rest) = arg_list(rest, |s| value(s, context))?;
+    let (predicate, rest) = reserve_predicate(rest, context, parsed_predicate, values.len())?;
+    Ok((OwnedFact { predicate, values }, rest))
+}
+
+/**
+ * Parses a single `assert p(:1, :2)` statement, reserving the predicate in `context`.
+ */
+pub fn parse_assertion<'a, 'b, 'c, D: MultiDiagram>(
+    src: &'a str,
+    diagram: &'b mut D,
+    context: &'c mut Context,
+) -> std::result::Result<OwnedFact, Error<'a>> {
+    let mut context = ParseContext::new(diagram, context);
+    let (fact, rest) = assertion(src, &mut context)?;
+    let rest = skip_whitespace(rest);
+    if rest != "" {
+        return Err(err_from_str("Unexpected input after assertion", rest));
+    }
+    Ok(fact)
+}
+
 #[cfg(test)]
 pub fn node_literal(src: &str) -> Node {
     let mut d = GraphDiagram::new(100);
     let mut c = Context::new();
     let src = format!("root: {} {{ }}", src);
     {
-        let mut context = ParseContext {
-            diagram: &mut d,
-            context: &mut c,
-        };
+        let mut context = ParseContext::new(&mut d, &mut c);
         toplevel_statement(&src, &mut context).expect("Could not parse node literal");
     }
     d.get_node(d.get_group(EdgeGroup::Roots)[0]).clone()
@@ -602,17 +1011,20 @@ pub fn node_literal(src: &str) -> Node {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use database::{database_literal, symbols};
+    use diagram::Diagram;
 
     #[test]
     fn can_parse_value() {
         let mut diagram = GraphDiagram::new(0);
         let mut context = Context::new();
-        let mut c = ParseContext {
-            diagram: &mut diagram,
-            context: &mut context,
-        };
+        let mut c = ParseContext::new(&mut diagram, &mut context);
         assert_eq!(value(":0", &mut c), Ok((Value::Symbol(0), "")));
         assert_eq!(value(":1", &mut c), Ok((Value::Symbol(1), "")));
+        assert_eq!(
+            value(":9223372036854775808", &mut c),
+            err_msg("Symbol is reserved for fresh symbols", ":9223372036854775808")
+        );
         assert_eq!(
             value(":blank", &mut c),
             Err(Error::Msg {
@@ -626,10 +1038,7 @@ mod tests {
     fn can_parse_register() {
         let mut diagram = GraphDiagram::new(0);
         let mut context = Context::new();
-        let mut c = ParseContext {
-            diagram: &mut diagram,
-            context: &mut context,
-        };
+        let mut c = ParseContext::new(&mut diagram, &mut context);
         assert_eq!(register("%0", &mut c), Ok((0, "")));
         assert_eq!(register("%1", &mut c), Ok((1, "")));
         assert_eq!(
@@ -645,16 +1054,14 @@ mod tests {
     fn can_parse_match_term() {
         let mut diagram = GraphDiagram::new(0);
         let mut context = Context::new();
-        let mut c = ParseContext {
-            diagram: &mut diagram,
-            context: &mut context,
-        };
+        let mut c = ParseContext::new(&mut diagram, &mut context);
         assert_eq!(
             match_term("_", &mut c),
             Ok((
                 MatchTerm {
                     constraint: MatchTermConstraint::Free,
                     target: None,
+                    if_unbound: false,
                 },
                 ""
             ))
@@ -665,6 +1072,7 @@ mod tests {
                 MatchTerm {
                     constraint: MatchTermConstraint::Free,
                     target: Some(1),
+                    if_unbound: false,
                 },
                 ""
             ))
@@ -675,6 +1083,7 @@ mod tests {
                 MatchTerm {
                     constraint: MatchTermConstraint::Free,
                     target: Some(1),
+                    if_unbound: false,
                 },
                 ""
             ))
@@ -685,6 +1094,7 @@ mod tests {
                 MatchTerm {
                     constraint: MatchTermConstraint::Constant(Value::Symbol(2)),
                     target: Some(3),
+                    if_unbound: false,
                 },
                 ""
             ))
@@ -695,6 +1105,7 @@ mod tests {
                 MatchTerm {
                     constraint: MatchTermConstraint::Register(2),
                     target: Some(3),
+                    if_unbound: false,
                 },
                 ""
             ))
@@ -705,10 +1116,7 @@ mod tests {
     fn can_parse_match_terms() {
         let mut diagram = GraphDiagram::new(0);
         let mut context = Context::new();
-        let mut c = ParseContext {
-            diagram: &mut diagram,
-            context: &mut context,
-        };
+        let mut c = ParseContext::new(&mut diagram, &mut context);
         assert_eq!(
             match_terms(" ( _ ) ", &mut c),
             Ok((
@@ -716,6 +1124,7 @@ mod tests {
                     MatchTerm {
                         constraint: MatchTermConstraint::Free,
                         target: None,
+                        if_unbound: false,
                     },
                 ],
                 " "
@@ -728,10 +1137,12 @@ mod tests {
                     MatchTerm {
                         constraint: MatchTermConstraint::Free,
                         target: None,
+                        if_unbound: false,
                     },
                     MatchTerm {
                         constraint: MatchTermConstraint::Free,
                         target: None,
+                        if_unbound: false,
                     },
                 ],
                 " "
@@ -744,10 +1155,12 @@ mod tests {
                     MatchTerm {
                         constraint: MatchTermConstraint::Free,
                         target: None,
+                        if_unbound: false,
                     },
                     MatchTerm {
                         constraint: MatchTermConstraint::Free,
                         target: None,
+                        if_unbound: false,
                     },
                 ],
                 " "
@@ -767,6 +1180,7 @@ mod tests {
                     MatchTerm {
                         constraint: MatchTermConstraint::Free,
                         target: Some(1),
+                        if_unbound: false,
                     },
                 ],
                 ""
@@ -779,6 +1193,7 @@ mod tests {
                     MatchTerm {
                         constraint: MatchTermConstraint::Constant(Value::Symbol(2)),
                         target: Some(3),
+                        if_unbound: false,
                     },
                 ],
                 ""
@@ -791,6 +1206,7 @@ mod tests {
                     MatchTerm {
                         constraint: MatchTermConstraint::Register(2),
                         target: Some(3),
+                        if_unbound: false,
                     },
                 ],
                 ""
@@ -802,10 +1218,7 @@ mod tests {
     fn can_parse_arm() {
         let mut diagram = GraphDiagram::new(0);
         let mut context = Context::new();
-        let mut c = ParseContext {
-            diagram: &mut diagram,
-            context: &mut context,
-        };
+        let mut c = ParseContext::new(&mut diagram, &mut context);
         assert_eq!(arm(" {  } ", &mut c), Ok((None, " ")));
         assert_eq!(c.diagram.len(), 0);
         assert_eq!(arm(" { test } ", &mut c), Ok((Some(NodeIndex(0)), " ")));
@@ -825,15 +1238,13 @@ mod tests {
                 OutputTerm::Constant(Value::Symbol(1)),
                 OutputTerm::Constant(Value::Symbol(2)),
             ],
+            skip_on_unbound: false,
         };
         let root = expected_diagram.insert_node(output_node);
         expected_diagram.insert_edge(Edge::Root(root));
         let mut d = GraphDiagram::new(0);
         let mut context = Context::new();
-        let mut c = ParseContext {
-            diagram: &mut d,
-            context: &mut context,
-        };
+        let mut c = ParseContext::new(&mut d, &mut context);
         assert_eq!(
             parse_diagram_inner("root: output test(:1, :2)", &mut c),
             Ok(((), ""))
@@ -850,12 +1261,15 @@ mod tests {
                 MatchTerm {
                     constraint: MatchTermConstraint::Constant(Value::Symbol(1)),
                     target: Some(0),
+                    if_unbound: false,
                 },
                 MatchTerm {
                     constraint: MatchTermConstraint::Free,
                     target: Some(1),
+                    if_unbound: false,
                 },
             ],
+            require_bound: false,
         };
         let match_anything_node = Node::Match {
             predicate: Predicate(0),
@@ -863,16 +1277,20 @@ mod tests {
                 MatchTerm {
                     constraint: MatchTermConstraint::Free,
                     target: None,
+                    if_unbound: false,
                 },
                 MatchTerm {
                     constraint: MatchTermConstraint::Free,
                     target: Some(1),
+                    if_unbound: false,
                 },
             ],
+            require_bound: false,
         };
         let output_node = Node::Output {
             predicate: Predicate(1),
             terms: vec![OutputTerm::Register(0), OutputTerm::Register(1)],
+            skip_on_unbound: false,
         };
         let output = expected_diagram.insert_node(output_node);
         let anything = expected_diagram.insert_node(match_anything_node);
@@ -895,10 +1313,7 @@ mod tests {
         context
             .predicate_name_to_predicate
             .insert("b".to_owned(), Predicate(1));
-        let mut c = ParseContext {
-            diagram: &mut d,
-            context: &mut context,
-        };
+        let mut c = ParseContext::new(&mut d, &mut context);
         assert_eq!(
             parse_diagram_inner(
                 r#"
@@ -926,19 +1341,416 @@ mod tests {
                 OutputTerm::Constant(Value::Symbol(1)),
                 OutputTerm::Constant(Value::Symbol(2)),
             ],
+            skip_on_unbound: false,
         };
         let root = expected_diagram.insert_node(output_node);
         expected_diagram.insert_edge(Edge::Root(root));
         let mut d = GraphDiagram::new(0);
         let mut context = Context::new();
-        let mut c = ParseContext {
-            diagram: &mut d,
-            context: &mut context,
-        };
+        let mut c = ParseContext::new(&mut d, &mut context);
         assert_eq!(
             parse_diagram_inner("root: output @2(:1, :2)", &mut c),
             Ok(((), ""))
         );
         assert_eq!(c.diagram, &expected_diagram);
     }
+
+    #[test]
+    fn can_update_diagram_referencing_existing_node() {
+        let (mut diagram, mut context) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) {
+          copy: output @1(%0)
+        }
+        "#,
+            1,
+        ).unwrap();
+        let copy = context.node_name_to_info["copy"].index;
+        assert_eq!(
+            update_diagram(
+                r#"
+        root: @2(_ -> %0) { copy }
+        "#,
+                &mut diagram,
+                &mut context,
+            ),
+            Ok(())
+        );
+        let roots = diagram.get_group(EdgeGroup::Roots).to_owned();
+        assert_eq!(roots.len(), 2);
+        assert_eq!(diagram.get_group(EdgeGroup::MatchTargets(roots[1])), &[copy]);
+        assert_eq!(diagram.len(), 3);
+    }
+
+    #[test]
+    fn can_retain_comments() {
+        let src = r#"
+        # Copies edges.
+        #  Indented.
+        root: @0(_ -> %0) {
+          # Emits the copy.
+          out: output @1(%0)  # not a leading comment
+        }
+        # Unused.
+        unused: output @2(:1)
+        "#;
+        let mut diagram = GraphDiagram::new(1);
+        let mut context = Context::with_comments();
+        assert_eq!(update_diagram(src, &mut diagram, &mut context), Ok(()));
+        let root = diagram.get_group(EdgeGroup::Roots)[0];
+        let out = context.node_name_to_info["out"].index;
+        let unused = context.node_name_to_info["unused"].index;
+        assert_eq!(
+            context.comments_for_node(root),
+            &[" Copies edges.".to_owned(), "  Indented.".to_owned()]
+        );
+        assert_eq!(context.comments_for_node(out), &[" Emits the copy.".to_owned()]);
+        assert_eq!(context.comments_for_node(unused), &[" Unused.".to_owned()]);
+
+        let (diagram, context) = parse_diagram(src, 1).unwrap();
+        let root = diagram.get_group(EdgeGroup::Roots)[0];
+        assert!(context.node_comments.is_none());
+        assert!(context.comments_for_node(root).is_empty());
+    }
+
+    #[test]
+    fn cannot_redefine_node_in_update() {
+        let (mut diagram, mut context) = parse_diagram("copy: output @1(:0)", 0).unwrap();
+        assert!(update_diagram("copy: output @1(:1)", &mut diagram, &mut context).is_err());
+    }
+
+    #[test]
+    fn can_parse_assertion() {
+        let mut diagram = GraphDiagram::new(0);
+        let mut context = Context::new();
+        let fact = parse_assertion("assert edge(:1, :2)", &mut diagram, &mut context).unwrap();
+        assert_eq!(
+            fact,
+            OwnedFact {
+                predicate: context.predicate_name_to_predicate["edge"],
+                values: vec![Value::Symbol(1), Value::Symbol(2)],
+            }
+        );
+        assert!(parse_assertion("assert edge(:1) :2", &mut diagram, &mut context).is_err());
+        assert!(parse_assertion("asserted(:1)", &mut diagram, &mut context).is_err());
+    }
+
+    #[test]
+    fn can_parse_program_with_assertions() {
+        let (diagram, context, input) = parse_program(
+            r#"
+        assert edge(:1, :2)
+        root: edge(_ -> %0, _) {
+          output source(%0)
+        }
+        assert edge(:3, :4)
+        assert @5(:6)
+        "#,
+            1,
+        ).unwrap();
+        let edge = context.predicate_name_to_predicate["edge"];
+        let source = context.predicate_name_to_predicate["source"];
+        assert_eq!(
+            input,
+            database_literal(vec![
+                (edge, vec![Value::Symbol(1), Value::Symbol(2)]),
+                (edge, vec![Value::Symbol(3), Value::Symbol(4)]),
+                (Predicate(5), vec![Value::Symbol(6)]),
+            ])
+        );
+        assert!(diagram.evaluate(&input).semantically_eq(&database_literal(vec![
+            (source, vec![Value::Symbol(1)]),
+            (source, vec![Value::Symbol(3)]),
+        ])));
+    }
+
+    #[test]
+    fn assertions_must_be_ground_and_match_arity() {
+        assert!(parse_program("assert edge(:1, %0)", 1).is_err());
+        assert!(parse_program("assert edge(:1, _)", 1).is_err());
+        assert!(parse_program("assert edge(:1, :2) assert edge(:1)", 1).is_err());
+        assert!(parse_program("root: edge(_, _) { } assert edge(:1)", 1).is_err());
+        assert!(parse_diagram("assert edge(:1, :2)", 1).is_err());
+    }
+
+    #[test]
+    fn duplicate_match_targets_are_an_error() {
+        assert!(parse_diagram("root: @0(_ -> %0, _ -> %0) { }", 1).is_err());
+        assert!(parse_diagram("root: @0(%0 -> %0, _ -> %1) { }", 2).is_ok());
+        assert!(parse_diagram("root: @0(_ -> %0, _ ->? %0) { }", 1).is_ok());
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        let mut src = "root: ".to_owned();
+        for _ in 0..10000 {
+            src.push_str("@0(_) {");
+        }
+        assert!(parse_diagram_with_max_nesting(&src, 0, 16).is_err());
+        assert!(parse_diagram(&src, 0).is_err());
+    }
+
+    #[test]
+    fn arm_reports_nesting_limit() {
+        let mut diagram = GraphDiagram::new(0);
+        let mut context = Context::new();
+        let mut c = ParseContext::new(&mut diagram, &mut context);
+        c.max_nesting = 0;
+        assert_eq!(
+            arm(" { } ", &mut c),
+            Err(Error::Msg {
+                msg: "Exceeded maximum nesting depth",
+                rest: " { } ",
+            })
+        );
+    }
+
+    #[test]
+    fn can_parse_skip_on_unbound_output_nodes() {
+        assert_eq!(
+            node_literal("output! @1(%0)"),
+            Node::Output {
+                predicate: Predicate(1),
+                terms: vec![OutputTerm::Register(0)],
+                skip_on_unbound: true,
+            }
+        );
+        assert_eq!(
+            node_literal("output @1(%0)"),
+            Node::Output {
+                predicate: Predicate(1),
+                terms: vec![OutputTerm::Register(0)],
+                skip_on_unbound: false,
+            }
+        );
+    }
+
+    #[test]
+    fn can_parse_require_bound_match_nodes() {
+        assert_eq!(
+            node_literal("@0!(%0, _)"),
+            Node::Match {
+                predicate: Predicate(0),
+                terms: vec![
+                    MatchTerm {
+                        constraint: MatchTermConstraint::Register(0),
+                        target: None,
+                        if_unbound: false,
+                    },
+                    MatchTerm {
+                        constraint: MatchTermConstraint::Free,
+                        target: None,
+                        if_unbound: false,
+                    },
+                ],
+                require_bound: true,
+            }
+        );
+    }
+
+    #[test]
+    fn can_parse_fresh_output_terms() {
+        assert_eq!(
+            node_literal("output @1(!, %0)"),
+            Node::Output {
+                predicate: Predicate(1),
+                terms: vec![OutputTerm::Fresh, OutputTerm::Register(0)],
+                skip_on_unbound: false,
+            }
+        );
+    }
+
+    #[test]
+    fn can_parse_nil_output_terms() {
+        assert_eq!(
+            node_literal("output @1(_, %0)"),
+            Node::Output {
+                predicate: Predicate(1),
+                terms: vec![OutputTerm::Constant(Value::Nil), OutputTerm::Register(0)],
+                skip_on_unbound: false,
+            }
+        );
+        let (diagram, _) = parse_diagram("root: output @1(_, :2)", 1).unwrap();
+        assert_eq!(
+            diagram.evaluate(&Database::new()),
+            database_literal(vec![(Predicate(1), vec![Value::Nil, Value::Symbol(2)])])
+        );
+    }
+
+    #[test]
+    fn can_parse_aggregate_nodes() {
+        assert_eq!(
+            node_literal("count @1(%0)"),
+            Node::Aggregate {
+                predicate: Predicate(1),
+                terms: vec![OutputTerm::Register(0)],
+                agg: AggKind::Count,
+            }
+        );
+        assert_eq!(
+            node_literal("sum %2 @1(%0, :3)"),
+            Node::Aggregate {
+                predicate: Predicate(1),
+                terms: vec![
+                    OutputTerm::Register(0),
+                    OutputTerm::Constant(Value::Symbol(3)),
+                ],
+                agg: AggKind::Sum(2),
+            }
+        );
+        assert_eq!(
+            node_literal("counted(_)"),
+            Node::Match {
+                predicate: Predicate(0),
+                terms: vec![
+                    MatchTerm {
+                        constraint: MatchTermConstraint::Free,
+                        target: None,
+                        if_unbound: false,
+                    },
+                ],
+                require_bound: false,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_trailing_input() {
+        let src = "root: @0(%0) { output @1(%0) }\n} output @1(:1)";
+        assert_eq!(
+            parse_diagram(src, 1).err(),
+            Some(Error::Msg {
+                msg: "Unexpected input after the last statement",
+                rest: "} output @1(:1)",
+            })
+        );
+        assert!(parse_diagram("# first\nroot: output @1(:1)\n# last", 0).is_ok());
+    }
+
+    #[test]
+    fn can_reference_nodes_before_definition() {
+        let (diagram, context) = parse_diagram(
+            r#"
+        root: @0(_ -> %0) { later }
+        later: output @1(%0)
+        "#,
+            1,
+        ).unwrap();
+        let root = diagram.get_group(EdgeGroup::Roots)[0];
+        let later = context.node_name_to_info["later"].index;
+        assert_eq!(diagram.get_group(EdgeGroup::MatchTargets(root)), &[later]);
+        assert_eq!(*diagram.get_node(later), node_literal("output @1(%0)"));
+        assert_eq!(diagram.len(), 2);
+    }
+
+    #[test]
+    fn rejects_undefined_node_names() {
+        let src = "root: @0(_ -> %0) { later } { never }\nlater: output @1(%0)";
+        assert_eq!(
+            parse_diagram(src, 1).err(),
+            Some(Error::Msg {
+                msg: "Node name was referenced but never defined",
+                rest: "never }\nlater: output @1(%0)",
+            })
+        );
+        let src = "# never\nroot: never(_ -> %0) { never }";
+        assert_eq!(
+            parse_diagram(src, 1).err(),
+            Some(Error::Msg {
+                msg: "Node name was referenced but never defined",
+                rest: "never }",
+            })
+        );
+    }
+
+    #[test]
+    fn updates_can_define_names_referenced_earlier() {
+        let mut diagram = GraphDiagram::new(1);
+        let mut context = Context::new();
+        let src = "root: @0(_ -> %0) { later }";
+        assert_eq!(update_diagram(src, &mut diagram, &mut context), Ok(()));
+        let src = "later: output @1(%0)";
+        assert_eq!(update_diagram(src, &mut diagram, &mut context), Ok(()));
+        let root = diagram.get_group(EdgeGroup::Roots)[0];
+        let later = context.node_name_to_info["later"].index;
+        assert_eq!(diagram.get_group(EdgeGroup::MatchTargets(root)), &[later]);
+    }
+
+    #[test]
+    fn updates_leave_match_columns_for_the_caller_to_resolve() {
+        let mut diagram = GraphDiagram::new(0);
+        let mut context = Context::new();
+        let src = "root: @0(_) { later }";
+        assert_eq!(update_diagram(src, &mut diagram, &mut context), Ok(()));
+        let src = "later: output @1($0)";
+        assert_eq!(update_diagram(src, &mut diagram, &mut context), Ok(()));
+        assert!(diagram.resolve_match_columns());
+        let input = symbols(Predicate(0), &[&[1]]);
+        assert!(diagram.evaluate(&input).semantically_eq(&symbols(Predicate(1), &[&[1]])));
+    }
+
+    #[test]
+    fn unresolved_match_columns_evaluate_like_unbound_registers() {
+        let mut diagram = GraphDiagram::new(0);
+        let mut context = Context::new();
+        let src = "root: @0(_) { output @1($0) }";
+        assert_eq!(update_diagram(src, &mut diagram, &mut context), Ok(()));
+        let evaluated = diagram.evaluate(&symbols(Predicate(0), &[&[1]]));
+        let expected = database_literal(vec![(Predicate(1), vec![Value::Nil])]);
+        assert!(evaluated.semantically_eq(&expected));
+    }
+
+    #[test]
+    fn can_declare_registers() {
+        let src = r#"
+        # Copies edges.
+        registers 2
+        root: @0(_ -> %0, _ -> %1) {
+          output @1(%1, %0)
+        }
+        "#;
+        let (diagram, _) = parse_diagram(src, 7).unwrap();
+        assert_eq!(diagram.get_num_registers(), 2);
+        let (diagram, _, _) = parse_program(src, 0).unwrap();
+        assert_eq!(diagram.get_num_registers(), 2);
+        let (diagram, _) = parse_diagram("registers: output @1(%4)", 3).unwrap();
+        assert_eq!(diagram.get_num_registers(), 3);
+    }
+
+    #[test]
+    fn rejects_registers_beyond_declaration() {
+        let src = "registers 2\nroot: @0(_ -> %0) { output @1(%2) }";
+        assert_eq!(
+            parse_diagram(src, 3).err(),
+            Some(Error::Msg {
+                msg: "Node uses a register beyond the declared number of registers",
+                rest: "output @1(%2) }",
+            })
+        );
+        assert!(parse_diagram("registers 1 root: @0(_ -> %1) { }", 3).is_err());
+        assert!(parse_diagram("registers 1 root: @0(%1) { }", 3).is_err());
+        assert!(parse_diagram("registers 1 root: sum %1 @0(%0)", 3).is_err());
+        assert!(parse_diagram("root: @0(_ -> %5) { }", 1).is_ok());
+    }
+
+    #[test]
+    fn rejects_duplicate_edges() {
+        let src = "a: output @1(%0)\nroot: @0(_ -> %0) { a; a }";
+        assert_eq!(
+            parse_diagram(src, 1).err(),
+            Some(Error::Msg {
+                msg: "Match node has the same target twice in one group",
+                rest: "@0(_ -> %0) { a; a }",
+            })
+        );
+        assert!(parse_diagram("a: output @1(%0) root: @0(_ -> %0) { a } { a }", 1).is_ok());
+        let src = "a: output @1(:0)\nroot: { a }\nroot: { a }";
+        assert_eq!(
+            parse_diagram(src, 1).err(),
+            Some(Error::Msg {
+                msg: "Node is already a root",
+                rest: " { a }",
+            })
+        );
+    }
 }
@@ -7,7 +7,7 @@ use tiny_map::TinyMap;
 #[derive(Clone, Debug)]
 pub struct PatchDiagram<'a> {
     graph_diagram: &'a GraphDiagram,
-    root: NodeIndex,
+    root: Option<NodeIndex>,
     next_node: usize,
     node_map: TinyMap<NodeIndex, Node>,
     match_targets: TinyMap<NodeIndex, Option<NodeIndex>>,
@@ -97,12 +97,12 @@ fn set_sources<'a, F: FnOnce() -> Option<&'a [NodeIndex]>>(
 }
 
 impl<'a> Diagram for PatchDiagram<'a> {
-    fn get_root(&self) -> NodeIndex {
+    fn get_root(&self) -> Option<NodeIndex> {
         self.root
     }
 
     fn set_root(&mut self, root: NodeIndex) {
-        self.root = root
+        self.root = Some(root)
     }
 
     fn insert_node(&mut self, node: Node) -> NodeIndex {
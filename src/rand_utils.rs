@@ -1,5 +1,10 @@
 use rand::Rng;
 
+use database::Database;
+use fact::Fact;
+use predicate::Predicate;
+use value::Value;
+
 pub fn choose_from_iter<R, I>(rng: &mut R, iter: I) -> Option<I::Item>
 where
     R: Rng,
@@ -16,6 +21,33 @@ where
     return result;
 }
 
+/**
+ * Generates `num_facts` facts spread uniformly over predicates `0..num_predicates`, each with
+ * `arity` values drawn uniformly from symbols `0..num_symbols`.
+ */
+pub fn random_database<R: Rng>(
+    rng: &mut R,
+    num_predicates: u64,
+    arity: usize,
+    num_symbols: u64,
+    num_facts: usize,
+) -> Database {
+    let mut db = Database::new();
+    let mut values = Vec::with_capacity(arity);
+    for _ in 0..num_facts {
+        let predicate = Predicate(rng.gen_range(0, num_predicates));
+        values.clear();
+        for _ in 0..arity {
+            values.push(Value::Symbol(rng.gen_range(0, num_symbols)));
+        }
+        db.insert_fact(Fact {
+            predicate,
+            values: &values,
+        });
+    }
+    return db;
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -45,4 +77,21 @@ mod tests {
         let mut rng = XorShiftRng::from_seed([0xde, 0xad, 0xbe, 0xef]);
         assert_eq!(Some(2), choose_from_iter(&mut rng, 0..3));
     }
+
+    #[test]
+    fn random_database_respects_parameters() {
+        let mut rng = XorShiftRng::from_seed([0xde, 0xad, 0xbe, 0xef]);
+        let db = random_database(&mut rng, 2, 3, 5, 100);
+        assert_eq!(db.len(), 100);
+        for fact in db.all_facts() {
+            assert!(fact.predicate.0 < 2);
+            assert_eq!(fact.values.len(), 3);
+            for value in fact.values {
+                match *value {
+                    Value::Symbol(s) => assert!(s < 5),
+                    Value::Nil => panic!("random databases only contain symbols"),
+                }
+            }
+        }
+    }
 }
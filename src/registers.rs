@@ -8,11 +8,31 @@ use std::ops;
 use value::Value;
 use weight::Weight;
 
+/**
+ * Registers with indices at or above the register file's size are scratch registers. Up to
+ * `MAX_SCRATCH_REGISTERS` of them are grown on demand, and are carried along with the rest of the
+ * register file, but are ignored by equality and hashing.
+ *
+ * Since a `RegisterSet` deduplicates register files (which is what makes evaluation of cyclic
+ * diagrams terminate), two states which only differ in their scratch registers are merged, and
+ * only the scratch registers of the first state to arrive are kept. They're left out of the
+ * deduplication key on purpose: a diagram which writes a fresh value into a scratch register on
+ * each trip around a cycle would otherwise never stop producing new states. Scratch registers
+ * should therefore only be used for values which are written and tested along a single path.
+ */
 #[derive(Clone, Debug)]
 pub struct RegisterFile {
     registers: Vec<Option<Value>>,
+    scratch: Vec<Option<Value>>,
 }
 
+const UNSET: &Option<Value> = &None;
+
+/**
+ * The number of scratch registers a register file can hold beyond its size.
+ */
+pub const MAX_SCRATCH_REGISTERS: usize = 64;
+
 impl PartialEq for RegisterFile {
     fn eq(&self, other: &Self) -> bool {
         self.registers.eq(&other.registers)
@@ -34,25 +54,78 @@ impl RegisterFile {
     pub fn new(size: usize) -> Self {
         RegisterFile {
             registers: iter::repeat(None).take(size).collect(),
+            scratch: Vec::new(),
         }
     }
 
     pub fn len(&self) -> usize {
         self.registers.len()
     }
+
+    /**
+     * Returns the register at `index`, or `None` if it's a scratch register which hasn't been
+     * written. Unlike indexing, this makes it clear at the call site that `index` may be out of
+     * range.
+     */
+    pub fn get(&self, index: usize) -> Option<&Option<Value>> {
+        if index < self.registers.len() {
+            self.registers.get(index)
+        } else {
+            self.scratch.get(index - self.registers.len())
+        }
+    }
+
+    /**
+     * Returns whether `index` names a register or a scratch register of this file.
+     */
+    pub fn has_register(&self, index: usize) -> bool {
+        index < self.registers.len() + MAX_SCRATCH_REGISTERS
+    }
+
+    /**
+     * Like `get`, but mutable, growing the scratch registers to reach `index` if needed. Returns
+     * `None` if `index` is beyond the last scratch register, so a malformed register number can't
+     * cause a huge allocation.
+     */
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Option<Value>> {
+        if index < self.registers.len() {
+            return self.registers.get_mut(index);
+        }
+        let scratch_index = index - self.registers.len();
+        if scratch_index >= MAX_SCRATCH_REGISTERS {
+            return None;
+        }
+        if scratch_index >= self.scratch.len() {
+            self.scratch.resize(scratch_index + 1, None);
+        }
+        self.scratch.get_mut(scratch_index)
+    }
+
+    /**
+     * Returns whether every register set in `self` has the same value in `other`, so that
+     * `other` is `self` with possibly more registers bound. Like equality, this ignores scratch
+     * registers.
+     */
+    pub fn subsumes(&self, other: &RegisterFile) -> bool {
+        self.registers
+            .iter()
+            .enumerate()
+            .all(|(index, value)| value.is_none() || other.registers.get(index) == Some(value))
+    }
 }
 
 impl ops::Index<usize> for RegisterFile {
     type Output = Option<Value>;
 
     fn index(&self, index: usize) -> &Option<Value> {
-        &self.registers[index]
+        self.get(index).unwrap_or(UNSET)
     }
 }
 
 impl ops::IndexMut<usize> for RegisterFile {
     fn index_mut(&mut self, index: usize) -> &mut Option<Value> {
-        &mut self.registers[index]
+        self.get_mut(index)
+            .expect("Register index is beyond the scratch registers")
     }
 }
 
@@ -73,10 +146,55 @@ impl State {
 
 impl Eq for State {}
 
+/**
+ * How a `RegisterSet` scales the weight of each pushed state before summing it into the stored
+ * weight.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WeightDecay {
+    None,
+    /**
+     * Divides each pushed weight by `divisor` raised to the state's depth, rounding toward zero.
+     * With a divisor larger than a diagram's branching factor, the weight a state accumulates
+     * through a recursive diagram stays bounded. Use `WeightDecay::by_depth` to construct this,
+     * since a divisor below two either does nothing or breaks the weights. If one is constructed
+     * directly anyway, it leaves weights unchanged.
+     */
+    ByDepth { divisor: i32 },
+}
+
+impl WeightDecay {
+    /**
+     * Returns `ByDepth` with the given divisor, or `None` if `divisor` is less than two.
+     */
+    pub fn by_depth(divisor: i32) -> Option<WeightDecay> {
+        if divisor < 2 {
+            None
+        } else {
+            Some(WeightDecay::ByDepth { divisor })
+        }
+    }
+
+    fn apply(self, weight: Weight, depth: usize) -> Weight {
+        match self {
+            WeightDecay::None => weight,
+            WeightDecay::ByDepth { divisor } if divisor < 2 => weight,
+            WeightDecay::ByDepth { divisor } => Weight(
+                divisor
+                    .checked_pow(depth as u32)
+                    .and_then(|scale| weight.0.checked_div(scale))
+                    .unwrap_or(0),
+            ),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct RegisterSet {
     num_registers: usize,
     states: hash_map::HashMap<RegisterFile, State>,
+    decay: WeightDecay,
+    subsumption: bool,
 }
 
 impl PartialEq for RegisterSet {
@@ -94,13 +212,64 @@ impl RegisterSet {
         RegisterSet {
             num_registers,
             states: hash_map::HashMap::new(),
+            decay: WeightDecay::None,
+            subsumption: false,
+        }
+    }
+
+    pub fn with_capacity(num_registers: usize, capacity: usize) -> Self {
+        RegisterSet {
+            num_registers,
+            states: hash_map::HashMap::with_capacity(capacity),
+            decay: WeightDecay::None,
+            subsumption: false,
         }
     }
 
+    pub fn reserve(&mut self, additional: usize) {
+        self.states.reserve(additional);
+    }
+
     pub fn num_registers(&self) -> usize {
         self.num_registers
     }
 
+    pub fn weight_decay(&self) -> WeightDecay {
+        self.decay
+    }
+
+    pub fn set_weight_decay(&mut self, decay: WeightDecay) {
+        self.decay = decay;
+    }
+
+    pub fn subsumption(&self) -> bool {
+        self.subsumption
+    }
+
+    /**
+     * With subsumption enabled, a pushed state which is subsumed by a stored state (see
+     * `RegisterFile::subsumes`) is dropped, and a pushed state removes the stored states it
+     * subsumes. This changes which states survive, so it's only sound when nothing downstream
+     * reads the extra bindings of the dropped states. Each push of a new state takes time linear
+     * in the size of the set.
+     */
+    pub fn set_subsumption(&mut self, subsumption: bool) {
+        self.subsumption = subsumption;
+    }
+
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    pub fn reset(&mut self, num_registers: usize) {
+        self.num_registers = num_registers;
+        self.states.clear();
+    }
+
     pub fn iter(&self) -> RegisterSetIter {
         RegisterSetIter {
             inner: self.states.iter(),
@@ -109,23 +278,45 @@ impl RegisterSet {
 
     /**
      * Return whether the state is *new*.
+     *
+     * A state whose summed weight reaches zero is removed. Weight decay is applied before
+     * summing, and a push whose weight decays to zero leaves an existing state's weight
+     * unchanged, so decay alone never removes a state. A new state whose weight decays to zero is
+     * still added, with a weight of one in the direction of the pushed weight, so that it remains
+     * reachable.
+     *
+     * With subsumption enabled, pushing a state subsumed by a stored state does nothing.
      */
     pub fn push(&mut self, registers: RegisterFile, weight: Weight, depth: usize) -> bool {
         assert!(self.num_registers() == registers.len());
+        if self.subsumption && !self.states.contains_key(&registers) {
+            if self.states.keys().any(|existing| existing.subsumes(&registers)) {
+                return false;
+            }
+            self.states.retain(|existing, _| !registers.subsumes(existing));
+        }
+        let decayed = self.decay.apply(weight, depth);
         match self.states.entry(registers) {
             hash_map::Entry::Occupied(mut entry) => {
                 if entry.get().depth > depth {
                     entry.get_mut().depth = depth;
                 }
-                entry.get_mut().weight.0 += weight.0;
-                if entry.get().weight.0 == 0 {
-                    entry.remove();
+                if decayed.0 != 0 {
+                    let total = entry.get().weight.saturating_add(decayed);
+                    entry.get_mut().weight = total;
+                    if entry.get().weight.0 == 0 {
+                        entry.remove();
+                    }
                 }
                 false
             }
             hash_map::Entry::Vacant(entry) => {
                 let mut state = State::zero();
-                state.weight.0 += weight.0;
+                state.weight.0 += if decayed.0 == 0 {
+                    weight.0.signum()
+                } else {
+                    decayed.0
+                };
                 state.depth = depth;
                 entry.insert(state);
                 true
@@ -150,3 +341,142 @@ impl<'a> Iterator for RegisterSetIter<'a> {
         self.inner.next().map(|(rs, s)| (rs, s.weight, s.depth))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::i32;
+
+    use super::*;
+
+    #[test]
+    fn scratch_registers_are_ignored_by_equality() {
+        let mut a = RegisterFile::new(1);
+        a[0] = Some(Value::Symbol(0));
+        let mut b = a.clone();
+        a[2] = Some(Value::Symbol(1));
+        assert_eq!(a[2], Some(Value::Symbol(1)));
+        assert_eq!(a[1], None);
+        assert_eq!(b[2], None);
+        assert_eq!(a, b);
+        let mut set = RegisterSet::new(1);
+        assert!(set.push(a, Weight(1), 0));
+        b[3] = Some(Value::Symbol(2));
+        assert!(!set.push(b, Weight(1), 0));
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    fn duplicate_weights_saturate() {
+        let mut set = RegisterSet::new(1);
+        let mut registers = RegisterFile::new(1);
+        registers[0] = Some(Value::Symbol(1));
+        assert!(set.push(registers.clone(), Weight(i32::MAX), 0));
+        assert!(!set.push(registers.clone(), Weight(i32::MAX), 0));
+        assert_eq!(set.iter().next().map(|(_, w, _)| w), Some(Weight(i32::MAX)));
+        set.push(registers.clone(), Weight(i32::MIN), 0);
+        set.push(registers.clone(), Weight(i32::MIN), 0);
+        assert_eq!(set.iter().next().map(|(_, w, _)| w), Some(Weight(i32::MIN)));
+    }
+
+    #[test]
+    fn subsumption_allows_extra_bindings() {
+        let mut general = RegisterFile::new(3);
+        general[0] = Some(Value::Symbol(1));
+        let mut specific = general.clone();
+        specific[2] = Some(Value::Symbol(2));
+        assert!(general.subsumes(&specific));
+        assert!(!specific.subsumes(&general));
+
+        let mut other = RegisterFile::new(3);
+        other[0] = Some(Value::Symbol(3));
+        other[2] = Some(Value::Symbol(2));
+        assert!(!general.subsumes(&other));
+        assert!(!other.subsumes(&general));
+
+        let mut equal = general.clone();
+        equal[4] = Some(Value::Symbol(5));
+        assert!(general.subsumes(&equal));
+        assert!(equal.subsumes(&general));
+        assert!(RegisterFile::new(3).subsumes(&specific));
+    }
+
+    #[test]
+    fn subsumption_keeps_most_general_states() {
+        let mut general = RegisterFile::new(2);
+        general[0] = Some(Value::Symbol(1));
+        let mut specific = general.clone();
+        specific[1] = Some(Value::Symbol(2));
+        let mut other = RegisterFile::new(2);
+        other[0] = Some(Value::Symbol(3));
+
+        let mut set = RegisterSet::new(2);
+        set.set_subsumption(true);
+        assert!(set.push(specific.clone(), Weight(1), 0));
+        assert!(set.push(other.clone(), Weight(1), 0));
+        assert!(set.push(general.clone(), Weight(1), 0));
+        assert!(!set.push(specific.clone(), Weight(1), 0));
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&general));
+        assert!(set.contains(&other));
+        assert!(!set.contains(&specific));
+
+        let mut plain = RegisterSet::new(2);
+        assert!(plain.push(general, Weight(1), 0));
+        assert!(plain.push(specific, Weight(1), 0));
+        assert_eq!(plain.len(), 2);
+    }
+
+    #[test]
+    fn checked_access_bounds_scratch() {
+        let mut registers = RegisterFile::new(1);
+        assert_eq!(registers.get(0), Some(&None));
+        assert_eq!(registers.get(1), None);
+        assert_eq!(registers.get_mut(1_000_000_000), None);
+        assert_eq!(registers.get_mut(1 + MAX_SCRATCH_REGISTERS), None);
+        assert_eq!(registers.get(1), None);
+        *registers.get_mut(0).unwrap() = Some(Value::Symbol(3));
+        assert_eq!(registers[0], Some(Value::Symbol(3)));
+        registers[2] = Some(Value::Symbol(4));
+        assert_eq!(registers.get(1), Some(&None));
+        assert_eq!(registers.get_mut(2), Some(&mut Some(Value::Symbol(4))));
+        assert_eq!(registers.get_mut(MAX_SCRATCH_REGISTERS), Some(&mut None));
+    }
+
+    #[test]
+    #[should_panic]
+    fn indexing_beyond_scratch_panics() {
+        let mut registers = RegisterFile::new(1);
+        registers[1 + MAX_SCRATCH_REGISTERS] = Some(Value::Symbol(1));
+    }
+
+    #[test]
+    fn weight_decay_scales_by_depth() {
+        let registers = RegisterFile::new(0);
+        let mut set = RegisterSet::new(0);
+        set.set_weight_decay(WeightDecay::ByDepth { divisor: 2 });
+        assert!(set.push(registers.clone(), Weight(8), 2));
+        assert!(!set.push(registers.clone(), Weight(8), 1));
+        assert!(!set.push(registers.clone(), Weight(1), 3));
+        assert_eq!(set.iter().next().map(|(_, w, d)| (w, d)), Some((Weight(6), 1)));
+        let mut set = RegisterSet::new(0);
+        set.set_weight_decay(WeightDecay::ByDepth { divisor: 2 });
+        assert!(set.push(registers.clone(), Weight(-1), 4));
+        assert_eq!(set.iter().next().map(|(_, w, _)| w), Some(Weight(-1)));
+    }
+
+    #[test]
+    fn weight_decay_rejects_small_divisors() {
+        assert_eq!(WeightDecay::by_depth(1), None);
+        assert_eq!(WeightDecay::by_depth(0), None);
+        assert_eq!(WeightDecay::by_depth(-2), None);
+        assert_eq!(
+            WeightDecay::by_depth(3),
+            Some(WeightDecay::ByDepth { divisor: 3 })
+        );
+        for &divisor in [0, -1, -3].iter() {
+            let decay = WeightDecay::ByDepth { divisor };
+            assert_eq!(decay.apply(Weight(5), 0), Weight(5));
+            assert_eq!(decay.apply(Weight(5), 3), Weight(5));
+        }
+    }
+}
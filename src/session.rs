@@ -0,0 +1,118 @@
+use context::Context;
+use database::Database;
+use fact::{Fact, OwnedFact};
+use graph_diagram::GraphDiagram;
+use parse::{parse_assertion, update_program, Error};
+
+/**
+ * An interactive front-end which accumulates a diagram and an input database one line at a time.
+ */
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub diagram: GraphDiagram,
+    pub context: Context,
+    pub input: Database,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExecResult {
+    Updated,
+    Asserted(OwnedFact),
+    Evaluated(Database),
+}
+
+impl Session {
+    pub fn new(num_registers: usize) -> Self {
+        Session {
+            diagram: GraphDiagram::new(num_registers),
+            context: Context::new(),
+            input: Database::new(),
+        }
+    }
+
+    /**
+     * Runs one line, which is either `evaluate`, an `assert p(...)` statement adding a fact to the
+     * input, or a program fragment as accepted by `update_program`. Match columns are resolved
+     * before evaluating, and any which can't be yet are evaluated like unbound registers.
+     */
+    pub fn exec<'a>(&mut self, line: &'a str) -> Result<ExecResult, Error<'a>> {
+        let trimmed = line.trim();
+        if trimmed == "evaluate" {
+            self.diagram.resolve_match_columns();
+            Ok(ExecResult::Evaluated(self.diagram.evaluate(&self.input)))
+        } else if trimmed.starts_with("assert ") || trimmed.starts_with("assert\t") {
+            let fact = parse_assertion(trimmed, &mut self.diagram, &mut self.context)?;
+            self.input.insert_fact(Fact {
+                predicate: fact.predicate,
+                values: &fact.values,
+            });
+            Ok(ExecResult::Asserted(fact))
+        } else {
+            update_program(
+                trimmed,
+                &mut self.diagram,
+                &mut self.context,
+                &mut self.input,
+            )?;
+            Ok(ExecResult::Updated)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::database_literal;
+    use diagram::Diagram;
+    use value::Value;
+
+    #[test]
+    fn can_define_assert_and_evaluate() {
+        let mut session = Session::new(1);
+        assert_eq!(
+            session.exec("root: edge(_ -> %0, :2) { output source(%0) }"),
+            Ok(ExecResult::Updated)
+        );
+        let edge = session.context.predicate_name_to_predicate["edge"];
+        let source = session.context.predicate_name_to_predicate["source"];
+        assert_eq!(
+            session.exec("assert edge(:1, :2)"),
+            Ok(ExecResult::Asserted(OwnedFact {
+                predicate: edge,
+                values: vec![Value::Symbol(1), Value::Symbol(2)],
+            }))
+        );
+        assert_eq!(
+            session.exec("  assert edge(:3, :4)  "),
+            Ok(ExecResult::Asserted(OwnedFact {
+                predicate: edge,
+                values: vec![Value::Symbol(3), Value::Symbol(4)],
+            }))
+        );
+        assert_eq!(
+            session.exec("evaluate"),
+            Ok(ExecResult::Evaluated(database_literal(vec![
+                (source, vec![Value::Symbol(1)])
+            ])))
+        );
+        assert!(session.exec("assert edge(:1").is_err());
+    }
+
+    #[test]
+    fn evaluating_resolves_match_columns() {
+        let mut session = Session::new(0);
+        assert_eq!(
+            session.exec("root: edge(_, :2) { output source($0) }"),
+            Ok(ExecResult::Updated)
+        );
+        let source = session.context.predicate_name_to_predicate["source"];
+        assert!(session.exec("assert edge(:1, :2)").is_ok());
+        assert_eq!(
+            session.exec("evaluate"),
+            Ok(ExecResult::Evaluated(database_literal(vec![
+                (source, vec![Value::Symbol(1)])
+            ])))
+        );
+        assert_eq!(session.diagram.get_num_registers(), 1);
+    }
+}
@@ -5,6 +5,7 @@ use value::Value;
 #[derive(Clone, Debug)]
 pub enum SimpleQueryTerm<'a> {
     Constant { value: &'a Value },
+    NotConstant { value: &'a Value },
     Free,
 }
 
@@ -22,7 +23,107 @@ impl<'a, 'b: 'a> SimpleQuery<'a, 'b> {
                 .zip(fact.values.iter())
                 .all(|(term, ref v)| match *term {
                     SimpleQueryTerm::Constant { ref value } => v == value,
+                    SimpleQueryTerm::NotConstant { ref value } => v != value,
                     SimpleQueryTerm::Free => true,
                 })
     }
 }
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OwnedSimpleQueryTerm {
+    Constant(Value),
+    NotConstant(Value),
+    Free,
+}
+
+impl OwnedSimpleQueryTerm {
+    pub fn as_term(&self) -> SimpleQueryTerm {
+        match *self {
+            OwnedSimpleQueryTerm::Constant(ref value) => SimpleQueryTerm::Constant { value },
+            OwnedSimpleQueryTerm::NotConstant(ref value) => SimpleQueryTerm::NotConstant { value },
+            OwnedSimpleQueryTerm::Free => SimpleQueryTerm::Free,
+        }
+    }
+}
+
+/**
+ * A `SimpleQuery` which owns its terms, so it can be stored or returned freely. Use `with_query`
+ * to borrow it as a `SimpleQuery`, or `Database::owned_simple_query` to run it directly.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OwnedSimpleQuery {
+    pub predicate: Predicate,
+    pub terms: Vec<OwnedSimpleQueryTerm>,
+}
+
+impl OwnedSimpleQuery {
+    pub fn query_terms(&self) -> Vec<SimpleQueryTerm> {
+        self.terms.iter().map(|term| term.as_term()).collect()
+    }
+
+    /**
+     * Calls `f` with this query borrowed as a `SimpleQuery`.
+     */
+    pub fn with_query<R, F>(&self, f: F) -> R
+    where
+        F: for<'a, 'b> FnOnce(SimpleQuery<'a, 'b>) -> R,
+    {
+        let terms = self.query_terms();
+        f(SimpleQuery {
+            predicate: self.predicate,
+            terms: &terms,
+        })
+    }
+
+    pub fn matches(&self, fact: Fact) -> bool {
+        self.with_query(|query| query.matches(fact))
+    }
+}
+
+/**
+ * Builds an `OwnedSimpleQuery` one column at a time, in any order. Columns which aren't given a
+ * term are free. Giving a column a second term replaces the first. Giving a term to a column
+ * beyond the predicate's arity panics, since the query would otherwise silently ignore it.
+ */
+#[derive(Clone, Debug)]
+pub struct SimpleQueryBuilder {
+    predicate: Predicate,
+    terms: Vec<OwnedSimpleQueryTerm>,
+}
+
+impl SimpleQueryBuilder {
+    pub fn new(predicate: Predicate, arity: usize) -> Self {
+        SimpleQueryBuilder {
+            predicate,
+            terms: vec![OwnedSimpleQueryTerm::Free; arity],
+        }
+    }
+
+    fn set(mut self, column: usize, term: OwnedSimpleQueryTerm) -> Self {
+        assert!(
+            column < self.terms.len(),
+            "Query column is beyond the predicate's arity"
+        );
+        self.terms[column] = term;
+        self
+    }
+
+    pub fn eq(self, column: usize, value: Value) -> Self {
+        self.set(column, OwnedSimpleQueryTerm::Constant(value))
+    }
+
+    pub fn not_eq(self, column: usize, value: Value) -> Self {
+        self.set(column, OwnedSimpleQueryTerm::NotConstant(value))
+    }
+
+    pub fn free(self, column: usize) -> Self {
+        self.set(column, OwnedSimpleQueryTerm::Free)
+    }
+
+    pub fn build(self) -> OwnedSimpleQuery {
+        OwnedSimpleQuery {
+            predicate: self.predicate,
+            terms: self.terms,
+        }
+    }
+}
@@ -6,10 +6,11 @@ use std::iter;
 use database::Database;
 use diagram::{Diagram, DiagramSpace, MultiDiagram, Node, OutputTerm};
 use evaluation::Evaluation;
-use frame::Frame;
+use frame::{Frame, FrameError};
 use gen_mutation::{GenMutation, IndividualMutationState, UniformMutationContext};
 use graph_diagram::GraphDiagram;
 use mutate::{apply_mutation, MutationResult};
+use mutation::Mutation;
 use node_index::NodeIndex;
 use predicate::Predicate;
 use value::Value;
@@ -19,11 +20,42 @@ pub struct DiagramIndividual {
     pub diagram: GraphDiagram,
     pub evaluations: Vec<Evaluation>,
     pub fitness: i64,
+    /**
+     * The cost of each sample when the individual was last scored. `fitness` is the negated sum,
+     * less any size penalty.
+     */
+    pub sample_costs: Vec<i64>,
     pub mutation_state: IndividualMutationState,
+    /**
+     * Every mutation applied to this individual, in order, if recording is enabled.
+     */
+    pub mutation_log: Option<Vec<Mutation>>,
 }
 
 impl DiagramIndividual {
-    fn blank(num_evaluations: usize, num_registers: usize) -> DiagramIndividual {
+    /**
+     * Adopts an existing diagram, for warm starting evolution. The fitness is left at its minimum
+     * until the individual is rescored.
+     */
+    pub fn from_diagram(diagram: GraphDiagram, num_evaluations: usize) -> DiagramIndividual {
+        let evaluations = iter::repeat(Evaluation::new())
+            .take(num_evaluations)
+            .collect();
+        DiagramIndividual {
+            diagram,
+            evaluations,
+            fitness: i64::min_value(),
+            sample_costs: Vec::new(),
+            mutation_state: IndividualMutationState::new(),
+            mutation_log: None,
+        }
+    }
+
+    fn blank(
+        num_evaluations: usize,
+        num_registers: usize,
+        record_mutations: bool,
+    ) -> DiagramIndividual {
         let diagram = GraphDiagram::new(num_registers);
 
         let evaluations = iter::repeat(Evaluation::new())
@@ -33,7 +65,13 @@ impl DiagramIndividual {
             diagram,
             evaluations,
             fitness: i64::min_value(),
+            sample_costs: Vec::new(),
             mutation_state: IndividualMutationState::new(),
+            mutation_log: if record_mutations {
+                Some(Vec::new())
+            } else {
+                None
+            },
         }
     }
 }
@@ -46,6 +84,15 @@ pub struct StepProblem {
     num_registers: usize,
     num_nodes: usize,
     num_0_terms: usize,
+    // Breaking fitness ties in favor of smaller diagrams makes selection deterministic, but also
+    // prevents neutral drift through larger diagrams, which some problems need in order to make
+    // progress. On by default.
+    prefer_smaller_diagrams: bool,
+    record_mutations: bool,
+    // Subtracted from the fitness once per reachable node, trading accuracy for parsimony.
+    size_penalty: i64,
+    // Diagrams which replace the first blank individuals of the initial population.
+    seeds: Vec<GraphDiagram>,
 }
 
 fn db_cost(expected: &Database, actual: &Database) -> i64 {
@@ -64,21 +111,94 @@ fn db_cost(expected: &Database, actual: &Database) -> i64 {
 }
 
 impl StepProblem {
+    /**
+     * Creates a problem of finding a diagram which maps the input of each sample to its output.
+     * Ties in fitness are broken in favor of smaller diagrams, see `set_prefer_smaller_diagrams`.
+     * Fails if a predicate has different numbers of terms across the samples.
+     */
+    pub fn new(
+        samples: Vec<(Database, Database)>,
+        space: DiagramSpace,
+        num_registers: usize,
+        num_nodes: usize,
+        num_0_terms: usize,
+    ) -> Result<Self, FrameError> {
+        Ok(StepProblem {
+            frame: Frame::from_samples(&samples)?,
+            samples,
+            space,
+            num_registers,
+            num_nodes,
+            num_0_terms,
+            prefer_smaller_diagrams: true,
+            record_mutations: false,
+            size_penalty: 0,
+            seeds: Vec::new(),
+        })
+    }
+
+    /**
+     * Sets whether individuals of equal fitness are ordered by their number of reachable nodes.
+     * Without this they compare equal, and selection between them is arbitrary.
+     */
+    pub fn set_prefer_smaller_diagrams(&mut self, prefer_smaller_diagrams: bool) {
+        self.prefer_smaller_diagrams = prefer_smaller_diagrams;
+    }
+
+    /**
+     * Sets whether each individual keeps a log of the mutations which produced it. Off by
+     * default, since the logs grow with every generation.
+     */
+    pub fn set_record_mutations(&mut self, record_mutations: bool) {
+        self.record_mutations = record_mutations;
+    }
+
+    /**
+     * Sets the fitness subtracted per reachable node of a diagram. Zero, the default, leaves the
+     * fitness as the cost over the samples alone.
+     */
+    pub fn set_size_penalty(&mut self, size_penalty: i64) {
+        self.size_penalty = size_penalty;
+    }
+
+    /**
+     * Adds a diagram to start the search from. Seeds replace the first blank individuals of the
+     * initial population, in the order they were added.
+     */
+    pub fn add_seed(&mut self, seed: GraphDiagram) {
+        self.seeds.push(seed);
+    }
+
+    /**
+     * Replaces every seed with `seeds`, see `add_seed`.
+     */
+    pub fn set_seeds(&mut self, seeds: Vec<GraphDiagram>) {
+        self.seeds = seeds;
+    }
+
     fn rescore(&self, individual: &mut DiagramIndividual, start: Option<NodeIndex>) {
         let mut fitness = 0;
+        individual.sample_costs.clear();
         for ((input, output), eval) in self.samples
             .iter()
             .map(|&(ref i, ref o)| (i, o))
             .zip(individual.evaluations.iter_mut())
         {
-            if let Some(result) = if let Some(start) = start {
-                eval.rerun_from(&individual.diagram, input, &[start], self.num_registers)
+            if let Some(start) = start {
+                if let Some(result) =
+                    eval.rerun_from(&individual.diagram, input, &[start], self.num_registers)
+                {
+                    *eval = result;
+                }
             } else {
-                eval.rerun_from(&individual.diagram, input, &[], self.num_registers)
-            } {
-                *eval = result;
+                eval.rerun(&individual.diagram, input, self.num_registers);
             }
-            fitness -= db_cost(output, &eval.total_db);
+            let cost = db_cost(output, &eval.total_db);
+            individual.sample_costs.push(cost);
+            fitness -= cost;
+        }
+        if self.size_penalty != 0 {
+            fitness -= self.size_penalty * individual.diagram.reachable_nodes().len() as i64;
         }
         individual.fitness = fitness;
     }
@@ -89,6 +209,13 @@ impl StepProblem {
                 UniformMutationContext::new(&self.frame, &self.space, &individual.diagram);
             context.gen_mutation(&mut individual.mutation_state, rng)
         };
+        self.apply_and_rescore(individual, mutation)
+    }
+
+    fn apply_and_rescore(&self, individual: &mut DiagramIndividual, mutation: Mutation) -> bool {
+        if let Some(ref mut log) = individual.mutation_log {
+            log.push(mutation.clone());
+        }
         if let Some(MutationResult {
             phenotype_could_have_changed,
             node_to_restart,
@@ -105,6 +232,20 @@ impl StepProblem {
         }
         return false;
     }
+
+    /**
+     * Applies `log` to a blank individual, reproducing the individual which recorded it. The log
+     * only reproduces the original run if this problem has the same samples, `Frame`, and
+     * `DiagramSpace` as the problem which recorded it.
+     */
+    pub fn replay(&self, log: &[Mutation]) -> DiagramIndividual {
+        let mut individual =
+            DiagramIndividual::blank(self.samples.len(), self.num_registers, true);
+        for mutation in log {
+            self.apply_and_rescore(&mut individual, mutation.clone());
+        }
+        individual
+    }
 }
 
 impl Problem for StepProblem {
@@ -114,9 +255,27 @@ impl Problem for StepProblem {
     where
         R: Rng,
     {
-        (0..count)
-            .map(|_| DiagramIndividual::blank(self.samples.len(), self.num_registers))
-            .collect()
+        let mut population: Vec<_> = self.seeds
+            .iter()
+            .take(count)
+            .map(|seed| {
+                let mut individual =
+                    DiagramIndividual::from_diagram(seed.clone(), self.samples.len());
+                if self.record_mutations {
+                    individual.mutation_log = Some(Vec::new());
+                }
+                self.rescore(&mut individual, None);
+                individual
+            })
+            .collect();
+        while population.len() < count {
+            population.push(DiagramIndividual::blank(
+                self.samples.len(),
+                self.num_registers,
+                self.record_mutations,
+            ));
+        }
+        population
     }
 
     fn mutate<R>(&self, individual: &mut Self::Individual, rng: &mut R) -> bool
@@ -135,7 +294,14 @@ impl Problem for StepProblem {
     where
         R: Rng,
     {
-        a.fitness.partial_cmp(&b.fitness)
+        match a.fitness.partial_cmp(&b.fitness) {
+            Some(Ordering::Equal) if self.prefer_smaller_diagrams => {
+                let a_size = a.diagram.reachable_nodes().len();
+                let b_size = b.diagram.reachable_nodes().len();
+                Some(b_size.cmp(&a_size))
+            }
+            ordering => ordering,
+        }
     }
 }
 
@@ -144,11 +310,24 @@ mod tests {
     use super::*;
     use database::database_literal;
     use evolution_strategies::{Engine, Strategy};
+    use parse::parse_diagram;
     use predicate::Predicate;
     use rand::SeedableRng;
     use rand::XorShiftRng;
     use value::Value;
 
+    /**
+     * A problem with room for two nodes using one register, and the default settings.
+     */
+    fn test_problem(samples: Vec<(Database, Database)>) -> StepProblem {
+        let space = DiagramSpace {
+            num_nodes: 3,
+            num_terms: 1,
+            num_registers: 1,
+        };
+        StepProblem::new(samples, space, 1, 2, 1).unwrap()
+    }
+
     #[test]
     fn evolve_simple_copy() {
         let rng = XorShiftRng::from_seed([0xba, 0xeb, 0xae, 0xee]);
@@ -185,6 +364,10 @@ mod tests {
             num_registers: 1,
             num_nodes: 2,
             num_0_terms: 1,
+            prefer_smaller_diagrams: false,
+            record_mutations: false,
+            size_penalty: 0,
+            seeds: vec![],
         };
         // Note that the numbers here can be increased if they cause test failures.
         let strategy = Strategy::MuLambda {
@@ -211,4 +394,154 @@ mod tests {
         }
         assert_eq!(engine.fitest().fitness, 0);
     }
+
+    #[test]
+    fn compare_prefers_smaller_diagram_on_tie() {
+        let mut rng = XorShiftRng::from_seed([0xde, 0xad, 0xbe, 0xef]);
+        let mut problem = test_problem(vec![]);
+        let mut small = DiagramIndividual::blank(0, 1, false);
+        small.diagram = parse_diagram("root: output @0(:0)", 1).unwrap().0;
+        small.fitness = -1;
+        let mut large = DiagramIndividual::blank(0, 1, false);
+        large.diagram = parse_diagram("root: @0(_) { output @0(:0) }", 1).unwrap().0;
+        large.fitness = -1;
+        assert_eq!(
+            problem.compare(&small, &large, &mut rng),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(
+            problem.compare(&large, &small, &mut rng),
+            Some(Ordering::Less)
+        );
+        large.fitness = 0;
+        assert_eq!(
+            problem.compare(&small, &large, &mut rng),
+            Some(Ordering::Less)
+        );
+        large.fitness = -1;
+        problem.set_prefer_smaller_diagrams(false);
+        assert_eq!(
+            problem.compare(&small, &large, &mut rng),
+            Some(Ordering::Equal)
+        );
+    }
+
+    #[test]
+    fn replay_reproduces_individual() {
+        let mut rng = XorShiftRng::from_seed([0xde, 0xad, 0xbe, 0xef]);
+        let samples = vec![
+            (
+                database_literal(vec![(Predicate(0), vec![Value::Symbol(0)])]),
+                database_literal(vec![(Predicate(1), vec![Value::Symbol(0)])]),
+            ),
+        ];
+        let mut problem = test_problem(samples);
+        problem.set_record_mutations(true);
+        let mut individual = problem.initialize(1, &mut rng).pop().unwrap();
+        for _ in 0..20 {
+            problem.mutate(&mut individual, &mut rng);
+        }
+        let log = individual.mutation_log.clone().unwrap();
+        assert_eq!(log.len(), 20);
+        let replayed = problem.replay(&log);
+        assert_eq!(replayed.diagram, individual.diagram);
+        assert_eq!(replayed.fitness, individual.fitness);
+    }
+
+    #[test]
+    fn seeded_individuals_are_rescored() {
+        let mut rng = XorShiftRng::from_seed([0xde, 0xad, 0xbe, 0xef]);
+        let samples = vec![
+            (
+                database_literal(vec![(Predicate(0), vec![Value::Symbol(0)])]),
+                database_literal(vec![(Predicate(1), vec![Value::Symbol(0)])]),
+            ),
+            (
+                database_literal(vec![(Predicate(0), vec![Value::Symbol(1)])]),
+                database_literal(vec![(Predicate(1), vec![Value::Symbol(1)])]),
+            ),
+        ];
+        let champion = parse_diagram("root: @0(_ -> %0) { output @1(%0) }", 1)
+            .unwrap()
+            .0;
+        let mut problem = test_problem(samples);
+        problem.add_seed(champion.clone());
+        let seeded = DiagramIndividual::from_diagram(champion.clone(), 2);
+        assert_eq!(seeded.fitness, i64::min_value());
+        assert_eq!(seeded.evaluations.len(), 2);
+
+        let population = problem.initialize(3, &mut rng);
+        assert_eq!(population.len(), 3);
+        assert_eq!(population[0].diagram, champion);
+        assert_eq!(population[0].fitness, 0);
+        assert_eq!(population[1].diagram, GraphDiagram::new(1));
+        assert_eq!(population[1].fitness, i64::min_value());
+        assert_eq!(
+            problem.compare(&population[0], &population[1], &mut rng),
+            Some(Ordering::Greater)
+        );
+    }
+
+    #[test]
+    fn rescore_records_sample_costs() {
+        let samples = vec![
+            (
+                database_literal(vec![(Predicate(0), vec![Value::Symbol(0)])]),
+                database_literal(vec![(Predicate(1), vec![Value::Symbol(0)])]),
+            ),
+            (
+                database_literal(vec![(Predicate(0), vec![Value::Symbol(1)])]),
+                database_literal(vec![
+                    (Predicate(1), vec![Value::Symbol(1)]),
+                    (Predicate(1), vec![Value::Symbol(2)]),
+                ]),
+            ),
+            (
+                database_literal(vec![(Predicate(0), vec![Value::Symbol(2)])]),
+                database_literal(vec![(Predicate(1), vec![Value::Symbol(0)])]),
+            ),
+        ];
+        let problem = test_problem(samples);
+        let diagram = parse_diagram("root: @0(_ -> %0) { output @1(%0) }", 1)
+            .unwrap()
+            .0;
+        let mut individual = DiagramIndividual::from_diagram(diagram, 3);
+        problem.rescore(&mut individual, None);
+        let expected: Vec<i64> = problem
+            .samples
+            .iter()
+            .zip(individual.evaluations.iter())
+            .map(|(&(_, ref output), eval)| db_cost(output, &eval.total_db))
+            .collect();
+        assert_eq!(expected, vec![0, 2, 3]);
+        assert_eq!(individual.sample_costs, expected);
+        assert_eq!(individual.fitness, -5);
+    }
+
+    #[test]
+    fn size_penalty_ranks_equally_accurate_diagrams() {
+        let mut rng = XorShiftRng::from_seed([0xde, 0xad, 0xbe, 0xef]);
+        let samples = vec![
+            (
+                database_literal(vec![(Predicate(0), vec![Value::Symbol(0)])]),
+                database_literal(vec![(Predicate(1), vec![Value::Symbol(0)])]),
+            ),
+        ];
+        let mut problem = test_problem(samples);
+        problem.set_size_penalty(1);
+        let small = parse_diagram("root: output @1(:0)", 1).unwrap().0;
+        let large = parse_diagram("root: @0(_) { output @1(:0) }", 1).unwrap().0;
+        let mut small = DiagramIndividual::from_diagram(small, 1);
+        let mut large = DiagramIndividual::from_diagram(large, 1);
+        problem.rescore(&mut small, None);
+        problem.rescore(&mut large, None);
+        assert_eq!(small.sample_costs, vec![0]);
+        assert_eq!(large.sample_costs, vec![0]);
+        assert_eq!(small.fitness, -1);
+        assert_eq!(large.fitness, -2);
+        assert_eq!(
+            problem.compare(&small, &large, &mut rng),
+            Some(Ordering::Greater)
+        );
+    }
 }
@@ -1,14 +1,44 @@
-use value::Value;
+use std::collections::{hash_map, HashMap};
+use std::hash::{Hash, Hasher};
+
+use index::{Index, IndexIter};
+use value::{Value, ValueKind};
 use weight::Weight;
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug)]
 pub struct Table {
     num_columns: usize,
     num_rows: usize,
     values: Vec<Value>,
     row_weights: Vec<Weight>,
+    indices: Vec<Index>,
+    column_kinds: Option<Vec<Option<ValueKind>>>,
+    // Set whenever a row might have a negative weight, so `add_weight` knows whether a positive
+    // weight could cancel against an existing row.
+    may_have_negative_rows: bool,
+    // The rows with each hash of their values, built the first time `add_weight` needs to find
+    // rows to cancel against, and kept up to date after that.
+    rows_by_hash: Option<HashMap<u64, Vec<usize>>>,
+}
+
+fn row_hash(row: &[Value]) -> u64 {
+    let mut hasher = hash_map::DefaultHasher::new();
+    row.hash(&mut hasher);
+    hasher.finish()
 }
 
+// Indices and `rows_by_hash` only accelerate lookups, so they don't take part in equality.
+impl PartialEq for Table {
+    fn eq(&self, other: &Self) -> bool {
+        self.num_columns == other.num_columns
+            && self.num_rows == other.num_rows
+            && self.values == other.values
+            && self.row_weights == other.row_weights
+    }
+}
+
+impl Eq for Table {}
+
 impl Table {
     pub fn new(num_columns: usize) -> Self {
         Table {
@@ -16,9 +46,59 @@ impl Table {
             num_rows: 0,
             values: Vec::new(),
             row_weights: Vec::new(),
+            indices: Vec::new(),
+            column_kinds: None,
+            may_have_negative_rows: false,
+            rows_by_hash: None,
+        }
+    }
+
+    /**
+     * Enables per-column kind checking. Each column's kind is established by the first value
+     * stored in it, and later rows must match.
+     */
+    pub fn enforce_column_kinds(&mut self) {
+        let mut kinds = vec![None; self.num_columns];
+        for row in 0..self.num_rows {
+            for (kind, value) in kinds.iter_mut().zip(self.row(row).iter()) {
+                if kind.is_none() {
+                    *kind = Some(value.kind());
+                }
+            }
+        }
+        self.column_kinds = Some(kinds);
+    }
+
+    pub fn column_kind(&self, column: usize) -> Option<ValueKind> {
+        self.column_kinds.as_ref().and_then(|kinds| kinds[column])
+    }
+
+    pub fn row_matches_kinds(&self, row: &[Value]) -> bool {
+        if let Some(ref kinds) = self.column_kinds {
+            kinds
+                .iter()
+                .zip(row.iter())
+                .all(|(kind, value)| match *kind {
+                    Some(kind) => value.kind() == kind,
+                    None => true,
+                })
+        } else {
+            true
+        }
+    }
+
+    pub fn try_push(&mut self, row: &[Value], weight: Weight) -> Option<usize> {
+        if row.len() == self.num_columns && self.row_matches_kinds(row) {
+            Some(self.push(row, weight))
+        } else {
+            None
         }
     }
 
+    pub fn num_columns(&self) -> usize {
+        self.num_columns
+    }
+
     pub fn num_rows(&self) -> usize {
         self.num_rows
     }
@@ -28,6 +108,7 @@ impl Table {
     }
 
     pub fn weight_mut(&mut self, row: usize) -> &mut Weight {
+        self.may_have_negative_rows = true;
         &mut self.row_weights[row]
     }
 
@@ -45,20 +126,260 @@ impl Table {
 
     pub fn push(&mut self, row: &[Value], weight: Weight) -> usize {
         assert!(row.len() == self.num_columns);
+        assert!(
+            self.row_matches_kinds(row),
+            "Row does not match the table's column kinds"
+        );
+        if let Some(ref mut kinds) = self.column_kinds {
+            for (kind, value) in kinds.iter_mut().zip(row.iter()) {
+                if kind.is_none() {
+                    *kind = Some(value.kind());
+                }
+            }
+        }
         self.values.extend_from_slice(row);
         self.row_weights.push(weight);
+        self.may_have_negative_rows |= weight.0 < 0;
         let result = self.num_rows;
         self.num_rows += 1;
+        for index in self.indices.iter_mut() {
+            index.add_row(row, result);
+        }
+        if let Some(ref mut rows_by_hash) = self.rows_by_hash {
+            rows_by_hash.entry(row_hash(row)).or_insert_with(Vec::new).push(result);
+        }
         result
     }
 
+    pub fn push_many(&mut self, rows: &[&[Value]], weights: &[Weight]) {
+        assert!(rows.len() == weights.len());
+        self.values.reserve(rows.len() * self.num_columns);
+        self.row_weights.reserve(rows.len());
+        for (row, weight) in rows.iter().zip(weights.iter()) {
+            self.push(row, *weight);
+        }
+    }
+
+    /**
+     * Adds each weight to its row, like `add_weight`. If no weight can cancel, the rows are pushed
+     * in one pass with `push_many`.
+     */
+    pub fn add_weights(&mut self, rows: &[&[Value]], weights: &[Weight]) {
+        assert!(rows.len() == weights.len());
+        if !self.may_have_negative_rows && weights.iter().all(|weight| weight.0 > 0) {
+            self.push_many(rows, weights);
+            return;
+        }
+        self.values.reserve(rows.len() * self.num_columns);
+        self.row_weights.reserve(rows.len());
+        for (row, weight) in rows.iter().zip(weights.iter()) {
+            self.add_weight(row, *weight);
+        }
+    }
+
+    /**
+     * Adds `weight` to the rows with values `row`, cancelling it against rows of the opposite
+     * sign first. Rows whose weight reaches zero are removed, see `remove_row`, and any weight
+     * left over is pushed as a new row, so a negative weight with nothing to cancel is kept until
+     * a positive weight cancels it. Rows to cancel against are found by hash, so this doesn't
+     * scan the table.
+     */
+    pub fn add_weight(&mut self, row: &[Value], weight: Weight) {
+        let mut weight = weight;
+        if weight.0 < 0 || self.may_have_negative_rows {
+            let hash = row_hash(row);
+            while !weight.is_zero() {
+                let opposite = self.rows_with_hash(hash).into_iter().find(|&row_index| {
+                    let existing = self.weight(row_index);
+                    !existing.is_zero() && (existing.0 < 0) != (weight.0 < 0)
+                        && self.row(row_index) == row
+                });
+                let row_index = match opposite {
+                    Some(row_index) => row_index,
+                    None => break,
+                };
+                let existing = self.weight(row_index);
+                let total = existing.saturating_add(weight);
+                if !total.is_zero() && (total.0 < 0) == (existing.0 < 0) {
+                    self.row_weights[row_index] = total;
+                    weight = Weight(0);
+                } else {
+                    self.remove_row(row_index);
+                    weight = total;
+                }
+            }
+        }
+        if !weight.is_zero() {
+            self.push(row, weight);
+        }
+    }
+
+    fn rows_with_hash(&mut self, hash: u64) -> Vec<usize> {
+        if self.rows_by_hash.is_none() {
+            let mut rows_by_hash: HashMap<u64, Vec<usize>> = HashMap::new();
+            for row in 0..self.num_rows {
+                rows_by_hash.entry(row_hash(self.row(row))).or_insert_with(Vec::new).push(row);
+            }
+            self.rows_by_hash = Some(rows_by_hash);
+        }
+        self.rows_by_hash
+            .as_ref()
+            .and_then(|rows_by_hash| rows_by_hash.get(&hash))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /**
+     * Removes a single row, moving the last row into its place.
+     */
+    pub fn remove_row(&mut self, row: usize) {
+        let last = self.num_rows - 1;
+        if let Some(mut rows_by_hash) = self.rows_by_hash.take() {
+            let removed_hash = row_hash(self.row(row));
+            let last_hash = row_hash(self.row(last));
+            if let hash_map::Entry::Occupied(mut entry) = rows_by_hash.entry(removed_hash) {
+                entry.get_mut().retain(|&r| r != row);
+                if entry.get().is_empty() {
+                    entry.remove();
+                }
+            }
+            if let Some(rows) = rows_by_hash.get_mut(&last_hash) {
+                for r in rows.iter_mut().filter(|r| **r == last) {
+                    *r = row;
+                }
+            }
+            self.rows_by_hash = Some(rows_by_hash);
+        }
+        for column in 0..self.num_columns {
+            self.values
+                .swap(row * self.num_columns + column, last * self.num_columns + column);
+        }
+        self.values.truncate(last * self.num_columns);
+        self.row_weights.swap_remove(row);
+        self.num_rows = last;
+        for index in self.indices.iter_mut() {
+            index.remove_row(row, last);
+        }
+    }
+
+    /**
+     * Drops every row whose values have a total weight of zero across the table, so that
+     * cancelled facts are no longer found. `add_weight` already removes rows as they cancel, so
+     * this is only needed after pushing rows or changing weights directly. Indices are rebuilt.
+     */
+    pub fn normalize(&mut self) {
+        let keep: Vec<bool> = {
+            let mut totals: HashMap<&[Value], Weight> = HashMap::new();
+            for (row, weight) in self.weighted_rows() {
+                let total = totals.entry(row).or_insert(Weight(0));
+                *total = total.saturating_add(weight);
+            }
+            self.weighted_rows()
+                .map(|(row, _)| !totals[row].is_zero())
+                .collect()
+        };
+        if keep.iter().all(|&k| k) {
+            return;
+        }
+        let mut values = Vec::new();
+        let mut row_weights = Vec::new();
+        for (row, row_values, weight) in self.enumerate_rows() {
+            if keep[row] {
+                values.extend_from_slice(row_values);
+                row_weights.push(weight);
+            }
+        }
+        self.replace_rows(values, row_weights);
+    }
+
+    /**
+     * Merges rows with equal values into a single row carrying their total weight, drops rows
+     * whose total is zero, and releases any spare capacity. Indices are rebuilt.
+     */
+    pub fn compact(&mut self) {
+        let (values, row_weights) = {
+            let mut row_for_values: HashMap<&[Value], usize> = HashMap::new();
+            let mut rows: Vec<&[Value]> = Vec::new();
+            let mut totals: Vec<Weight> = Vec::new();
+            for (row, weight) in self.weighted_rows() {
+                match row_for_values.entry(row) {
+                    hash_map::Entry::Occupied(entry) => {
+                        let total = &mut totals[*entry.get()];
+                        *total = total.saturating_add(weight);
+                    }
+                    hash_map::Entry::Vacant(entry) => {
+                        entry.insert(rows.len());
+                        rows.push(row);
+                        totals.push(weight);
+                    }
+                }
+            }
+            let mut values = Vec::new();
+            let mut row_weights = Vec::new();
+            for (row, total) in rows.into_iter().zip(totals) {
+                if !total.is_zero() {
+                    values.extend_from_slice(row);
+                    row_weights.push(total);
+                }
+            }
+            (values, row_weights)
+        };
+        self.replace_rows(values, row_weights);
+        self.values.shrink_to_fit();
+        self.row_weights.shrink_to_fit();
+        self.indices.shrink_to_fit();
+    }
+
+    fn replace_rows(&mut self, values: Vec<Value>, row_weights: Vec<Weight>) {
+        self.num_rows = row_weights.len();
+        self.values = values;
+        self.row_weights = row_weights;
+        self.may_have_negative_rows = self.row_weights.iter().any(|weight| weight.0 < 0);
+        self.rows_by_hash = None;
+        let indices: Vec<Index> = self.indices.drain(..).collect();
+        for index in indices {
+            self.add_index(index.column(), index.value().clone());
+        }
+    }
+
+    pub fn add_index(&mut self, column: usize, value: Value) {
+        assert!(column < self.num_columns);
+        if self.get_index(column, &value).is_some() {
+            return;
+        }
+        let mut index = Index::new(column, value);
+        for row in 0..self.num_rows {
+            index.add_row(self.row(row), row);
+        }
+        self.indices.push(index);
+    }
+
+    pub fn get_index(&self, column: usize, value: &Value) -> Option<&Index> {
+        self.indices
+            .iter()
+            .find(|index| index.column() == column && index.value() == value)
+    }
+
     pub fn iter(&self) -> Iter {
         Iter {
             table: self,
             row: 0,
+            index_rows: None,
         }
     }
 
+    pub fn iter_index(&self, column: usize, value: &Value) -> Option<Iter> {
+        self.get_index(column, value).map(|index| Iter {
+            table: self,
+            row: 0,
+            index_rows: Some(index.iter()),
+        })
+    }
+
+    pub fn enumerate_rows<'a>(&'a self) -> impl Iterator<Item = (usize, &'a [Value], Weight)> + 'a {
+        (0..self.num_rows).map(move |row| (row, self.row(row), self.weight(row)))
+    }
+
     pub fn weighted_rows(&self) -> WeightedRows {
         WeightedRows {
             table: self,
@@ -71,12 +392,16 @@ impl Table {
 pub struct Iter<'a> {
     table: &'a Table,
     row: usize,
+    index_rows: Option<IndexIter<'a>>,
 }
 
 impl<'a> Iterator for Iter<'a> {
     type Item = &'a [Value];
 
     fn next(&mut self) -> Option<Self::Item> {
+        if let Some(ref mut index_rows) = self.index_rows {
+            return index_rows.next().map(|row| self.table.row(row));
+        }
         if self.row < self.table.num_rows() {
             let result = self.table.row(self.row);
             self.row += 1;
@@ -107,3 +432,106 @@ impl<'a> Iterator for WeightedRows<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn untyped_table_accepts_mixed_kinds() {
+        let mut table = Table::new(1);
+        table.push(&[Value::Symbol(1)], Weight(1));
+        table.push(&[Value::Nil], Weight(1));
+        assert_eq!(table.num_rows(), 2);
+        assert_eq!(table.column_kind(0), None);
+    }
+
+    #[test]
+    fn typed_table_rejects_mismatched_kinds() {
+        let mut table = Table::new(2);
+        table.enforce_column_kinds();
+        assert_eq!(table.column_kind(0), None);
+        assert_eq!(
+            table.try_push(&[Value::Symbol(1), Value::Nil], Weight(1)),
+            Some(0)
+        );
+        assert_eq!(table.column_kind(0), Some(ValueKind::Symbol));
+        assert_eq!(table.column_kind(1), Some(ValueKind::Nil));
+        assert_eq!(
+            table.try_push(&[Value::Symbol(2), Value::Symbol(3)], Weight(1)),
+            None
+        );
+        assert_eq!(table.try_push(&[Value::Nil, Value::Nil], Weight(1)), None);
+        assert_eq!(
+            table.try_push(&[Value::Symbol(4), Value::Nil], Weight(1)),
+            Some(1)
+        );
+        assert_eq!(table.num_rows(), 2);
+    }
+
+    #[test]
+    fn add_weight_cancels_rows() {
+        let mut table = Table::new(1);
+        table.add_index(0, Value::Symbol(1));
+        table.add_index(0, Value::Symbol(3));
+        table.add_weight(&[Value::Symbol(1)], Weight(1));
+        table.add_weight(&[Value::Symbol(2)], Weight(1));
+        table.add_weight(&[Value::Symbol(3)], Weight(1));
+        table.add_weight(&[Value::Symbol(1)], Weight(-1));
+        assert_eq!(table.num_rows(), 2);
+        assert_eq!(table.row(0), &[Value::Symbol(3)]);
+        assert_eq!(table.row(1), &[Value::Symbol(2)]);
+        assert_eq!(table.iter_index(0, &Value::Symbol(1)).unwrap().count(), 0);
+        let threes: Vec<&[Value]> = table.iter_index(0, &Value::Symbol(3)).unwrap().collect();
+        assert_eq!(threes, vec![&[Value::Symbol(3)][..]]);
+
+        table.add_weight(&[Value::Symbol(1)], Weight(-2));
+        assert_eq!(table.weight(2), Weight(-2));
+        assert_eq!(table.iter_index(0, &Value::Symbol(1)).unwrap().count(), 1);
+        table.add_weight(&[Value::Symbol(1)], Weight(3));
+        assert_eq!(table.num_rows(), 3);
+        assert_eq!(table.weight(2), Weight(1));
+        table.add_weight(&[Value::Symbol(1)], Weight(-1));
+        table.add_weight(&[Value::Symbol(2)], Weight(0));
+        assert_eq!(table.num_rows(), 2);
+        assert_eq!(table.iter_index(0, &Value::Symbol(1)).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn normalize_drops_cancelled_rows() {
+        let mut table = Table::new(1);
+        table.add_index(0, Value::Symbol(1));
+        table.push(&[Value::Symbol(1)], Weight(1));
+        table.push(&[Value::Symbol(2)], Weight(1));
+        table.push(&[Value::Symbol(1)], Weight(-1));
+        table.normalize();
+        assert_eq!(table.num_rows(), 1);
+        assert_eq!(table.row(0), &[Value::Symbol(2)]);
+        assert_eq!(table.iter_index(0, &Value::Symbol(1)).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn compact_saturates_totals() {
+        let mut table = Table::new(1);
+        table.push(&[Value::Symbol(1)], Weight(i32::MAX));
+        table.push(&[Value::Symbol(1)], Weight(i32::MAX));
+        table.compact();
+        assert_eq!(table.num_rows(), 1);
+        assert_eq!(table.weight(0), Weight(i32::MAX));
+    }
+
+    #[test]
+    fn enumerate_rows_includes_indices_and_weights() {
+        let mut table = Table::new(1);
+        table.push(&[Value::Symbol(1)], Weight(1));
+        table.push(&[Value::Symbol(2)], Weight(-2));
+        let rows: Vec<_> = table.enumerate_rows().collect();
+        assert_eq!(
+            rows,
+            vec![
+                (0, &[Value::Symbol(1)][..], Weight(1)),
+                (1, &[Value::Symbol(2)][..], Weight(-2)),
+            ]
+        );
+    }
+}
@@ -1,5 +1,27 @@
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum Value {
     Symbol(u64),
     Nil,
 }
+
+/**
+ * Symbols from here up are reserved for `OutputTerm::Fresh`, so that fresh symbols can't collide
+ * with symbols in the input. The parser rejects them as constants.
+ */
+pub const FIRST_FRESH_SYMBOL: u64 = 1 << 63;
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum ValueKind {
+    Symbol,
+    Nil,
+}
+
+impl Value {
+    pub fn kind(&self) -> ValueKind {
+        match *self {
+            Value::Symbol(_) => ValueKind::Symbol,
+            Value::Nil => ValueKind::Nil,
+        }
+    }
+}
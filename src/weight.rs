@@ -1,2 +1,30 @@
+use std::fmt;
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Weight(pub i32);
+
+impl Weight {
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    /**
+     * Adds the weights, clamping to the range of `i32` instead of wrapping.
+     */
+    pub fn saturating_add(self, other: Weight) -> Weight {
+        Weight(self.0.saturating_add(other.0))
+    }
+
+    /**
+     * Multiplies the weights, clamping to the range of `i32` instead of wrapping.
+     */
+    pub fn saturating_mul(self, other: Weight) -> Weight {
+        Weight(self.0.saturating_mul(other.0))
+    }
+}
+
+impl fmt::Display for Weight {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
@@ -0,0 +1,59 @@
+extern crate match_diagram;
+
+use match_diagram::database::symbols;
+use match_diagram::diagram::Diagram;
+use match_diagram::evaluation::Evaluation;
+use match_diagram::parse::parse_program;
+
+const CLOSURE: &str = r#"
+registers 3
+
+# Every edge is a path.
+root: edge(_ -> %0, _ -> %1) {
+  output path(%0, %1)
+}
+
+# A path followed by an edge is a path.
+root: path(_ -> %0, _ -> %1) {
+  edge(%1, _ -> %2) {
+    output path(%0, %2)
+  }
+}
+
+assert edge(:1, :2)
+assert edge(:2, :3)
+assert edge(:3, :1)
+assert edge(:3, :4)
+assert edge(:5, :6)
+"#;
+
+#[test]
+fn evaluates_transitive_closure() {
+    let (diagram, context, input) = parse_program(CLOSURE, 0).unwrap();
+    assert_eq!(diagram.get_num_registers(), 3);
+    let path = context.predicate_name_to_predicate["path"];
+
+    let expected = symbols(
+        path,
+        &[
+            &[1, 1],
+            &[1, 2],
+            &[1, 3],
+            &[1, 4],
+            &[2, 1],
+            &[2, 2],
+            &[2, 3],
+            &[2, 4],
+            &[3, 1],
+            &[3, 2],
+            &[3, 3],
+            &[3, 4],
+            &[5, 6],
+        ],
+    );
+
+    let single_pass = Evaluation::run_multi(&diagram, &input, diagram.get_num_registers());
+    assert!(!single_pass.total_db.semantically_eq(&expected));
+    let closure = Evaluation::run_fixpoint(&diagram, &input, diagram.get_num_registers());
+    assert!(closure.total_db.semantically_eq(&expected));
+}